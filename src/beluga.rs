@@ -1,21 +1,40 @@
-use crate::dictionary::SPEC;
+#[cfg(feature = "encryption")]
+use crate::cipher::{NodeCipher, SALT_SIZE};
+use crate::collation::{collator, Collator, DEFAULT_COLLATION};
+use crate::dictionary::{MAX_SPEC, MIN_SPEC, REDIRECT, SPEC};
 use crate::error::{Error, Result};
-use crate::tree::{Serializable, Smoothable, Tree};
+use crate::tree::{NodeCodec, Serializable, Smoothable, Tree, TreeBuilder, TreeStats};
 use crate::utils::*;
+#[cfg(feature = "zstd")]
+use crate::zstd_codec::ZstdCodec;
+use base64::prelude::*;
 use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Display;
+use std::hash::{DefaultHasher, Hash, Hasher};
 use std::io::{SeekFrom, Write};
-use std::path::Path;
+use std::sync::Arc;
 use tokio::fs::File;
-use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use tracing::{debug, warn};
+#[cfg(feature = "nfc")]
+use unicode_normalization::UnicodeNormalization;
 
-const LEAF_NODE_SIZE: usize = 64 * 1024;
-const INDEX_NODE_SIZE: usize = 64 * 1024;
 pub const EXT_ENTRY: &str = "bel";
 pub const EXT_RESOURCE: &str = "beld";
 pub const EXT_RAW_ENTRY: &str = "bel-db";
 pub const EXT_RAW_RESOURCE: &str = "beld-db";
+pub const MAGIC: &[u8; 4] = b"BELG";
+pub const COMPRESSION_DEFLATE: &str = "deflate";
+pub const COMPRESSION_ZSTD: &str = "zstd";
+/// Whitespace written after the metadata JSON, which parsers skip
+const METADATA_SPARE: usize = 32;
+/// Last bytes of a file with a versioned footer
+pub const FOOTER_MAGIC: &[u8; 4] = b"BELF";
+pub const FOOTER_VERSION: u16 = 1;
+/// Size of the fixed trailer of files written before the versioned footer
+const LEGACY_TRAILER_SIZE: usize = 24;
 
 #[derive(Clone, Copy, PartialEq, Eq)]
 pub enum BelFileType {
@@ -28,10 +47,35 @@ pub fn parse_file_type(file: &str) -> Result<BelFileType> {
     match ext {
         Some(EXT_ENTRY) => Ok(BelFileType::Entry),
         Some(EXT_RESOURCE) => Ok(BelFileType::Resource),
-        _ => Err(Error::Msg("Invalid file extension".to_string())),
+        _ => Err(Error::InvalidExtension(file.to_string())),
     }
 }
 
+/// Read the magic and spec at the start of a file. Files written before the
+/// magic was introduced start directly with spec 1 and are accepted as such.
+pub(crate) async fn read_spec<R: AsyncRead + AsyncSeek + Unpin>(file: &mut R) -> Result<u16> {
+    let mut buf = [0u8; 4];
+    if file.read_exact(&mut buf).await.is_err() {
+        return Err(Error::Malformed("truncated beluga header".to_string()));
+    }
+    if &buf == MAGIC {
+        return match file.read_u16().await {
+            Ok(spec) if (MIN_SPEC..=MAX_SPEC).contains(&spec) => Ok(spec),
+            Ok(spec) => Err(Error::UnsupportedSpec {
+                found: spec,
+                expected: SPEC,
+            }),
+            Err(_) => Err(Error::Malformed("truncated beluga header".to_string())),
+        };
+    }
+    // the magic came with spec 1, nothing older exists
+    if u8v_to_u16(&buf[..2])? == 1 {
+        file.seek(SeekFrom::Start(2)).await?;
+        return Ok(1);
+    }
+    Err(Error::Malformed("not a beluga file".to_string()))
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Metadata {
     pub version: String,
@@ -40,6 +84,81 @@ pub struct Metadata {
     pub email: String,
     pub create_time: String,
     pub comment: String,
+    /// Language code of the headwords, e.g. "en"
+    #[serde(default)]
+    pub source_language: String,
+    /// Language code of the definitions
+    #[serde(default)]
+    pub target_language: String,
+    #[serde(default)]
+    pub license: String,
+    /// Name of the icon resource
+    #[serde(default)]
+    pub icon: Option<String>,
+    /// Nodes carry a trailing CRC32. Files written before checksums lack it.
+    #[serde(default)]
+    pub checksum: bool,
+    /// Build a tree of reversed headwords on save for suffix search. Its root
+    /// is in the footer, or just before the legacy 24-byte trailer.
+    #[serde(default)]
+    pub suffix_index: bool,
+    /// Write node lengths and child pointers as varints on save, which needs
+    /// a reader that knows the node flag. Smaller for many short entries.
+    #[serde(default)]
+    pub varint: bool,
+    /// Nodes are encrypted with AES-256-GCM, see `Beluga::set_key`
+    #[serde(default)]
+    pub encrypted: bool,
+    /// Hex salt deriving the file key of an encrypted file from the user key
+    #[serde(default)]
+    pub salt: String,
+    /// Size limit of a leaf node in bytes before compression
+    #[serde(default = "default_node_size")]
+    pub leaf_node_size: usize,
+    /// Size limit of an index node in bytes before compression
+    #[serde(default = "default_node_size")]
+    pub index_node_size: usize,
+    /// Id of the `Collator` ordering the headwords, see `collation`
+    #[serde(default = "default_collation")]
+    pub collation: String,
+    /// Node compression, `COMPRESSION_DEFLATE` or `COMPRESSION_ZSTD`, which
+    /// needs the `zstd` feature
+    #[serde(default = "default_compression")]
+    pub compression: String,
+    /// Base64 zstd dictionary nodes are compressed with, empty for none.
+    /// `Beluga` trains one on save; `BelugaBuilder` uses the one given.
+    #[serde(default)]
+    pub zstd_dictionary: String,
+    /// Nodes are followed by a link from each leaf to its left sibling, so
+    /// leaves can be read backwards. Set before saving; readers without
+    /// support for it cannot read the file.
+    #[serde(default)]
+    pub prev_links: bool,
+    /// Nodes are followed by a link from each leaf to its right sibling,
+    /// which is then left out of the node, so `Beluga::append_entries` can
+    /// patch it in place instead of writing every leaf on the left again.
+    /// Set before saving; readers without support for it cannot read the
+    /// file.
+    #[serde(default)]
+    pub next_links: bool,
+}
+
+fn default_node_size() -> usize {
+    64 * 1024
+}
+
+fn default_compression() -> String {
+    COMPRESSION_DEFLATE.to_string()
+}
+
+fn default_collation() -> String {
+    DEFAULT_COLLATION.to_string()
+}
+
+impl Default for Metadata {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Metadata {
@@ -51,6 +170,22 @@ impl Metadata {
             email: String::from(""),
             create_time: String::from(""),
             comment: String::from(""),
+            source_language: String::from(""),
+            target_language: String::from(""),
+            license: String::from(""),
+            icon: None,
+            checksum: false,
+            suffix_index: false,
+            varint: false,
+            encrypted: false,
+            salt: String::from(""),
+            leaf_node_size: default_node_size(),
+            index_node_size: default_node_size(),
+            collation: default_collation(),
+            compression: default_compression(),
+            zstd_dictionary: String::new(),
+            prev_links: false,
+            next_links: false,
         }
     }
 }
@@ -58,6 +193,22 @@ impl Metadata {
 #[derive(Debug, Clone)]
 pub struct EntryKey(pub String);
 
+impl EntryKey {
+    /// Key for `name` in the form headwords are stored in: Unicode NFC when
+    /// the `nfc` feature is enabled, so composed and decomposed spellings of
+    /// the same word meet. Files must be built and read with the same setting.
+    pub fn normalized(name: &str) -> Self {
+        #[cfg(feature = "nfc")]
+        {
+            Self(name.nfc().collect())
+        }
+        #[cfg(not(feature = "nfc"))]
+        {
+            Self(name.to_string())
+        }
+    }
+}
+
 impl Display for EntryKey {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", self.0)
@@ -66,7 +217,7 @@ impl Display for EntryKey {
 
 impl PartialOrd for EntryKey {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        self.0.partial_cmp(&other.0)
+        Some(self.cmp(other))
     }
 }
 
@@ -90,17 +241,26 @@ impl Serializable for EntryKey {
     }
 
     fn size(&self) -> usize {
-        self.0.bytes().len()
+        self.0.len()
     }
 
+    /// Invalid UTF-8 from a malformed file is replaced with U+FFFD rather
+    /// than failing every read of the node holding it.
     fn from_bytes(bytes: &[u8]) -> Self {
-        Self(String::from_utf8(bytes.to_vec()).unwrap())
+        match String::from_utf8(bytes.to_vec()) {
+            Ok(s) => Self(s),
+            Err(_) => {
+                let s = String::from_utf8_lossy(bytes).into_owned();
+                warn!("Headword is not valid UTF-8. {}", s);
+                Self(s)
+            }
+        }
     }
 }
 
 impl Smoothable for EntryKey {
-    fn smooth(&self) -> Self {
-        EntryKey(self.0.to_lowercase())
+    fn smooth(&self, collator: &dyn Collator) -> Self {
+        EntryKey(collator.sort_key(&Self::normalized(&self.0).0))
     }
 }
 
@@ -121,138 +281,1075 @@ impl Serializable for EntryValue {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SaveStage {
+    EntryTree,
+    TokenTree,
+    SuffixTree,
+    /// Root pointers are written, the file is complete
+    Finalize,
+}
+
+#[derive(Debug, Clone)]
+pub struct VerifyReport {
+    pub entry_num: usize,
+    pub entry_nodes: usize,
+    pub token_num: usize,
+    pub token_nodes: usize,
+    /// The first problem found, `None` if the file is sound
+    pub anomaly: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct CompactReport {
+    pub src_size: u64,
+    pub dest_size: u64,
+}
+
+impl CompactReport {
+    /// Bytes saved, negative if the file grew
+    pub fn reduction(&self) -> i64 {
+        self.src_size as i64 - self.dest_size as i64
+    }
+}
+
+fn fingerprint(value: &[u8], seed: u8) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    seed.hash(&mut hasher);
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Node codec for reading a file described by `metadata`. Encrypted files
+/// need `key` and the `encryption` feature.
+pub(crate) fn node_codec(metadata: &Metadata, key: Option<&[u8; 32]>) -> Result<NodeCodec> {
+    #[allow(unused_mut)]
+    let mut codec = NodeCodec::new(metadata.checksum, false);
+    codec.prev_links = metadata.prev_links;
+    codec.next_links = metadata.next_links;
+    if metadata.encrypted {
+        let key = match key {
+            Some(k) => k,
+            None => {
+                return Err(Error::Msg(
+                    "dictionary is encrypted, key required".to_string(),
+                ))
+            }
+        };
+        #[cfg(feature = "encryption")]
+        {
+            let salt = (0..metadata.salt.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(metadata.salt.get(i..i + 2).unwrap_or(""), 16))
+                .collect::<std::result::Result<Vec<u8>, _>>()
+                .ok()
+                .and_then(|v| <[u8; SALT_SIZE]>::try_from(v).ok())
+                .ok_or_else(|| Error::BadMetadata("invalid salt".to_string()))?;
+            codec.cipher = Some(NodeCipher::new(key, &salt));
+        }
+        #[cfg(not(feature = "encryption"))]
+        {
+            let _ = key;
+            return Err(Error::Msg(
+                "dictionary is encrypted, built without encryption support".to_string(),
+            ));
+        }
+    }
+    set_compression(&mut codec, metadata)?;
+    Ok(codec)
+}
+
+/// Compress nodes of `codec` as `metadata` says
+#[allow(unused_variables)]
+fn set_compression(codec: &mut NodeCodec, metadata: &Metadata) -> Result<()> {
+    match metadata.compression.as_str() {
+        COMPRESSION_DEFLATE => Ok(()),
+        #[cfg(feature = "zstd")]
+        COMPRESSION_ZSTD => {
+            let dictionary = BASE64_STANDARD
+                .decode(&metadata.zstd_dictionary)
+                .map_err(|_| Error::BadMetadata("invalid zstd dictionary".to_string()))?;
+            codec.zstd = Some(ZstdCodec::new(&dictionary));
+            Ok(())
+        }
+        #[cfg(not(feature = "zstd"))]
+        COMPRESSION_ZSTD => Err(Error::Msg(
+            "dictionary is compressed with zstd, built without zstd support".to_string(),
+        )),
+        c => Err(Error::BadMetadata(format!("unknown compression {}", c))),
+    }
+}
+
+/// Train the zstd dictionary to save in `metadata` on leaves of `tree`, if
+/// nodes are to be compressed with zstd
+#[cfg(feature = "zstd")]
+fn train_zstd(metadata: &mut Metadata, tree: &Tree<EntryKey, EntryValue>) {
+    if metadata.compression != COMPRESSION_ZSTD {
+        return;
+    }
+    metadata.zstd_dictionary = match ZstdCodec::train(&tree.sample_leaves(1024, metadata.varint)) {
+        Some(d) => BASE64_STANDARD.encode(d),
+        None => {
+            warn!("Too few nodes to train a zstd dictionary, compress without");
+            String::new()
+        }
+    };
+}
+
+/// Node codec for saving with an optional `key`, recording the checksum and
+/// encryption settings in `metadata` before it is written.
+fn save_codec(metadata: &mut Metadata, key: Option<&[u8; 32]>) -> Result<NodeCodec> {
+    metadata.checksum = true;
+    #[allow(unused_mut)]
+    let mut codec = NodeCodec::new(true, metadata.varint);
+    codec.prev_links = metadata.prev_links;
+    codec.next_links = metadata.next_links;
+    metadata.encrypted = false;
+    metadata.salt = String::new();
+    #[cfg(feature = "encryption")]
+    if let Some(key) = key {
+        let salt = NodeCipher::random_salt();
+        metadata.encrypted = true;
+        metadata.salt = salt.iter().map(|b| format!("{:02x}", b)).collect();
+        codec.cipher = Some(NodeCipher::new(key, &salt));
+    }
+    #[cfg(not(feature = "encryption"))]
+    let _ = key;
+    set_compression(&mut codec, metadata)?;
+    Ok(codec)
+}
+
+/// Magic, spec and metadata at the start of a file
+async fn write_header<W: AsyncWrite + Unpin>(file: &mut W, metadata: &Metadata) -> Result<()> {
+    file.write_all(MAGIC).await?;
+    file.write_all(&u16_to_u8v(SPEC)).await?;
+    let mut metadata = metadata_json(metadata)?;
+    // room for `Beluga::append_entries` to update the metadata in place
+    metadata.push_str(&" ".repeat(METADATA_SPARE));
+    file.write_all(&u32_to_u8v(metadata.len() as u32)).await?;
+    file.write_all(metadata.as_bytes()).await?;
+    Ok(())
+}
+
+fn metadata_json(metadata: &Metadata) -> Result<String> {
+    serde_json::to_string(metadata).map_err(|e| Error::BadMetadata(e.to_string()))
+}
+
+/// Footer pointing to the roots of `entry_root`, `token_root` and, if any,
+/// `suffix_root` and `resource_root`
+async fn write_roots<W: AsyncWrite + Unpin>(
+    file: &mut W,
+    suffix_root: Option<(u64, u32)>,
+    entry_root: (u64, u32),
+    token_root: (u64, u32),
+    resource_root: Option<(u64, u32)>,
+) -> Result<()> {
+    let mut footer = Footer::new();
+    footer.set_root(RootKind::Entry, entry_root);
+    footer.set_root(RootKind::Token, token_root);
+    if let Some(root) = suffix_root {
+        footer.set_root(RootKind::Suffix, root);
+    }
+    if let Some(root) = resource_root {
+        footer.set_root(RootKind::Resource, root);
+    }
+    footer.write_to(file).await
+}
+
+/// Tree a footer points to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RootKind {
+    Entry = 1,
+    Token = 2,
+    Suffix = 3,
+    /// Resources embedded in an entry file, see `Beluga::input_resource`
+    Resource = 4,
+}
+
+/// Root pointers at the end of a file. The footer is
+///
+/// | Bytes  | Description                               |
+/// | ------ | ----------------------------------------- |
+/// | 2      | version                                   |
+/// | 2      | root number                               |
+/// | 13 * n | root kind u8, node offset u64, size u32   |
+/// | 4      | footer length, including this and magic   |
+/// | 4      | magic `BELF`                              |
+///
+/// so a reader finds it from the end of the file. Roots of unknown kinds are
+/// skipped. Files without the magic end with the legacy 24-byte trailer,
+/// read as version 0.
+#[derive(Debug, Clone, Default)]
+pub struct Footer {
+    pub version: u16,
+    roots: Vec<(u8, u64, u32)>,
+}
+
+impl Footer {
+    pub fn new() -> Self {
+        Self {
+            version: FOOTER_VERSION,
+            roots: vec![],
+        }
+    }
+
+    pub fn root(&self, kind: RootKind) -> Option<(u64, u32)> {
+        self.roots
+            .iter()
+            .find(|r| r.0 == kind as u8)
+            .map(|r| (r.1, r.2))
+    }
+
+    pub fn set_root(&mut self, kind: RootKind, root: (u64, u32)) {
+        self.roots.retain(|r| r.0 != kind as u8);
+        self.roots.push((kind as u8, root.0, root.1));
+    }
+
+    pub async fn write_to<W: AsyncWrite + Unpin>(&self, file: &mut W) -> Result<()> {
+        let mut buf = u16_to_u8v(self.version);
+        buf.extend(u16_to_u8v(self.roots.len() as u16));
+        for (kind, offset, size) in &self.roots {
+            buf.push(*kind);
+            buf.extend(u64_to_u8v(*offset));
+            buf.extend(u32_to_u8v(*size));
+        }
+        buf.extend(u32_to_u8v((buf.len() + 8) as u32));
+        buf.extend(FOOTER_MAGIC);
+        file.write_all(&buf).await?;
+        file.flush().await?;
+        Ok(())
+    }
+
+    /// Read the footer at the end of `file`. `legacy_suffix` tells whether a
+    /// legacy trailer is preceded by a suffix root, see
+    /// `Metadata::suffix_index`.
+    pub async fn read_from<R: AsyncRead + AsyncSeek + Unpin>(
+        file: &mut R,
+        legacy_suffix: bool,
+    ) -> Result<Self> {
+        let truncated = || Error::Malformed("truncated beluga footer".to_string());
+        let mut tail = [0u8; 8];
+        file.seek(SeekFrom::End(-8))
+            .await
+            .map_err(|_| truncated())?;
+        file.read_exact(&mut tail).await.map_err(|_| truncated())?;
+        if &tail[4..] != FOOTER_MAGIC {
+            return Self::read_legacy(file, legacy_suffix).await;
+        }
+        let length = u8v_to_u32(&tail[..4])? as usize;
+        if length < 12 {
+            return Err(Error::Malformed("invalid beluga footer".to_string()));
+        }
+        let mut buf = vec![0; length - 8];
+        file.seek(SeekFrom::End(-(length as i64)))
+            .await
+            .map_err(|_| truncated())?;
+        file.read_exact(&mut buf).await.map_err(|_| truncated())?;
+        let mut scanner = Scanner::new(&buf);
+        let version = u8v_to_u16(scanner.read(2))?;
+        if version > FOOTER_VERSION {
+            return Err(Error::Msg(format!(
+                "unsupported beluga footer version {}",
+                version
+            )));
+        }
+        let num = u8v_to_u16(scanner.read(2))? as usize;
+        if buf.len() != 4 + num * 13 {
+            return Err(Error::Malformed("invalid beluga footer".to_string()));
+        }
+        let mut roots = Vec::with_capacity(num);
+        for _ in 0..num {
+            roots.push((scanner.read_u8(), scanner.read_u64(), scanner.read_u32()));
+        }
+        Ok(Self { version, roots })
+    }
+
+    async fn read_legacy<R: AsyncRead + AsyncSeek + Unpin>(
+        file: &mut R,
+        suffix: bool,
+    ) -> Result<Self> {
+        let size = LEGACY_TRAILER_SIZE + if suffix { 12 } else { 0 };
+        let mut buf = vec![0; size];
+        file.seek(SeekFrom::End(-(size as i64))).await?;
+        file.read_exact(&mut buf).await?;
+        let mut scanner = Scanner::new(&buf);
+        let mut footer = Self {
+            version: 0,
+            roots: vec![],
+        };
+        if suffix {
+            footer.set_root(RootKind::Suffix, (scanner.read_u64(), scanner.read_u32()));
+        }
+        footer.set_root(RootKind::Entry, (scanner.read_u64(), scanner.read_u32()));
+        footer.set_root(RootKind::Token, (scanner.read_u64(), scanner.read_u32()));
+        Ok(footer)
+    }
+
+    /// Entry and token roots, which every file has
+    pub fn main_roots(&self) -> Result<((u64, u32), (u64, u32))> {
+        match (self.root(RootKind::Entry), self.root(RootKind::Token)) {
+            (Some(entry), Some(token)) => Ok((entry, token)),
+            _ => Err(Error::Malformed("beluga footer misses a root".to_string())),
+        }
+    }
+}
+
+/// Create `dest` for writing, failing if it already exists
+async fn create_new(dest: &str) -> Result<File> {
+    match tokio::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(dest)
+        .await
+    {
+        Ok(f) => Ok(f),
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+            Err(Error::Msg(format!("Destination exists: {}", dest)))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Byte length of the HTML character reference `text` starts with, e.g.
+/// `&amp;` or `&#160;`, if any
+fn entity_len(text: &str) -> Option<usize> {
+    let body = text.strip_prefix('&')?;
+    let name = &body[..body.find(';')?];
+    let valid = (1..=10).contains(&name.len())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '#');
+    valid.then_some(name.len() + 2)
+}
+
+/// Distinct lowercase words of `text` in order of first appearance, e.g. to
+/// index a definition. Words are split at whitespace and punctuation, and
+/// HTML tags are skipped. So are character references such as `&amp;` or
+/// `&nbsp;`, which split words like the characters they stand for.
+pub fn tokenize(text: &str) -> Vec<String> {
+    let mut words: Vec<String> = vec![];
+    let mut seen: HashSet<String> = HashSet::new();
+    let mut word = String::new();
+    let mut in_tag = false;
+    let mut skip_to = 0;
+    for (i, c) in text
+        .char_indices()
+        .chain(std::iter::once((text.len(), ' ')))
+    {
+        if i < skip_to {
+            continue;
+        }
+        let c = match entity_len(&text[i..]) {
+            Some(len) if !in_tag => {
+                skip_to = i + len;
+                ' '
+            }
+            _ => c,
+        };
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => in_tag = false,
+            _ if in_tag => {}
+            c if c.is_alphanumeric() => word.extend(c.to_lowercase()),
+            _ => {}
+        }
+        if (in_tag || !c.is_alphanumeric()) && !word.is_empty() {
+            if seen.insert(word.clone()) {
+                words.push(word.clone());
+            }
+            word.clear();
+        }
+    }
+    words
+}
+
+/// Token value as stored: each entry name prefixed by its u16 length
+fn token_value(value: Vec<String>) -> EntryValue {
+    let mut data: Vec<u8> = vec![];
+    for item in value {
+        let bs = item.as_bytes();
+        let mut size = u16_to_u8v(bs.len() as u16);
+        data.append(&mut size);
+        data.append(&mut bs.to_vec());
+    }
+    EntryValue(data)
+}
+
+/// Add the reversed `key` to a suffix tree unless it repeats `last`
+fn insert_suffix(tree: &mut Tree<EntryKey, EntryValue>, last: &mut Option<String>, key: &EntryKey) {
+    if last.as_ref() != Some(&key.0) {
+        let reversed: String = key.0.chars().rev().collect();
+        tree.insert(EntryKey(reversed), EntryValue(vec![]));
+        *last = Some(key.0.clone());
+    }
+}
+
 pub struct Beluga {
     pub metadata: Metadata,
     pub file_type: BelFileType,
     entry_tree: Tree<EntryKey, EntryValue>,
     token_tree: Tree<EntryKey, EntryValue>,
+    /// Resources embedded in an entry file
+    resource_tree: Tree<EntryKey, EntryValue>,
+    #[cfg(feature = "encryption")]
+    key: Option<[u8; 32]>,
+    /// Fingerprint of each distinct value to the first name it was input
+    /// under, when deduplicating
+    dedup: Option<HashMap<(u64, u64, usize), String>>,
+    dedup_saved: u64,
+    collator: Arc<dyn Collator>,
+    /// Sync saved files to disk before returning
+    durable: bool,
 }
 
 impl Beluga {
-    pub fn new(metadata: Metadata, file_type: BelFileType) -> Self {
-        Self {
+    /// The trees split nodes at `metadata.leaf_node_size` and
+    /// `metadata.index_node_size`, 64KiB by default, and order headwords by
+    /// the collator registered as `metadata.collation`, failing if there is
+    /// none, see `collation::register_collator`.
+    pub fn new(metadata: Metadata, file_type: BelFileType) -> Result<Self> {
+        let collator = collator(&metadata.collation)?;
+        let (index_size, leaf_size) = (metadata.index_node_size, metadata.leaf_node_size);
+        Ok(Self {
             metadata,
             file_type,
-            entry_tree: Tree::new(INDEX_NODE_SIZE, LEAF_NODE_SIZE),
-            token_tree: Tree::new(INDEX_NODE_SIZE, LEAF_NODE_SIZE),
+            entry_tree: Tree::new(index_size, leaf_size, collator.clone()),
+            token_tree: Tree::new(index_size, leaf_size, collator.clone()),
+            resource_tree: Tree::new(index_size, leaf_size, collator.clone()),
+            #[cfg(feature = "encryption")]
+            key: None,
+            dedup: None,
+            dedup_saved: 0,
+            collator,
+            durable: true,
+        })
+    }
+
+    /// Whether `save` and the like sync the file to disk before returning,
+    /// on by default. The nodes are synced before the footer is written and
+    /// the footer after, so a crash never leaves a footer pointing to nodes
+    /// that did not make it to disk. Off, saving a scratch file is faster.
+    pub fn set_durable(&mut self, durable: bool) {
+        self.durable = durable;
+    }
+
+    /// Store a value input again under another name as a redirect to the
+    /// first name instead of a second copy, e.g. an image shared by several
+    /// resources. Values are matched by a 128-bit fingerprint and length.
+    pub fn set_dedup(&mut self, dedup: bool) {
+        self.dedup = if dedup { Some(HashMap::new()) } else { None };
+    }
+
+    /// Bytes not stored thanks to deduplication so far
+    pub fn dedup_saved(&self) -> u64 {
+        self.dedup_saved
+    }
+
+    /// Encrypt the nodes with `key` on save. Lookups then need the same key,
+    /// see `Dictionary::new_with_key`.
+    #[cfg(feature = "encryption")]
+    pub fn set_key(&mut self, key: Option<[u8; 32]>) {
+        self.key = key;
+    }
+
+    /// Only the metadata block of `filepath`, without reading any tree, e.g.
+    /// to show `entry_num` of many dictionaries cheaply.
+    pub async fn read_metadata(filepath: &str) -> Result<Metadata> {
+        let mut file = File::open(filepath).await?;
+        Self::read_metadata_from(&mut file).await
+    }
+
+    /// Read the spec and the metadata laid out as that spec says, leaving
+    /// `file` right after the header
+    pub(crate) async fn read_metadata_from<R: AsyncRead + AsyncSeek + Unpin>(
+        file: &mut R,
+    ) -> Result<Metadata> {
+        let spec = read_spec(file).await?;
+        match spec {
+            1 => {
+                let metadata_length = file.read_u32().await? as usize;
+                let mut buf = vec![0; metadata_length];
+                file.read_exact(&mut buf).await?;
+                serde_json::from_slice(&buf[..]).map_err(|e| Error::BadMetadata(e.to_string()))
+            }
+            _ => Err(Error::UnsupportedSpec {
+                found: spec,
+                expected: SPEC,
+            }),
         }
     }
 
-    pub async fn from_file(filepath: &str) -> Self {
-        let ext = parse_file_type(filepath).expect("fail to parse file type");
-        let mut file = File::open(filepath).await.expect("fail to open file");
-        let spec = file.read_u16().await.expect("fail to read spec");
-        if spec == SPEC {
-            let metadata_length =
-                file.read_u32().await.expect("fail to read metadata length") as usize;
-            let mut buf = vec![0; metadata_length];
-            file.read_exact(&mut buf)
-                .await
-                .expect("fail to read metadata");
-            let metadata = serde_json::from_slice(&buf[..]).expect("invalid metadata");
-            let mut po = Self::new(metadata, ext);
-            // root node
-            file.seek(SeekFrom::End(-24)).await.expect("seek to -24");
-            let mut buf = vec![0; 24];
-            file.read_exact(&mut buf).await.expect("fail to read roots");
-            let mut scanner = Scanner::new(&buf);
-            let entry_root_offset = scanner.read_u64();
-            let entry_root_size = scanner.read_u32();
-            let token_root_offset = scanner.read_u64();
-            let token_root_size = scanner.read_u32();
-            println!("Parsing entry tree...");
-            po.entry_tree = Tree::from_file(
-                &mut file,
-                entry_root_offset,
-                entry_root_size,
-                INDEX_NODE_SIZE,
-                LEAF_NODE_SIZE,
+    /// Read the spec, metadata and the footer of a file
+    async fn read_header<R: AsyncRead + AsyncSeek + Unpin>(
+        file: &mut R,
+    ) -> Result<(Metadata, Footer)> {
+        let metadata = Self::read_metadata_from(file).await?;
+        let footer = Footer::read_from(file, metadata.suffix_index).await?;
+        Ok((metadata, footer))
+    }
+
+    /// Load the trees of `filepath` into memory, every node of them, which
+    /// is what editing and saving again need. This costs memory and startup
+    /// time proportional to the file; to only look entries up open it with
+    /// `Dictionary`, which reads nodes lazily.
+    pub async fn from_file(filepath: &str) -> Result<Self> {
+        Self::from_file_with_key(filepath, None).await
+    }
+
+    /// `from_file` for a file that may be encrypted with `key`. The key is
+    /// kept, so saving again encrypts with it too.
+    pub async fn from_file_with_key(filepath: &str, key: Option<&[u8; 32]>) -> Result<Self> {
+        let ext = parse_file_type(filepath)?;
+        let mut file = File::open(filepath).await?;
+        Self::from_reader_with_key(&mut file, ext, key).await
+    }
+
+    /// `from_file` for a file held by `reader`, e.g. a `Cursor<Vec<u8>>`
+    /// filled by `save_to_writer`.
+    pub async fn from_reader<R: AsyncRead + AsyncSeek + Unpin>(
+        reader: &mut R,
+        file_type: BelFileType,
+    ) -> Result<Self> {
+        Self::from_reader_with_key(reader, file_type, None).await
+    }
+
+    /// `from_reader` for a file that may be encrypted with `key`.
+    pub async fn from_reader_with_key<R: AsyncRead + AsyncSeek + Unpin>(
+        file: &mut R,
+        file_type: BelFileType,
+        key: Option<&[u8; 32]>,
+    ) -> Result<Self> {
+        let (metadata, footer) = Self::read_header(file).await?;
+        let (entry_root, token_root) = footer.main_roots()?;
+        let codec = node_codec(&metadata, key)?;
+        let collator = collator(&metadata.collation)?;
+        let mut po = Self::new(metadata, file_type)?;
+        #[cfg(feature = "encryption")]
+        po.set_key(key.copied());
+        debug!("Parsing entry tree");
+        po.entry_tree = Tree::from_file(
+            file,
+            entry_root.0,
+            entry_root.1,
+            po.metadata.index_node_size,
+            po.metadata.leaf_node_size,
+            &codec,
+            collator.clone(),
+        )
+        .await?;
+        debug!("Parsing token tree");
+        po.token_tree = Tree::from_file(
+            file,
+            token_root.0,
+            token_root.1,
+            po.metadata.index_node_size,
+            po.metadata.leaf_node_size,
+            &codec,
+            collator.clone(),
+        )
+        .await?;
+        if let Some(resource_root) = footer.root(RootKind::Resource) {
+            debug!("Parsing resource tree");
+            po.resource_tree = Tree::from_file(
+                file,
+                resource_root.0,
+                resource_root.1,
+                po.metadata.index_node_size,
+                po.metadata.leaf_node_size,
+                &codec,
+                collator.clone(),
             )
-            .await
-            .expect("fail to parse entry tree");
-            println!("Parsing token tree...");
-            po.token_tree = Tree::from_file(
-                &mut file,
-                token_root_offset,
-                token_root_size,
-                INDEX_NODE_SIZE,
-                LEAF_NODE_SIZE,
+            .await?;
+        }
+        Ok(po)
+    }
+
+    /// Check that the trees of `filepath` are structurally sound: nodes lie
+    /// within the file and decode, keys are sorted within and across leaves
+    /// and the sibling chain links every leaf exactly once, in order. Nodes
+    /// are read one at a time; a broken one is reported as the anomaly.
+    pub async fn verify(filepath: &str) -> Result<VerifyReport> {
+        Self::verify_with_key(filepath, None).await
+    }
+
+    /// `verify` for a file that may be encrypted with `key`.
+    pub async fn verify_with_key(filepath: &str, key: Option<&[u8; 32]>) -> Result<VerifyReport> {
+        let mut file = File::open(filepath).await?;
+        let file_size = file.metadata().await?.len();
+        let (metadata, footer) = Self::read_header(&mut file).await?;
+        let (entry_root, token_root) = footer.main_roots()?;
+        let resource_root = footer.root(RootKind::Resource).unwrap_or((0, 0));
+        let codec = node_codec(&metadata, key)?;
+        let collator = collator(&metadata.collation)?;
+        let mut report = VerifyReport {
+            entry_num: 0,
+            entry_nodes: 0,
+            token_num: 0,
+            token_nodes: 0,
+            anomaly: None,
+        };
+        let roots = [
+            ("entry", entry_root),
+            ("token", token_root),
+            ("resource", resource_root),
+        ];
+        for (name, root) in roots {
+            let check = Tree::<EntryKey, EntryValue>::check_file(
+                &mut file, file_size, root, &codec, &*collator,
             )
-            .await
-            .expect("fail to parse token tree");
-            po
+            .await?;
+            if name == "entry" {
+                report.entry_num = check.record_num;
+                report.entry_nodes = check.node_num;
+            } else if name == "token" {
+                report.token_num = check.record_num;
+                report.token_nodes = check.node_num;
+            }
+            if let Some(anomaly) = check.anomaly {
+                report.anomaly = Some(format!("{} tree: {}", name, anomaly));
+                return Ok(report);
+            }
+        }
+        if report.entry_num as u64 != metadata.entry_num {
+            report.anomaly = Some(format!(
+                "metadata has {} entries but the entry tree has {}",
+                metadata.entry_num, report.entry_num
+            ));
+        }
+        Ok(report)
+    }
+
+    /// Rewrite `src` to `dest` with full nodes, e.g. after the tree was built
+    /// in random order, which leaves nodes half empty. Entries are loaded in
+    /// tree order into a `BelugaBuilder` and `dest` is verified afterwards.
+    pub async fn compact(src: &str, dest: &str) -> Result<CompactReport> {
+        let po = Self::from_file(src).await?;
+        let mut builder = BelugaBuilder::new(dest, po.metadata.clone()).await?;
+        let mut failure: Option<Error> = None;
+        po.traverse_token(&mut |k, v| {
+            if failure.is_none() {
+                match Self::parse_token_entries(&v.0) {
+                    Ok(names) => builder.input_token(k.0.clone(), names),
+                    Err(e) => failure = Some(e),
+                }
+            }
+        });
+        po.traverse_resource(&mut |k, v| builder.input_resource(k.0.clone(), v.0.clone()));
+        if let Some(e) = failure {
+            return Err(e);
+        }
+        let entries = po.entry_tree.freeze();
+        for (k, v) in entries.range(..) {
+            builder.input_entry(k.0.clone(), v.0.clone()).await?;
+        }
+        builder.finish().await?;
+        if let Some(anomaly) = Self::verify(dest).await?.anomaly {
+            return Err(Error::Msg(format!(
+                "compacted file is invalid: {}",
+                anomaly
+            )));
+        }
+        Ok(CompactReport {
+            src_size: std::fs::metadata(src)?.len(),
+            dest_size: std::fs::metadata(dest)?.len(),
+        })
+    }
+
+    /// Add `entries` to the file at `path` without rewriting it: the changed
+    /// nodes and new root pointers are appended and the metadata is updated
+    /// in place. A name already in the file, or earlier in `entries`, gets
+    /// the new value instead of a second entry. The nodes replaced stay in
+    /// the file as garbage until `compact`. With `Metadata::next_links` only
+    /// the nodes on the way to the new keys are read and written again, the
+    /// links of their siblings are patched in place. Otherwise the whole
+    /// entry tree is loaded, every leaf left of a changed one is written
+    /// again and so is a suffix index, if any, in full. Fails without
+    /// touching the file when the metadata outgrew its room.
+    pub async fn append_entries(path: &str, entries: Vec<(String, Vec<u8>)>) -> Result<()> {
+        Self::append_entries_with_key(path, entries, None).await
+    }
+
+    /// `append_entries` for a file that may be encrypted with `key`.
+    pub async fn append_entries_with_key(
+        path: &str,
+        entries: Vec<(String, Vec<u8>)>,
+        key: Option<&[u8; 32]>,
+    ) -> Result<()> {
+        let mut file = tokio::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .await?;
+        let mut head = [0u8; 10];
+        file.read_exact(&mut head).await?;
+        // files without magic start with the spec right away
+        let length_at = if &head[..4] == MAGIC { 6 } else { 2 };
+        let room = u8v_to_u32(&head[length_at..length_at + 4])? as usize;
+        file.seek(SeekFrom::Start(0)).await?;
+        let (mut metadata, mut footer) = Self::read_header(&mut file).await?;
+        // the count if every name is new, which is known once the trees are
+        // read
+        let mut bound = metadata.clone();
+        bound.entry_num += entries.len() as u64;
+        if metadata_json(&bound)?.len() > room {
+            return Err(Error::Msg(
+                "no room to update the metadata, save the file again instead".to_string(),
+            ));
+        }
+        let mut codec = node_codec(&metadata, key)?;
+        codec.varint = metadata.varint;
+        metadata.entry_num += if metadata.next_links {
+            Self::append_lazily(&mut file, &metadata, &mut footer, &codec, entries).await?
         } else {
-            panic!("invalid beluga spec");
+            Self::append_loaded(path, &mut file, key, &mut footer, &codec, entries).await?
+        };
+        let metadata_text = metadata_json(&metadata)?;
+        file.sync_all().await?;
+        footer.version = FOOTER_VERSION;
+        footer.write_to(&mut file).await?;
+        file.seek(SeekFrom::Start(length_at as u64 + 4)).await?;
+        let mut metadata_text = metadata_text.into_bytes();
+        metadata_text.resize(room, b' ');
+        file.write_all(&metadata_text).await?;
+        file.flush().await?;
+        file.sync_all().await?;
+        Ok(())
+    }
+
+    /// Part of `append_entries_with_key` for a file with `next_links`: the
+    /// trees are read lazily, only along the paths to the new keys. Returns
+    /// how many of the keys were not in the file yet.
+    async fn append_lazily(
+        file: &mut File,
+        metadata: &Metadata,
+        footer: &mut Footer,
+        codec: &NodeCodec,
+        entries: Vec<(String, Vec<u8>)>,
+    ) -> Result<u64> {
+        let (entry_root, _) = footer.main_roots()?;
+        let collator = collator(&metadata.collation)?;
+        let lazy = |root| {
+            Tree::<EntryKey, EntryValue>::lazy(
+                root,
+                metadata.index_node_size,
+                metadata.leaf_node_size,
+                collator.clone(),
+            )
+        };
+        let mut entry_tree = lazy(entry_root);
+        let mut suffix_tree = if metadata.suffix_index {
+            Some(lazy(footer.root(RootKind::Suffix).unwrap_or((0, 0))))
+        } else {
+            None
+        };
+        let mut added = 0;
+        for (name, value) in entries {
+            let key = EntryKey::normalized(&name);
+            if let Some(tree) = suffix_tree.as_mut() {
+                let reversed = EntryKey(key.0.chars().rev().collect());
+                tree.load_path(file, &reversed, codec).await?;
+                if !tree.holds_near(&reversed) {
+                    tree.insert(reversed, EntryValue(vec![]));
+                }
+            }
+            entry_tree.load_path(file, &key, codec).await?;
+            if let Some(value) = entry_tree.replace_near(&key, EntryValue(value)) {
+                entry_tree.insert(key, value);
+                added += 1;
+            }
+        }
+        file.seek(SeekFrom::End(0)).await?;
+        let entry_root = entry_tree.write_changes_to(file, codec).await?;
+        footer.set_root(RootKind::Entry, entry_root);
+        if let Some(tree) = suffix_tree {
+            let suffix_root = tree.write_changes_to(file, codec).await?;
+            footer.set_root(RootKind::Suffix, suffix_root);
+        }
+        Ok(added)
+    }
+
+    /// Part of `append_entries_with_key` for a file without `next_links`,
+    /// whose entry tree is read whole, returning how many keys are new
+    async fn append_loaded(
+        path: &str,
+        file: &mut File,
+        key: Option<&[u8; 32]>,
+        footer: &mut Footer,
+        codec: &NodeCodec,
+        entries: Vec<(String, Vec<u8>)>,
+    ) -> Result<u64> {
+        let mut po = Self::from_file_with_key(path, key).await?;
+        let mut added = 0;
+        for (name, value) in entries {
+            let key = EntryKey::normalized(&name);
+            if let Some(value) = po.entry_tree.replace_near(&key, EntryValue(value)) {
+                po.input_entry(name, value.0);
+                added += 1;
+            }
+        }
+        file.seek(SeekFrom::End(0)).await?;
+        let entry_root = po.entry_tree.write_changes_to(file, codec).await?;
+        footer.set_root(RootKind::Entry, entry_root);
+        if po.metadata.suffix_index {
+            let suffix_root = po.suffix_tree().write_to(file, codec, None).await?;
+            footer.set_root(RootKind::Suffix, suffix_root);
         }
+        Ok(added)
+    }
+
+    /// Embed the resource `name` in an entry file, so a dictionary can ship
+    /// as a single file. Resources go to a tree of their own, apart from the
+    /// entries, and are found by `Dictionary::search_resource` before any
+    /// `.beld` file.
+    pub fn input_resource(&mut self, name: String, data: Vec<u8>) {
+        let key = EntryKey::normalized(&name);
+        self.resource_tree.insert(key, EntryValue(data));
     }
 
     pub fn input_entry(&mut self, name: String, value: Vec<u8>) {
         self.metadata.entry_num += 1;
-        self.entry_tree.insert(EntryKey(name), EntryValue(value));
+        let key = EntryKey::normalized(&name);
+        let mut value = value;
+        if let Some(seen) = self.dedup.as_mut() {
+            let fingerprint = (fingerprint(&value, 0), fingerprint(&value, 1), value.len());
+            match seen.get(&fingerprint) {
+                Some(first) if *first != key.0 => {
+                    let link = format!("{}{}", REDIRECT, first).into_bytes();
+                    if link.len() < value.len() {
+                        self.dedup_saved += (value.len() - link.len()) as u64;
+                        value = link;
+                    }
+                }
+                Some(_) => {}
+                None => {
+                    seen.insert(fingerprint, key.0.clone());
+                }
+            }
+        }
+        self.entry_tree.insert(key, EntryValue(value));
     }
 
     pub fn input_token(&mut self, name: String, value: Vec<String>) {
-        let key = EntryKey(name);
-        let mut data: Vec<u8> = vec![];
-        for item in value {
-            let bs = item.as_bytes();
-            let mut size = u16_to_u8v(bs.len() as u16);
-            data.append(&mut size);
-            data.append(&mut bs.to_vec());
+        let key = EntryKey::normalized(&name);
+        self.token_tree.insert(key, token_value(value));
+    }
+
+    /// Fill the token tree with the words of every definition, see
+    /// `tokenize`, so a search for a word also lists the entries using it.
+    /// Words in `stopwords`, lowercase, are left out; redirects are not
+    /// indexed. Returns the number of tokens added.
+    pub fn build_fulltext_tokens(&mut self, stopwords: &[&str]) -> usize {
+        let mut tokens: HashMap<String, Vec<String>> = HashMap::new();
+        self.entry_tree.traverse(|k, v| {
+            let text = match std::str::from_utf8(&v.0) {
+                Ok(t) if !t.trim_start().starts_with(REDIRECT) => t,
+                _ => return,
+            };
+            for word in tokenize(text) {
+                if stopwords.contains(&word.as_str()) {
+                    continue;
+                }
+                let names = tokens.entry(word).or_default();
+                if names.last() != Some(&k.0) {
+                    names.push(k.0.clone());
+                }
+            }
+        });
+        let added = tokens.len();
+        for (word, names) in tokens {
+            self.input_token(word, names);
         }
-        self.token_tree.insert(key, EntryValue(data));
+        added
+    }
+
+    /// Entry names of a token value, failing on a name that is not UTF-8
+    pub fn parse_token_entries(data: &[u8]) -> Result<Vec<String>> {
+        Self::parse_token_entries_with(data, true)
     }
 
-    pub fn parse_token_entries(data: &[u8]) -> Vec<String> {
+    /// Entry names of a token value. With `strict` a name that is not UTF-8
+    /// or a truncated value is an error; otherwise invalid bytes become
+    /// U+FFFD and a truncated tail is dropped, so a damaged value still
+    /// yields the names it holds.
+    pub fn parse_token_entries_with(data: &[u8], strict: bool) -> Result<Vec<String>> {
         let mut result: Vec<String> = vec![];
-        let mut scanner = Scanner::new(&data);
+        let mut scanner = Scanner::new(data);
         loop {
             if scanner.is_end() {
                 break;
             }
-            let size = scanner.read_u16();
-            let str = scanner.read_string(size as usize);
-            result.push(str);
-        }
-        result
-    }
-
-    pub fn save(&mut self, dest: &str) {
-        println!("Writing to {}...", dest);
-        let file_path = Path::new(dest);
-        if file_path.exists() {
-            panic!("Destination exists: {}", dest);
-        }
-        let file_path = Path::new(dest);
-        let mut file = std::fs::File::create(file_path).expect("fail to create file");
-        // spec
-        let spec_buf = u16_to_u8v(SPEC);
-        file.write_all(&spec_buf).expect("fail to write spec");
-        // metadata
-        let metadata = serde_json::to_string(&self.metadata).expect("Fail to serialize metdata");
-        let metadata_length = metadata.as_bytes().len() as u32;
-        let metadata_length_buf = u32_to_u8v(metadata_length);
-        file.write_all(&metadata_length_buf)
-            .expect("fail to write metadata length");
-        file.write_all(metadata.as_bytes())
-            .expect("fail to wirte metadata");
+            let bytes = scanner
+                .try_read_u16()
+                .and_then(|size| scanner.try_read(size as usize));
+            let bytes = match bytes {
+                Ok(b) => b,
+                Err(e) if !strict => {
+                    warn!("Token entries are truncated. {}", e);
+                    break;
+                }
+                Err(e) => return Err(e),
+            };
+            let name = if strict {
+                String::from_utf8(bytes.to_vec())
+                    .map_err(|_| Error::Msg("invalid utf-8 string".to_string()))?
+            } else {
+                String::from_utf8_lossy(bytes).into_owned()
+            };
+            result.push(name);
+        }
+        Ok(result)
+    }
+
+    /// Save to `dest`, failing if it already exists
+    pub async fn save(&mut self, dest: &str) -> Result<()> {
+        self.save_with_progress(dest, |_, _, _| {}).await
+    }
+
+    /// Save to `dest`, replacing it if it already exists
+    pub async fn save_overwrite(&mut self, dest: &str) -> Result<()> {
+        let mut file = File::create(dest).await?;
+        self.save_file(&mut file, |_, _, _| {}).await
+    }
+
+    /// Save to `dest`, calling `progress` with the current stage and the
+    /// number of nodes written so far out of the stage total.
+    pub async fn save_with_progress<F>(&mut self, dest: &str, progress: F) -> Result<()>
+    where
+        F: FnMut(SaveStage, u64, u64),
+    {
+        let mut file = create_new(dest).await?;
+        self.save_file(&mut file, progress).await
+    }
+
+    async fn save_file<F>(&mut self, file: &mut File, progress: F) -> Result<()>
+    where
+        F: FnMut(SaveStage, u64, u64),
+    {
+        let durable = self.durable;
+        self.write_file(file, progress, async |f: &mut File| {
+            if durable {
+                f.sync_all().await?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    /// Save to `w`, e.g. a `Cursor<Vec<u8>>` to build a file in memory. The
+    /// file starts at the current position, which should be the start of `w`
+    /// as node offsets are taken from it.
+    pub async fn save_to_writer<W: AsyncWrite + AsyncSeek + Unpin>(
+        &mut self,
+        w: &mut W,
+    ) -> Result<()> {
+        self.write_file(w, |_, _, _| {}, async |_: &mut W| Ok(()))
+            .await
+    }
+
+    /// Write the whole file, calling `sync` once the nodes are written and
+    /// flushed and again after the footer
+    async fn write_file<W, F, S>(
+        &mut self,
+        file: &mut W,
+        mut progress: F,
+        mut sync: S,
+    ) -> Result<()>
+    where
+        W: AsyncWrite + AsyncSeek + Unpin,
+        F: FnMut(SaveStage, u64, u64),
+        S: AsyncFnMut(&mut W) -> Result<()>,
+    {
+        #[cfg(feature = "zstd")]
+        train_zstd(&mut self.metadata, &self.entry_tree);
+        #[cfg(feature = "encryption")]
+        let codec = save_codec(&mut self.metadata, self.key.as_ref())?;
+        #[cfg(not(feature = "encryption"))]
+        let codec = save_codec(&mut self.metadata, None)?;
+        write_header(file, &self.metadata).await?;
         // entry tree
-        println!("Writing entry nodes...");
-        let (entry_root_offset, entry_root_size) = self.entry_tree.write_to(&mut file);
+        let entry_root = self
+            .entry_tree
+            .write_to(
+                file,
+                &codec,
+                Some(&mut |done, total| progress(SaveStage::EntryTree, done, total)),
+            )
+            .await?;
         // token tree
-        println!("Writing token nodes...");
-        let (token_root_offset, token_root_size) = self.token_tree.write_to(&mut file);
-        file.write_all(&u64_to_u8v(entry_root_offset))
-            .expect("fail to write entry root offset");
-        file.write_all(&u32_to_u8v(entry_root_size))
-            .expect("fail to write entry root size");
-        file.write_all(&u64_to_u8v(token_root_offset))
-            .expect("fail to write token root offset");
-        file.write_all(&u32_to_u8v(token_root_size))
-            .expect("fail to write token root size");
-        let file_metadata = file.metadata().expect("fail to get file metadata");
-        let file_size = (file_metadata.len() as f64) / 1024.0 / 1024.0;
-        println!("{} - {:.2}M", dest, file_size);
+        let token_root = self
+            .token_tree
+            .write_to(
+                file,
+                &codec,
+                Some(&mut |done, total| progress(SaveStage::TokenTree, done, total)),
+            )
+            .await?;
+        // reversed entry tree
+        let suffix_root = if self.metadata.suffix_index {
+            let tree = self.suffix_tree();
+            let mut on_node = |done, total| progress(SaveStage::SuffixTree, done, total);
+            Some(tree.write_to(file, &codec, Some(&mut on_node)).await?)
+        } else {
+            None
+        };
+        // embedded resources
+        let resource_root = if self.resource_tree.record_num() > 0 {
+            Some(self.resource_tree.write_to(file, &codec, None).await?)
+        } else {
+            None
+        };
+        sync(file).await?;
+        write_roots(file, suffix_root, entry_root, token_root, resource_root).await?;
+        sync(file).await?;
+        progress(SaveStage::Finalize, 1, 1);
+        Ok(())
+    }
+
+    fn suffix_tree(&self) -> Tree<EntryKey, EntryValue> {
+        let mut tree = Tree::new(
+            self.metadata.index_node_size,
+            self.metadata.leaf_node_size,
+            self.collator.clone(),
+        );
+        let mut last: Option<String> = None;
+        self.entry_tree
+            .traverse(|k, _| insert_suffix(&mut tree, &mut last, k));
+        tree
+    }
+
+    /// Rough size of the file `save` would write, for a progress estimate or
+    /// to check the disk before a long build. A suffix index is built to be
+    /// measured, which takes a while for a large dictionary.
+    pub fn estimate_size(&self) -> u64 {
+        let mut metadata = self.metadata.clone();
+        #[cfg(feature = "zstd")]
+        train_zstd(&mut metadata, &self.entry_tree);
+        #[cfg(feature = "encryption")]
+        let codec = save_codec(&mut metadata, self.key.as_ref());
+        #[cfg(not(feature = "encryption"))]
+        let codec = save_codec(&mut metadata, None);
+        // `save` fails as well then, size what it would write by default
+        let codec = codec.unwrap_or_else(|_| NodeCodec::new(true, metadata.varint));
+        let metadata_len = metadata_json(&metadata).map_or(0, |m| m.len());
+        let header = (MAGIC.len() + 2 + 4 + metadata_len + METADATA_SPARE) as u64;
+        let mut size = header + self.entry_tree.estimate_size(&codec);
+        size += self.token_tree.estimate_size(&codec);
+        let mut roots = 2;
+        if self.metadata.suffix_index {
+            size += self.suffix_tree().estimate_size(&codec);
+            roots += 1;
+        }
+        if self.resource_tree.record_num() > 0 {
+            size += self.resource_tree.estimate_size(&codec);
+            roots += 1;
+        }
+        size + 4 + roots * 13 + 8
+    }
+
+    pub fn entry_stats(&self) -> TreeStats {
+        self.entry_tree.stats()
+    }
+
+    pub fn token_stats(&self) -> TreeStats {
+        self.token_tree.stats()
     }
 
     pub fn traverse_entry<F>(&self, walk: &mut F)
@@ -262,10 +1359,1140 @@ impl Beluga {
         self.entry_tree.traverse(walk);
     }
 
+    /// `traverse_entry` with each definition or resource open to change,
+    /// see `Tree::traverse_mut`
+    pub fn traverse_entry_mut<F>(&mut self, walk: &mut F)
+    where
+        F: FnMut(&EntryKey, &mut EntryValue),
+    {
+        self.entry_tree.traverse_mut(walk);
+    }
+
     pub fn traverse_token<F>(&self, walk: &mut F)
     where
         F: FnMut(&EntryKey, &EntryValue),
     {
         self.token_tree.traverse(walk);
     }
+
+    /// Walk the resources embedded with `input_resource`
+    pub fn traverse_resource<F>(&self, walk: &mut F)
+    where
+        F: FnMut(&EntryKey, &EntryValue),
+    {
+        self.resource_tree.traverse(walk);
+    }
+
+    /// Write every entry to `dest` as a line of JSON, `{"name":..,"definition":..}`.
+    /// Resource data and definitions that are not UTF-8 are base64 encoded
+    /// and flagged with `"base64":true`.
+    pub fn export_jsonl(&self, dest: &str) -> Result<()> {
+        let file = std::fs::File::create(dest)?;
+        let mut writer = std::io::BufWriter::new(file);
+        let binary = matches!(self.file_type, BelFileType::Resource);
+        let mut failure: Option<std::io::Error> = None;
+        self.entry_tree.traverse(|k, v| {
+            if failure.is_some() {
+                return;
+            }
+            let text = if binary {
+                None
+            } else {
+                std::str::from_utf8(&v.0).ok()
+            };
+            let line = match text {
+                Some(t) => serde_json::json!({ "name": k.0, "definition": t }),
+                None => serde_json::json!({
+                    "name": k.0,
+                    "definition": BASE64_STANDARD.encode(&v.0),
+                    "base64": true,
+                }),
+            };
+            if let Err(e) = serde_json::to_writer(&mut writer, &line)
+                .map_err(std::io::Error::from)
+                .and_then(|_| writer.write_all(b"\n"))
+            {
+                failure = Some(e);
+            }
+        });
+        if let Some(e) = failure {
+            return Err(e.into());
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Builds a file from entries input in key order, as `Beluga` would save it,
+/// without holding the entry tree in memory: full leaves are written to
+/// `dest` right away, which needs `Metadata::next_links`, so the builder
+/// turns it on. Tokens, embedded resources and the suffix index, which holds
+/// headwords only, are still built in memory. Values are not deduplicated.
+pub struct BelugaBuilder {
+    metadata: Metadata,
+    file: File,
+    /// Codec and room for the metadata, once the header is written
+    started: Option<(NodeCodec, usize)>,
+    entries: TreeBuilder<EntryKey, EntryValue>,
+    token_tree: Tree<EntryKey, EntryValue>,
+    resource_tree: Tree<EntryKey, EntryValue>,
+    suffix_tree: Option<Tree<EntryKey, EntryValue>>,
+    last_name: Option<String>,
+    #[cfg(feature = "encryption")]
+    key: Option<[u8; 32]>,
+    durable: bool,
+}
+
+impl BelugaBuilder {
+    /// Start building `dest`, failing if it already exists
+    pub async fn new(dest: &str, mut metadata: Metadata) -> Result<Self> {
+        let collator = collator(&metadata.collation)?;
+        let file = create_new(dest).await?;
+        metadata.next_links = true;
+        let (index_size, leaf_size) = (metadata.index_node_size, metadata.leaf_node_size);
+        let suffix_tree = if metadata.suffix_index {
+            Some(Tree::new(index_size, leaf_size, collator.clone()))
+        } else {
+            None
+        };
+        Ok(Self {
+            metadata,
+            file,
+            started: None,
+            entries: TreeBuilder::new(index_size, leaf_size, collator.clone()),
+            token_tree: Tree::new(index_size, leaf_size, collator.clone()),
+            resource_tree: Tree::new(index_size, leaf_size, collator),
+            suffix_tree,
+            last_name: None,
+            #[cfg(feature = "encryption")]
+            key: None,
+            durable: true,
+        })
+    }
+
+    /// Sync the file to disk in `finish`, see `Beluga::set_durable`
+    pub fn set_durable(&mut self, durable: bool) {
+        self.durable = durable;
+    }
+
+    /// Encrypt the nodes with `key`, see `Beluga::set_key`. Fails once an
+    /// entry was input, as nodes are encrypted as they are written.
+    #[cfg(feature = "encryption")]
+    pub fn set_key(&mut self, key: Option<[u8; 32]>) -> Result<()> {
+        if self.started.is_some() {
+            return Err(Error::Msg("set the key before the first entry".to_string()));
+        }
+        self.key = key;
+        Ok(())
+    }
+
+    /// Write the header, with the entry count still unknown, and set up the
+    /// codec
+    async fn start(&mut self) -> Result<()> {
+        if self.started.is_some() {
+            return Ok(());
+        }
+        #[cfg(feature = "encryption")]
+        let codec = save_codec(&mut self.metadata, self.key.as_ref())?;
+        #[cfg(not(feature = "encryption"))]
+        let codec = save_codec(&mut self.metadata, None)?;
+        write_header(&mut self.file, &self.metadata).await?;
+        let room = metadata_json(&self.metadata)?.len() + METADATA_SPARE;
+        self.started = Some((codec, room));
+        Ok(())
+    }
+
+    /// Add an entry. Names must come in the order of the entry tree, by the
+    /// sort keys of `metadata.collation`; an entry sorting before the
+    /// previous one is an error and is left out.
+    pub async fn input_entry(&mut self, name: String, value: Vec<u8>) -> Result<()> {
+        self.start().await?;
+        let key = EntryKey::normalized(&name);
+        let (codec, _) = self.started.as_ref().unwrap();
+        self.entries
+            .insert(&mut self.file, codec, key.clone(), EntryValue(value))
+            .await?;
+        if let Some(tree) = self.suffix_tree.as_mut() {
+            insert_suffix(tree, &mut self.last_name, &key);
+        }
+        Ok(())
+    }
+
+    /// Same as `Beluga::input_token`, in any order
+    pub fn input_token(&mut self, name: String, value: Vec<String>) {
+        let key = EntryKey::normalized(&name);
+        self.token_tree.insert(key, token_value(value));
+    }
+
+    /// Same as `Beluga::input_resource`, in any order
+    pub fn input_resource(&mut self, name: String, data: Vec<u8>) {
+        let key = EntryKey::normalized(&name);
+        self.resource_tree.insert(key, EntryValue(data));
+    }
+
+    /// Write the rest of the file
+    pub async fn finish(mut self) -> Result<()> {
+        self.start().await?;
+        let (codec, room) = self.started.take().unwrap();
+        self.metadata.entry_num = self.entries.record_num() as u64;
+        let file = &mut self.file;
+        let entry_root = self.entries.finish(file, &codec).await?;
+        let token_root = self.token_tree.write_to(file, &codec, None).await?;
+        let suffix_root = match &self.suffix_tree {
+            Some(tree) => Some(tree.write_to(file, &codec, None).await?),
+            None => None,
+        };
+        let resource_root = if self.resource_tree.record_num() > 0 {
+            Some(self.resource_tree.write_to(file, &codec, None).await?)
+        } else {
+            None
+        };
+        // the header went out before the entry count was known
+        let mut metadata_text = metadata_json(&self.metadata)?.into_bytes();
+        if metadata_text.len() > room {
+            return Err(Error::Msg("no room to update the metadata".to_string()));
+        }
+        metadata_text.resize(room, b' ');
+        let end = file.stream_position().await?;
+        file.seek(SeekFrom::Start((MAGIC.len() + 2 + 4) as u64))
+            .await?;
+        file.write_all(&metadata_text).await?;
+        file.seek(SeekFrom::Start(end)).await?;
+        if self.durable {
+            file.sync_all().await?;
+        }
+        write_roots(file, suffix_root, entry_root, token_root, resource_root).await?;
+        if self.durable {
+            file.sync_all().await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::*;
+
+    fn words(n: usize) -> Vec<(String, String)> {
+        (0..n)
+            .map(|i| (format!("word{:04}", i), format!("value of word {}", i)))
+            .collect()
+    }
+
+    async fn save_words(dir: &std::path::Path, file: &str, n: usize) -> String {
+        let entries = words(n);
+        let entries: Vec<(&str, &str)> = entries
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+        save_entries(dir, file, small_nodes(256), &entries).await
+    }
+
+    async fn entry_root(path: &str) -> (u64, u32) {
+        let mut file = File::open(path).await.unwrap();
+        let footer = Footer::read_from(&mut file, false).await.unwrap();
+        footer.root(RootKind::Entry).unwrap()
+    }
+
+    #[tokio::test]
+    async fn verify_accepts_fresh_file() {
+        let dir = temp_dir("verify_accepts_fresh_file");
+        let path = save_words(&dir, "fresh.bel", 300).await;
+        let report = Beluga::verify(&path).await.unwrap();
+        assert_eq!(report.anomaly, None);
+        assert_eq!(report.entry_num, 300);
+        assert!(report.entry_nodes > 1);
+    }
+
+    #[tokio::test]
+    async fn verify_reports_bad_root_offset() {
+        let dir = temp_dir("verify_reports_bad_root_offset");
+        let path = save_words(&dir, "offset.bel", 300).await;
+        let root = entry_root(&path).await;
+        let mut bytes = std::fs::read(&path).unwrap();
+        let mut pattern = u64_to_u8v(root.0);
+        pattern.extend(u32_to_u8v(root.1));
+        let at = bytes
+            .windows(pattern.len())
+            .rposition(|w| w == pattern)
+            .unwrap();
+        let beyond = bytes.len() as u64 + 100;
+        bytes[at..at + 8].copy_from_slice(&u64_to_u8v(beyond));
+        std::fs::write(&path, &bytes).unwrap();
+        let report = Beluga::verify(&path).await.unwrap();
+        let anomaly = report.anomaly.unwrap();
+        assert!(anomaly.contains("outside of the file"), "{}", anomaly);
+    }
+
+    #[tokio::test]
+    async fn verify_reports_corrupt_node() {
+        let dir = temp_dir("verify_reports_corrupt_node");
+        let path = save_words(&dir, "corrupt.bel", 300).await;
+        let root = entry_root(&path).await;
+        let mut bytes = std::fs::read(&path).unwrap();
+        let middle = (root.0 + root.1 as u64 / 2) as usize;
+        bytes[middle] ^= 0xff;
+        std::fs::write(&path, &bytes).unwrap();
+        assert!(Beluga::from_file(&path).await.is_err());
+        let report = Beluga::verify(&path).await.unwrap();
+        let anomaly = report.anomaly.unwrap();
+        assert!(anomaly.contains("corrupt"), "{}", anomaly);
+    }
+
+    #[tokio::test]
+    async fn save_reports_progress_by_stage() {
+        let dir = temp_dir("save_reports_progress_by_stage");
+        let path = file_path(&dir, "progress.bel");
+        let mut po = Beluga::new(small_nodes(256), BelFileType::Entry).unwrap();
+        for (name, value) in words(300) {
+            po.input_entry(name, value.into_bytes());
+        }
+        po.input_token("value".to_string(), vec!["word0001".to_string()]);
+        let mut calls: Vec<(SaveStage, u64, u64)> = vec![];
+        po.save_with_progress(&path, |stage, done, total| calls.push((stage, done, total)))
+            .await
+            .unwrap();
+        let mut order: Vec<SaveStage> = calls.iter().map(|c| c.0).collect();
+        order.dedup();
+        assert_eq!(
+            order,
+            vec![
+                SaveStage::EntryTree,
+                SaveStage::TokenTree,
+                SaveStage::Finalize
+            ]
+        );
+        let entries: Vec<&(SaveStage, u64, u64)> = calls
+            .iter()
+            .filter(|c| c.0 == SaveStage::EntryTree)
+            .collect();
+        let total = po.entry_stats().node_num as u64;
+        assert_eq!(entries.len() as u64, total);
+        assert_eq!(
+            *entries.last().unwrap(),
+            &(SaveStage::EntryTree, total, total)
+        );
+        assert_eq!(calls.last(), Some(&(SaveStage::Finalize, 1, 1)));
+    }
+
+    #[tokio::test]
+    async fn save_keeps_an_existing_file() {
+        let dir = temp_dir("save_keeps_an_existing_file");
+        let path = save_words(&dir, "exists.bel", 10).await;
+        let mut po = Beluga::new(Metadata::new(), BelFileType::Entry).unwrap();
+        po.input_entry("other".to_string(), b"value".to_vec());
+        assert!(po.save(&path).await.is_err());
+        let (dict, _) = open(&path).await;
+        assert_eq!(dict.metadata().entry_num, 10);
+        po.save_overwrite(&path).await.unwrap();
+        let (dict, cache) = open(&path).await;
+        assert_eq!(dict.metadata().entry_num, 1);
+        assert_eq!(
+            dict.search_entry(cache.clone(), "other").await.as_deref(),
+            Some("value")
+        );
+        assert_eq!(dict.search_entry(cache, "word0001").await, None);
+    }
+
+    #[tokio::test]
+    async fn language_fields_round_trip() {
+        let dir = temp_dir("language_fields_round_trip");
+        let mut metadata = Metadata::new();
+        metadata.source_language = "en".to_string();
+        metadata.target_language = "zh".to_string();
+        metadata.license = "CC BY-SA 4.0".to_string();
+        metadata.icon = Some("icon.png".to_string());
+        let path = save_entries(&dir, "fields.bel", metadata, &[("apple", "fruit")]).await;
+        let read = open(&path).await.0.metadata();
+        assert_eq!(read.source_language, "en");
+        assert_eq!(read.target_language, "zh");
+        assert_eq!(read.license, "CC BY-SA 4.0");
+        assert_eq!(read.icon.as_deref(), Some("icon.png"));
+
+        let old = r#"{"version":"1.0","entry_num":3,"author":"","email":"","create_time":"","comment":""}"#;
+        let read: Metadata = serde_json::from_str(old).unwrap();
+        assert_eq!(read.entry_num, 3);
+        assert_eq!(read.source_language, "");
+        assert_eq!(read.license, "");
+        assert_eq!(read.icon, None);
+    }
+
+    async fn read_metadata(path: &str) -> Result<Metadata> {
+        let mut file = File::open(path).await?;
+        Ok(Beluga::read_header(&mut file).await?.0)
+    }
+
+    #[tokio::test]
+    async fn header_magic_is_checked() {
+        let dir = temp_dir("header_magic_is_checked");
+        let path = save_words(&dir, "magic.bel", 10).await;
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(&bytes[..4], MAGIC);
+        assert_eq!(read_metadata(&path).await.unwrap().entry_num, 10);
+        // older files start directly with spec 1
+        let legacy = file_path(&dir, "legacy.bel");
+        std::fs::write(&legacy, &bytes[4..]).unwrap();
+        assert_eq!(read_metadata(&legacy).await.unwrap().entry_num, 10);
+
+        let bad = file_path(&dir, "bad.bel");
+        let mut bad_bytes = bytes.clone();
+        bad_bytes[..4].copy_from_slice(b"PNG\x00");
+        std::fs::write(&bad, bad_bytes).unwrap();
+        match read_metadata(&bad).await {
+            Err(Error::Malformed(msg)) => assert_eq!(msg, "not a beluga file"),
+            _ => panic!("bad magic accepted"),
+        }
+        assert!(crate::dictionary::Dictionary::new(&bad, 0).await.is_err());
+
+        for len in [0, 3, 5] {
+            let truncated = file_path(&dir, "truncated.bel");
+            std::fs::write(&truncated, &bytes[..len]).unwrap();
+            match read_metadata(&truncated).await {
+                Err(Error::Malformed(msg)) => assert_eq!(msg, "truncated beluga header"),
+                _ => panic!("truncated header of {} bytes accepted", len),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn from_file_reports_bad_files() {
+        let dir = temp_dir("from_file_reports_bad_files");
+        let missing = file_path(&dir, "missing.bel");
+        assert!(matches!(
+            Beluga::from_file(&missing).await,
+            Err(Error::FileError(_))
+        ));
+        let garbage = file_path(&dir, "garbage.bel");
+        tokio::fs::write(&garbage, b"not a dictionary at all")
+            .await
+            .unwrap();
+        assert!(matches!(
+            Beluga::from_file(&garbage).await,
+            Err(Error::Malformed(_))
+        ));
+        assert!(matches!(
+            Beluga::from_file(&file_path(&dir, "words.txt")).await,
+            Err(Error::InvalidExtension(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn read_metadata_counts_like_a_full_parse() {
+        let dir = temp_dir("read_metadata_counts_like_a_full_parse");
+        let path = save_words(&dir, "count.bel", 120).await;
+        let metadata = Beluga::read_metadata(&path).await.unwrap();
+        let po = Beluga::from_file(&path).await.unwrap();
+        let mut parsed = 0;
+        po.traverse_entry(&mut |_, _| parsed += 1);
+        assert_eq!(parsed, 120);
+        assert_eq!(metadata.entry_num, parsed);
+        let (dict, _cache) = open(&path).await;
+        assert_eq!(dict.entry_count(), parsed);
+    }
+
+    #[test]
+    fn truncated_token_entries() {
+        let mut data = vec![0, 5];
+        data.extend_from_slice(b"apple");
+        data.extend_from_slice(&[0, 6]);
+        data.extend_from_slice(b"ban");
+        assert!(matches!(
+            Beluga::parse_token_entries(&data),
+            Err(Error::Malformed(_))
+        ));
+        assert_eq!(
+            Beluga::parse_token_entries_with(&data, false).unwrap(),
+            vec!["apple"]
+        );
+        assert!(Beluga::parse_token_entries_with(&data[..1], false)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn export_jsonl_writes_a_line_per_entry() {
+        let dir = temp_dir("export_jsonl_writes_a_line_per_entry");
+        let mut po = Beluga::new(Metadata::new(), BelFileType::Entry).unwrap();
+        for (name, value) in words(30) {
+            po.input_entry(name, value.into_bytes());
+        }
+        po.input_entry("binary".to_string(), vec![0xff, 0xfe, 0]);
+        let dest = file_path(&dir, "entries.jsonl");
+        po.export_jsonl(&dest).unwrap();
+        let text = std::fs::read_to_string(&dest).unwrap();
+        let lines: Vec<serde_json::Value> = text
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 31);
+        assert_eq!(lines[0]["name"], "binary");
+        assert_eq!(lines[0]["base64"], true);
+        let definition = lines[0]["definition"].as_str().unwrap();
+        assert_eq!(
+            BASE64_STANDARD.decode(definition).unwrap(),
+            vec![0xff, 0xfe, 0]
+        );
+        assert_eq!(lines[1]["name"], "word0000");
+        assert_eq!(lines[1]["definition"], "value of word 0");
+        assert!(lines[1].get("base64").is_none());
+    }
+
+    fn owned(entries: &[(&str, &str)]) -> Vec<(String, Vec<u8>)> {
+        entries
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.as_bytes().to_vec()))
+            .collect()
+    }
+
+    const APPENDED: [(&str, &str); 4] = [
+        ("aardvark", "before the first"),
+        ("word0100a", "in the middle"),
+        ("zebra", "after the last"),
+        ("word0005", "replaced"),
+    ];
+
+    #[tokio::test]
+    async fn appended_entries_are_found() {
+        for next_links in [false, true] {
+            let dir = temp_dir(&format!("appended_entries_are_found_{}", next_links));
+            let mut metadata = small_nodes(256);
+            metadata.suffix_index = true;
+            metadata.next_links = next_links;
+            metadata.prev_links = next_links;
+            let entries = words(300);
+            let entries: Vec<(&str, &str)> = entries
+                .iter()
+                .map(|(k, v)| (k.as_str(), v.as_str()))
+                .collect();
+            let path = save_entries(&dir, "append.bel", metadata, &entries).await;
+            let size = std::fs::metadata(&path).unwrap().len();
+            Beluga::append_entries(&path, owned(&APPENDED))
+                .await
+                .unwrap();
+            let grown = std::fs::metadata(&path).unwrap().len() - size;
+            if next_links {
+                // only the paths to the new keys are written again
+                assert!(grown < size / 4, "{} bytes appended to {}", grown, size);
+            }
+            // enough in one spot to split leaves and index nodes
+            let split = (0..50)
+                .map(|i| (format!("word0200-{:02}", i), b"split".to_vec()))
+                .collect();
+            Beluga::append_entries(&path, split).await.unwrap();
+            let report = Beluga::verify(&path).await.unwrap();
+            assert_eq!(report.anomaly, None);
+            assert_eq!(report.entry_num, 353);
+
+            let (dict, cache) = open(&path).await;
+            for (name, value) in &APPENDED {
+                assert_eq!(
+                    dict.search_entry(cache.clone(), name).await.as_deref(),
+                    Some(*value)
+                );
+            }
+            assert_eq!(
+                dict.search_entry(cache.clone(), "word0150")
+                    .await
+                    .as_deref(),
+                Some("value of word 150")
+            );
+            assert_eq!(
+                dict.search_suffix(cache.clone(), "0100a", 10).await,
+                vec!["word0100a"]
+            );
+            assert_eq!(
+                dict.search_suffix(cache.clone(), "word0005", 10).await,
+                vec!["word0005"]
+            );
+            assert_eq!(
+                dict.search_entry(cache.clone(), "word0200-37")
+                    .await
+                    .as_deref(),
+                Some("split")
+            );
+            let names = dict.filter_headwords(cache, |_| true, 400).await;
+            assert_eq!(names.len(), 353);
+            assert_eq!(names.first().map(String::as_str), Some("aardvark"));
+            assert_eq!(names.last().map(String::as_str), Some("zebra"));
+        }
+    }
+
+    #[tokio::test]
+    async fn append_counts_new_names_only() {
+        for next_links in [false, true] {
+            let dir = temp_dir(&format!("append_counts_new_names_only_{}", next_links));
+            let mut metadata = small_nodes(256);
+            metadata.next_links = next_links;
+            let entries = words(300);
+            let entries: Vec<(&str, &str)> = entries
+                .iter()
+                .map(|(k, v)| (k.as_str(), v.as_str()))
+                .collect();
+            let path = save_entries(&dir, "append.bel", metadata, &entries).await;
+            let appended = [
+                ("word0010", "again"),
+                ("fresh", "first"),
+                ("fresh", "second"),
+            ];
+            Beluga::append_entries(&path, owned(&appended))
+                .await
+                .unwrap();
+            let report = Beluga::verify(&path).await.unwrap();
+            assert_eq!(report.anomaly, None);
+            assert_eq!(report.entry_num, 301);
+
+            let (dict, cache) = open(&path).await;
+            assert_eq!(dict.metadata().entry_num, 301);
+            assert_eq!(
+                dict.search_entry(cache.clone(), "word0010")
+                    .await
+                    .as_deref(),
+                Some("again")
+            );
+            assert_eq!(
+                dict.search_entry(cache, "fresh").await.as_deref(),
+                Some("second")
+            );
+        }
+    }
+
+    #[cfg(feature = "encryption")]
+    #[tokio::test]
+    async fn appends_to_encrypted_file_with_key() {
+        let dir = temp_dir("appends_to_encrypted_file_with_key");
+        let path = file_path(&dir, "secret.bel");
+        let key = [7u8; 32];
+        let mut metadata = small_nodes(256);
+        metadata.next_links = true;
+        let mut po = Beluga::new(metadata, BelFileType::Entry).unwrap();
+        po.set_key(Some(key));
+        for (name, value) in words(300) {
+            po.input_entry(name, value.into_bytes());
+        }
+        po.save(&path).await.unwrap();
+        assert!(Beluga::append_entries(&path, owned(&APPENDED))
+            .await
+            .is_err());
+        Beluga::append_entries_with_key(&path, owned(&APPENDED), Some(&key))
+            .await
+            .unwrap();
+        let report = Beluga::verify_with_key(&path, Some(&key)).await.unwrap();
+        assert_eq!(report.anomaly, None);
+        assert_eq!(report.entry_num, 303);
+        let (dict, _) = crate::dictionary::Dictionary::new_with_key(&path, 0, &key)
+            .await
+            .unwrap();
+        assert_eq!(
+            dict.search_entry(new_cache(), "word0100a").await.as_deref(),
+            Some("in the middle")
+        );
+    }
+
+    #[cfg(feature = "encryption")]
+    #[tokio::test]
+    async fn encrypted_file_needs_its_key() {
+        use crate::dictionary::Dictionary;
+        let dir = temp_dir("encrypted_file_needs_its_key");
+        let path = file_path(&dir, "secret.bel");
+        let key = [42u8; 32];
+        let mut po = Beluga::new(small_nodes(256), BelFileType::Entry).unwrap();
+        po.set_key(Some(key));
+        for (name, value) in words(300) {
+            po.input_entry(name, value.into_bytes());
+        }
+        po.save(&path).await.unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        assert!(!bytes.windows(13).any(|w| w == b"value of word"));
+
+        let (dict, _) = Dictionary::new_with_key(&path, 0, &key).await.unwrap();
+        let cache = new_cache();
+        for (name, value) in words(300) {
+            assert_eq!(dict.search_entry(cache.clone(), &name).await, Some(value));
+        }
+        assert!(Dictionary::new(&path, 0).await.is_err());
+        let (dict, _) = Dictionary::new_with_key(&path, 0, &[43u8; 32])
+            .await
+            .unwrap();
+        assert_eq!(dict.search_entry(new_cache(), "word0100").await, None);
+        let report = Beluga::verify_with_key(&path, Some(&[43u8; 32]))
+            .await
+            .unwrap();
+        assert!(report.anomaly.is_some());
+    }
+
+    #[cfg(feature = "encryption")]
+    #[tokio::test]
+    async fn verify_reports_tampered_link() {
+        let dir = temp_dir("verify_reports_tampered_link");
+        let path = file_path(&dir, "secret.bel");
+        let key = [7u8; 32];
+        let mut builder = BelugaBuilder::new(&path, small_nodes(256)).await.unwrap();
+        builder.set_key(Some(key)).unwrap();
+        for (name, value) in words(300) {
+            builder.input_entry(name, value.into_bytes()).await.unwrap();
+        }
+        builder.finish().await.unwrap();
+        let mut bytes = std::fs::read(&path).unwrap();
+        // the first leaves are written back to back, each ending with a
+        // plain link to the one right after it
+        let link = |bytes: &[u8], at: usize| {
+            (
+                u8v_to_u64(&bytes[at..at + 8]).unwrap(),
+                u8v_to_u32(&bytes[at + 8..at + 12]).unwrap(),
+            )
+        };
+        let first = (0..bytes.len() - 12)
+            .find(|&at| link(&bytes, at).0 == at as u64 + 12)
+            .unwrap();
+        let second = link(&bytes, first);
+        let third = link(&bytes, (second.0 + second.1 as u64) as usize - 12);
+        // skip the second leaf, which neither tag nor checksum cover
+        bytes[first..first + 8].copy_from_slice(&u64_to_u8v(third.0));
+        bytes[first + 8..first + 12].copy_from_slice(&u32_to_u8v(third.1));
+        std::fs::write(&path, &bytes).unwrap();
+        let report = Beluga::verify_with_key(&path, Some(&key)).await.unwrap();
+        let anomaly = report.anomaly.unwrap();
+        assert!(anomaly.contains("links to"), "{}", anomaly);
+    }
+
+    #[tokio::test]
+    async fn dedup_links_repeated_resources() {
+        let dir = temp_dir("dedup_links_repeated_resources");
+        // noise, so the copies do not simply compress away
+        let mut seed = 1u32;
+        let image: Vec<u8> = (0..4000)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (seed >> 16) as u8
+            })
+            .collect();
+        let mut sizes = vec![];
+        for dedup in [false, true] {
+            let sub = dir.join(format!("dedup-{}", dedup));
+            std::fs::create_dir_all(&sub).unwrap();
+            save_entries(&sub, "pics.bel", Metadata::new(), &[("cat", "meow")]).await;
+            let path = file_path(&sub, "pics.beld");
+            let mut po = Beluga::new(Metadata::new(), BelFileType::Resource).unwrap();
+            po.set_dedup(dedup);
+            for name in ["a/cat.png", "b/cat.png", "c/cat.png"] {
+                po.input_entry(name.to_string(), image.clone());
+            }
+            po.input_entry("dog.png".to_string(), b"woof".to_vec());
+            po.input_entry("puppy.png".to_string(), b"woof".to_vec());
+            if dedup {
+                // two images replaced, the short value is kept as is
+                assert!(po.dedup_saved() > 2 * 3900);
+            } else {
+                assert_eq!(po.dedup_saved(), 0);
+            }
+            po.save(&path).await.unwrap();
+            sizes.push(std::fs::metadata(&path).unwrap().len());
+
+            let (dict, cache) = open(&file_path(&sub, "pics.bel")).await;
+            for name in ["a/cat.png", "b/cat.png", "c/cat.png"] {
+                assert_eq!(
+                    dict.search_resource(cache.clone(), name).await,
+                    Some(image.clone())
+                );
+            }
+            assert_eq!(
+                dict.search_resource(cache, "puppy.png").await.as_deref(),
+                Some(&b"woof"[..])
+            );
+        }
+        assert!(sizes[1] < sizes[0]);
+    }
+
+    #[tokio::test]
+    async fn node_sizes_follow_metadata() {
+        let dir = temp_dir("node_sizes_follow_metadata");
+        let mut leaves = vec![];
+        for (file, metadata) in [
+            ("default.bel", Metadata::new()),
+            ("small.bel", small_nodes(512)),
+        ] {
+            let mut po = Beluga::new(metadata, BelFileType::Entry).unwrap();
+            for (name, value) in words(200) {
+                po.input_entry(name, value.into_bytes());
+            }
+            let path = file_path(&dir, file);
+            po.save(&path).await.unwrap();
+            let read = Beluga::read_metadata(&path).await.unwrap();
+            assert_eq!(read.leaf_node_size, po.metadata.leaf_node_size);
+            assert_eq!(read.index_node_size, po.metadata.index_node_size);
+            let loaded = Beluga::from_file(&path).await.unwrap();
+            assert_eq!(loaded.entry_stats().leaf_num, po.entry_stats().leaf_num);
+            leaves.push(po.entry_stats().leaf_num);
+        }
+        assert_eq!(Metadata::new().leaf_node_size, 64 * 1024);
+        assert_eq!(leaves[0], 1);
+        assert!(leaves[1] > 5, "{:?}", leaves);
+    }
+
+    #[tokio::test]
+    async fn builder_matches_saved_file() {
+        let dir = temp_dir("builder_matches_saved_file");
+        let saved = save_words(&dir, "saved.bel", 300).await;
+        let built = file_path(&dir, "built.bel");
+        let mut builder = BelugaBuilder::new(&built, small_nodes(256)).await.unwrap();
+        for (name, value) in words(300) {
+            builder.input_entry(name, value.into_bytes()).await.unwrap();
+        }
+        builder.finish().await.unwrap();
+        assert_eq!(Beluga::verify(&built).await.unwrap().anomaly, None);
+
+        let saved = Beluga::from_file(&saved).await.unwrap();
+        let built = Beluga::from_file(&built).await.unwrap();
+        assert_eq!(built.metadata.entry_num, saved.metadata.entry_num);
+        // same entries, in full leaves rather than ones split in half
+        assert!(built.entry_stats().leaf_num < saved.entry_stats().leaf_num);
+        let mut entries = vec![];
+        saved.traverse_entry(&mut |k, v| entries.push((k.0.clone(), v.0.clone())));
+        let mut i = 0;
+        built.traverse_entry(&mut |k, v| {
+            assert_eq!((&k.0, &v.0), (&entries[i].0, &entries[i].1));
+            i += 1;
+        });
+        assert_eq!(i, 300);
+    }
+
+    #[tokio::test]
+    async fn builder_rejects_entries_out_of_order() {
+        let dir = temp_dir("builder_rejects_entries_out_of_order");
+        let path = file_path(&dir, "built.bel");
+        let mut builder = BelugaBuilder::new(&path, small_nodes(256)).await.unwrap();
+        for (name, value) in words(100) {
+            builder.input_entry(name, value.into_bytes()).await.unwrap();
+        }
+        let late = builder
+            .input_entry("word0050a".to_string(), b"late".to_vec())
+            .await;
+        assert!(late.is_err());
+        builder
+            .input_entry("word0100".to_string(), b"last".to_vec())
+            .await
+            .unwrap();
+        builder.finish().await.unwrap();
+        assert_eq!(Beluga::verify(&path).await.unwrap().anomaly, None);
+
+        let (dict, cache) = open(&path).await;
+        assert_eq!(dict.search_entry(cache.clone(), "word0050a").await, None);
+        assert_eq!(
+            dict.search_entry(cache, "word0100").await.as_deref(),
+            Some("last")
+        );
+    }
+
+    #[test]
+    fn new_rejects_unknown_collation() {
+        let mut metadata = Metadata::new();
+        metadata.collation = "no-such-collation".to_string();
+        assert!(Beluga::new(metadata, BelFileType::Entry).is_err());
+    }
+
+    #[tokio::test]
+    async fn swedish_file_keeps_swedish_order() {
+        let dir = temp_dir("swedish_file_keeps_swedish_order");
+        let mut metadata = Metadata::new();
+        metadata.collation = "sv".to_string();
+        let path = save_entries(
+            &dir,
+            "sv.bel",
+            metadata,
+            &[
+                ("ö", "x"),
+                ("zon", "x"),
+                ("åt", "x"),
+                ("Ärm", "x"),
+                ("ost", "x"),
+            ],
+        )
+        .await;
+        let (dict, cache) = open(&path).await;
+        let names = dict.filter_headwords(cache.clone(), |_| true, 10).await;
+        assert_eq!(names, vec!["ost", "zon", "åt", "Ärm", "ö"]);
+        assert_eq!(
+            dict.search_entry(cache, "ärm").await,
+            None,
+            "exact lookup keeps case"
+        );
+        let po = Beluga::from_file(&path).await.unwrap();
+        assert_eq!(po.metadata.collation, "sv");
+    }
+
+    #[test]
+    fn invalid_headword_bytes_are_replaced() {
+        assert_eq!(EntryKey::from_bytes(b"caf\xc3\xa9").0, "café");
+        assert_eq!(EntryKey::from_bytes(b"ab\xffc").0, "ab\u{fffd}c");
+        assert_eq!(EntryKey::from_bytes(b"\xc3").0, "\u{fffd}");
+    }
+
+    #[tokio::test]
+    async fn compact_keeps_entries_and_shrinks() {
+        let dir = temp_dir("compact_keeps_entries_and_shrinks");
+        // the appends rewrite nodes and leave the old ones behind
+        let entries = words(300);
+        let entries: Vec<(&str, &str)> = entries
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+        let src = save_entries(&dir, "src.bel", small_nodes(256), &entries).await;
+        Beluga::append_entries(&src, owned(&APPENDED))
+            .await
+            .unwrap();
+        Beluga::append_entries(&src, owned(&[("word0200b", "late")]))
+            .await
+            .unwrap();
+        let dest = file_path(&dir, "dest.bel");
+        let report = Beluga::compact(&src, &dest).await.unwrap();
+        assert_eq!(report.src_size, std::fs::metadata(&src).unwrap().len());
+        assert_eq!(report.dest_size, std::fs::metadata(&dest).unwrap().len());
+        assert!(report.reduction() > 0);
+
+        let mut all = vec![];
+        for path in [&src, &dest] {
+            let (dict, cache) = open(path).await;
+            let mut found: Vec<(String, Vec<u8>)> = vec![];
+            dict.for_each_entry(cache, |k, v| found.push((k.to_string(), v.to_vec())))
+                .await;
+            assert_eq!(found.len() as u64, dict.metadata().entry_num);
+            all.push(found);
+        }
+        assert_eq!(all[0].len(), 304);
+        assert_eq!(all[0], all[1]);
+    }
+
+    #[tokio::test]
+    async fn nodes_are_synced_before_the_footer() {
+        let mut po = Beluga::new(small_nodes(256), BelFileType::Entry).unwrap();
+        for (name, value) in words(100) {
+            po.input_entry(name, value.into_bytes());
+        }
+        let mut cursor = std::io::Cursor::new(Vec::new());
+        // what the file held at each sync
+        let mut synced: Vec<Vec<u8>> = vec![];
+        po.write_file(
+            &mut cursor,
+            |_, _, _| {},
+            async |f: &mut std::io::Cursor<Vec<u8>>| {
+                synced.push(f.get_ref().clone());
+                Ok(())
+            },
+        )
+        .await
+        .unwrap();
+        let bytes = cursor.into_inner();
+        assert_eq!(synced.len(), 2);
+        assert!(!synced[0].ends_with(FOOTER_MAGIC));
+        assert!(synced[0].len() < bytes.len());
+        assert!(bytes.starts_with(&synced[0]));
+        assert_eq!(synced[1], bytes);
+        assert!(bytes.ends_with(FOOTER_MAGIC));
+    }
+
+    #[tokio::test]
+    async fn save_to_writer_round_trips_in_memory() {
+        let mut po = Beluga::new(small_nodes(256), BelFileType::Entry).unwrap();
+        for (name, value) in words(300) {
+            po.input_entry(name, value.into_bytes());
+        }
+        po.input_token("value".to_string(), vec!["word0001".to_string()]);
+        po.input_resource("a.png".to_string(), vec![1, 2, 3]);
+        let mut cursor = std::io::Cursor::new(Vec::new());
+        po.save_to_writer(&mut cursor).await.unwrap();
+        assert!(cursor.get_ref().ends_with(FOOTER_MAGIC));
+
+        cursor.set_position(0);
+        let read = Beluga::from_reader(&mut cursor, BelFileType::Entry)
+            .await
+            .unwrap();
+        assert_eq!(read.metadata.entry_num, 300);
+        let mut entries = vec![];
+        read.traverse_entry(&mut |k, v| {
+            entries.push((k.0.clone(), String::from_utf8(v.0.clone()).unwrap()))
+        });
+        assert_eq!(entries, words(300));
+        let mut tokens = vec![];
+        read.traverse_token(&mut |k, v| {
+            tokens.push((k.0.clone(), Beluga::parse_token_entries(&v.0).unwrap()))
+        });
+        assert_eq!(
+            tokens,
+            vec![("value".to_string(), vec!["word0001".to_string()])]
+        );
+        let mut resources = vec![];
+        read.traverse_resource(&mut |k, v| resources.push((k.0.clone(), v.0.clone())));
+        assert_eq!(resources, vec![("a.png".to_string(), vec![1, 2, 3])]);
+    }
+
+    #[tokio::test]
+    async fn footer_round_trips_and_legacy_trailer_reads() {
+        let mut footer = Footer::new();
+        footer.set_root(RootKind::Entry, (100, 20));
+        footer.set_root(RootKind::Token, (0, 0));
+        footer.roots.push((9, 7, 7));
+        footer.set_root(RootKind::Entry, (120, 30));
+        let mut cursor = std::io::Cursor::new(b"nodes".to_vec());
+        cursor.set_position(5);
+        footer.write_to(&mut cursor).await.unwrap();
+        let read = Footer::read_from(&mut cursor, false).await.unwrap();
+        assert_eq!(read.version, FOOTER_VERSION);
+        assert_eq!(read.main_roots().unwrap(), ((120, 30), (0, 0)));
+        assert_eq!(read.root(RootKind::Suffix), None);
+
+        let dir = temp_dir("footer_round_trips_and_legacy_trailer_reads");
+        let path = save_words(&dir, "footer.bel", 50).await;
+        let mut file = File::open(&path).await.unwrap();
+        let (entry, token) = Footer::read_from(&mut file, false)
+            .await
+            .unwrap()
+            .main_roots()
+            .unwrap();
+        let mut bytes = std::fs::read(&path).unwrap();
+        let footer_len = u8v_to_u32(&bytes[bytes.len() - 8..bytes.len() - 4]).unwrap();
+        bytes.truncate(bytes.len() - footer_len as usize);
+        for (offset, size) in [entry, token] {
+            bytes.extend(u64_to_u8v(offset));
+            bytes.extend(u32_to_u8v(size));
+        }
+        let legacy = file_path(&dir, "legacy.bel");
+        std::fs::write(&legacy, bytes).unwrap();
+        let mut file = File::open(&legacy).await.unwrap();
+        let read = Footer::read_from(&mut file, false).await.unwrap();
+        assert_eq!(read.version, 0);
+        assert_eq!(read.main_roots().unwrap(), (entry, token));
+        let (dict, cache) = open(&legacy).await;
+        assert_eq!(
+            dict.search_entry(cache, "word0042").await.as_deref(),
+            Some("value of word 42")
+        );
+    }
+
+    #[tokio::test]
+    async fn size_estimate_is_close_to_saved_size() {
+        let dir = temp_dir("size_estimate_is_close_to_saved_size");
+        for (file, suffix_index) in [("plain.bel", false), ("suffix.bel", true)] {
+            let mut metadata = small_nodes(4096);
+            metadata.suffix_index = suffix_index;
+            let mut po = Beluga::new(metadata, BelFileType::Entry).unwrap();
+            for (name, value) in words(3000) {
+                po.input_entry(name, value.into_bytes());
+            }
+            po.input_token("value".to_string(), vec!["word0001".to_string()]);
+            let estimate = po.estimate_size();
+            let path = file_path(&dir, file);
+            po.save(&path).await.unwrap();
+            let actual = std::fs::metadata(&path).unwrap().len();
+            let error = estimate.abs_diff(actual) as f64 / actual as f64;
+            assert!(
+                error < 0.2,
+                "{}: estimated {} saved {}",
+                file,
+                estimate,
+                actual
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn specs_out_of_range_are_rejected() {
+        let dir = temp_dir("specs_out_of_range_are_rejected");
+        let path = save_words(&dir, "spec.bel", 10).await;
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(u8v_to_u16(&bytes[4..6]).unwrap(), SPEC);
+        assert!((MIN_SPEC..=MAX_SPEC).contains(&SPEC));
+        for spec in [MIN_SPEC - 1, MAX_SPEC + 1] {
+            let mut changed = bytes.clone();
+            changed[4..6].copy_from_slice(&u16_to_u8v(spec));
+            let other = file_path(&dir, &format!("spec{}.bel", spec));
+            std::fs::write(&other, changed).unwrap();
+            let unsupported = |r: Result<()>| matches!(r, Err(Error::UnsupportedSpec { found, .. }) if found == spec);
+            assert!(unsupported(Beluga::read_metadata(&other).await.map(|_| ())));
+            assert!(unsupported(Beluga::from_file(&other).await.map(|_| ())));
+            assert!(unsupported(
+                crate::dictionary::Dictionary::new(&other, 0)
+                    .await
+                    .map(|_| ())
+            ));
+        }
+    }
+
+    #[cfg(feature = "zstd")]
+    #[tokio::test]
+    async fn trained_zstd_dictionary_shrinks_small_nodes() {
+        let dir = temp_dir("trained_zstd_dictionary_shrinks_small_nodes");
+        let mut metadata = small_nodes(1024);
+        metadata.compression = COMPRESSION_ZSTD.to_string();
+        let mut po = Beluga::new(metadata.clone(), BelFileType::Entry).unwrap();
+        for (name, value) in words(3000) {
+            po.input_entry(name, value.into_bytes());
+        }
+        let saved = file_path(&dir, "saved.bel");
+        po.save(&saved).await.unwrap();
+        let trained = Beluga::read_metadata(&saved).await.unwrap().zstd_dictionary;
+        assert!(!trained.is_empty());
+        let (dict, cache) = open(&saved).await;
+        assert_eq!(
+            dict.search_entry(cache, "word2999").await.as_deref(),
+            Some("value of word 2999")
+        );
+
+        let mut sizes = vec![];
+        for (file, zstd_dictionary) in [("plain.bel", String::new()), ("trained.bel", trained)] {
+            let path = file_path(&dir, file);
+            let mut metadata = metadata.clone();
+            metadata.zstd_dictionary = zstd_dictionary;
+            let mut builder = BelugaBuilder::new(&path, metadata).await.unwrap();
+            for (name, value) in words(3000) {
+                builder.input_entry(name, value.into_bytes()).await.unwrap();
+            }
+            builder.finish().await.unwrap();
+            assert_eq!(Beluga::verify(&path).await.unwrap().anomaly, None);
+            sizes.push(std::fs::metadata(&path).unwrap().len());
+        }
+        assert!(sizes[1] < sizes[0], "{:?}", sizes);
+    }
+
+    #[tokio::test]
+    async fn corrupt_token_values_decode_lossily() {
+        // a good name, one that is not UTF-8 and a truncated one
+        let mut blob = token_value(vec!["ice cream".to_string()]).0;
+        blob.extend(u16_to_u8v(3));
+        blob.extend(b"ca\xff");
+        blob.extend(u16_to_u8v(10));
+        blob.extend(b"tr");
+        assert!(Beluga::parse_token_entries(&blob).is_err());
+        assert_eq!(
+            Beluga::parse_token_entries_with(&blob, false).unwrap(),
+            vec!["ice cream", "ca\u{fffd}"]
+        );
+
+        let dir = temp_dir("corrupt_token_values_decode_lossily");
+        let path = file_path(&dir, "tokens.bel");
+        let mut po = Beluga::new(Metadata::new(), BelFileType::Entry).unwrap();
+        po.input_entry("cream".to_string(), b"dairy".to_vec());
+        po.input_entry("ice cream".to_string(), b"dessert".to_vec());
+        po.token_tree
+            .insert(EntryKey("cream".to_string()), EntryValue(blob));
+        po.save(&path).await.unwrap();
+        let (dict, cache) = open(&path).await;
+        let options = crate::dictionary::SearchOptions::new().phrase_limit(5);
+        assert_eq!(
+            dict.search(cache.clone(), "cream", &options).await,
+            vec!["cream", "ice cream", "ca\u{fffd}"]
+        );
+        let options = options.strict_tokens(true);
+        assert_eq!(dict.search(cache, "cream", &options).await, vec!["cream"]);
+    }
+
+    #[test]
+    fn tokenize_skips_tags_and_entities() {
+        assert_eq!(
+            tokenize("<b>Salt&amp;pepper</b>&nbsp;to taste,&#160;AT&T"),
+            vec!["salt", "pepper", "to", "taste", "at", "t"]
+        );
+        // without the semicolon it is text
+        assert_eq!(tokenize("fish &amp chips"), vec!["fish", "amp", "chips"]);
+    }
 }
@@ -0,0 +1,169 @@
+use crate::error::{Error, Result};
+use crate::utils::fold_diacritics;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::{Arc, OnceLock, RwLock};
+
+pub const DEFAULT_COLLATION: &str = "default";
+
+/// Order of the headwords in a file. Trees compare names by their sort key,
+/// names with equal sort keys sit side by side and are told apart by an exact
+/// comparison. A file is built and read with the collator named by
+/// `Metadata::collation`, otherwise lookups break.
+pub trait Collator: Debug + Send + Sync {
+    /// Stored in the metadata to find the collator again when reading
+    fn id(&self) -> &str;
+
+    /// Key `name` is sorted by, compared by code point. It must keep
+    /// prefixes, i.e. the key of a prefix of `name` is a prefix of its key,
+    /// for prefix search to work.
+    fn sort_key(&self, name: &str) -> String;
+}
+
+/// Code point order ignoring case, what files without a collation use
+#[derive(Debug)]
+pub struct DefaultCollator;
+
+impl Collator for DefaultCollator {
+    fn id(&self) -> &str {
+        DEFAULT_COLLATION
+    }
+
+    fn sort_key(&self, name: &str) -> String {
+        name.to_lowercase()
+    }
+}
+
+/// German dictionary order (DIN 5007-1): umlauts sort with their base
+/// letter and ß as ss.
+#[derive(Debug)]
+pub struct GermanCollator;
+
+impl Collator for GermanCollator {
+    fn id(&self) -> &str {
+        "de"
+    }
+
+    fn sort_key(&self, name: &str) -> String {
+        fold_diacritics(&name.to_lowercase().replace('ß', "ss"))
+    }
+}
+
+/// Swedish order: å, ä and ö are letters of their own after z, æ and ø sort
+/// as ä and ö, other accented letters with their base letter.
+#[derive(Debug)]
+pub struct SwedishCollator;
+
+impl Collator for SwedishCollator {
+    fn id(&self) -> &str {
+        "sv"
+    }
+
+    fn sort_key(&self, name: &str) -> String {
+        // private use code points above every letter keep them after z
+        let name: String = name
+            .to_lowercase()
+            .chars()
+            .map(|c| match c {
+                'å' => '\u{e000}',
+                'ä' | 'æ' => '\u{e001}',
+                'ö' | 'ø' => '\u{e002}',
+                _ => c,
+            })
+            .collect();
+        fold_diacritics(&name)
+    }
+}
+
+fn registry() -> &'static RwLock<HashMap<String, Arc<dyn Collator>>> {
+    static REGISTRY: OnceLock<RwLock<HashMap<String, Arc<dyn Collator>>>> = OnceLock::new();
+    REGISTRY.get_or_init(|| {
+        let builtin: [Arc<dyn Collator>; 3] = [
+            Arc::new(DefaultCollator),
+            Arc::new(GermanCollator),
+            Arc::new(SwedishCollator),
+        ];
+        RwLock::new(
+            builtin
+                .into_iter()
+                .map(|c| (c.id().to_string(), c))
+                .collect(),
+        )
+    })
+}
+
+/// Make `collator` available under its id, e.g. one backed by ICU for a
+/// locale not built in. It must be registered before building or reading a
+/// file using it.
+pub fn register_collator(collator: Arc<dyn Collator>) {
+    registry()
+        .write()
+        .unwrap()
+        .insert(collator.id().to_string(), collator);
+}
+
+/// The collator registered as `id`
+pub fn collator(id: &str) -> Result<Arc<dyn Collator>> {
+    registry()
+        .read()
+        .unwrap()
+        .get(id)
+        .cloned()
+        .ok_or_else(|| Error::UnknownCollation(id.to_string()))
+}
+
+pub fn default_collator() -> Arc<dyn Collator> {
+    Arc::new(DefaultCollator)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sorted(collator: &dyn Collator, words: &[&str]) -> Vec<String> {
+        let mut words: Vec<String> = words.iter().map(|w| w.to_string()).collect();
+        words.sort_by_key(|w| collator.sort_key(w));
+        words
+    }
+
+    #[test]
+    fn german_sorts_umlauts_with_base_letter() {
+        let words = sorted(
+            &GermanCollator,
+            &["Zug", "Öl", "ob", "Straße", "Strasse", "Stroh"],
+        );
+        assert_eq!(words, vec!["ob", "Öl", "Straße", "Strasse", "Stroh", "Zug"]);
+        assert_eq!(
+            GermanCollator.sort_key("Straße"),
+            GermanCollator.sort_key("strasse")
+        );
+    }
+
+    #[test]
+    fn swedish_sorts_extra_letters_after_z() {
+        let words = sorted(&SwedishCollator, &["ö", "ärm", "åt", "zon", "ost", "éa"]);
+        assert_eq!(words, vec!["éa", "ost", "zon", "åt", "ärm", "ö"]);
+    }
+
+    #[test]
+    fn collators_are_found_by_id() {
+        assert!(matches!(
+            collator("xx-test"),
+            Err(Error::UnknownCollation(id)) if id == "xx-test"
+        ));
+        #[derive(Debug)]
+        struct Reversed;
+        impl Collator for Reversed {
+            fn id(&self) -> &str {
+                "xx-reversed"
+            }
+            fn sort_key(&self, name: &str) -> String {
+                name.chars()
+                    .map(|c| char::from_u32(0x10ffff - c as u32).unwrap_or(c))
+                    .collect()
+            }
+        }
+        register_collator(Arc::new(Reversed));
+        assert_eq!(collator("xx-reversed").unwrap().id(), "xx-reversed");
+    }
+}
@@ -1,27 +1,37 @@
 use crate::error::{Error, Result};
-use flate2::read::DeflateDecoder;
 use tokio::{
     fs::{self, File},
-    io::{AsyncReadExt, AsyncSeekExt},
     sync::RwLock,
 };
 use tracing::{error, info, instrument, warn};
 
 use crate::{
-    beluga::{parse_file_type, BelFileType, Beluga, EntryKey, EntryValue, Metadata, EXT_RESOURCE},
+    beluga::{
+        node_codec, parse_file_type, BelFileType, Beluga, EntryKey, EntryValue, Footer, Metadata,
+        RootKind, EXT_RESOURCE,
+    },
+    collation::{collator, Collator},
     lru::{LruCache, SizedValue},
-    tree::{Node, Serializable},
-    utils::Scanner,
+    tree::{scan_leaf, LeafScan, Node, NodeCodec, Record, Serializable, Smoothable},
+    utils::{self, bounded_levenshtein, glob_match},
 };
 use std::{
-    io::{Read, SeekFrom},
+    borrow::Cow,
+    collections::HashMap,
+    io::{Read, Write},
     path::Path,
     sync::Arc,
+    time::{Duration, Instant},
 };
 
+/// Spec of the files written
 pub const SPEC: u16 = 1;
+/// Oldest and newest spec that can be read
+pub const MIN_SPEC: u16 = 1;
+pub const MAX_SPEC: u16 = 1;
 
-static REDIRECT: &str = "@@@LINK=";
+pub(crate) static REDIRECT: &str = "@@@LINK=";
+const MAX_REDIRECTS: usize = 16;
 
 type EntryNode = Node<EntryKey, EntryValue>;
 pub type NodeCache = LruCache<(u32, u64), DictNode>;
@@ -30,7 +40,12 @@ pub type NodeCache = LruCache<(u32, u64), DictNode>;
 pub struct DictNode {
     node: EntryNode,
     children: Vec<(u64, u32)>,
+    /// Bytes of the decoded node, its weight in the cache
     size: u64,
+    /// Bytes read from the file, to read the node again
+    zip_size: u32,
+    /// Left sibling of a leaf in a file linking leaves both ways
+    prev: (u64, u32),
 }
 
 impl DictNode {
@@ -39,6 +54,8 @@ impl DictNode {
             node,
             children: Vec::new(),
             size: 0,
+            zip_size: 0,
+            prev: (0, 0),
         }
     }
 }
@@ -49,87 +66,165 @@ impl SizedValue for DictNode {
     }
 }
 
+/// Whether `name` starts with `prefix` in case. With `smart_case` only the
+/// capitals of `prefix` have to match, its other letters match either case.
+fn starts_with_case(name: &str, prefix: &str, smart_case: bool) -> bool {
+    if !smart_case {
+        return name.starts_with(prefix);
+    }
+    let mut chars = name.chars();
+    prefix.chars().all(|p| match chars.next() {
+        Some(c) => c == p || (!p.is_uppercase() && c.to_lowercase().eq(p.to_lowercase())),
+        None => false,
+    })
+}
+
+/// `distance` edits as a score from 0 to 1 relative to the longer word of
+/// `len` characters, so one edit in a long word scores higher than in a
+/// short one
+fn similarity(distance: usize, len: usize) -> f64 {
+    if len == 0 {
+        return 1.0;
+    }
+    1.0 - distance as f64 / len as f64
+}
+
+/// Whether a smoothed key sorts after every key starting with `prefix`, i.e.
+/// a scan in tree order has left the prefix range.
+fn past_prefix(smooth_key: &str, prefix: &str) -> bool {
+    !smooth_key.starts_with(prefix) && smooth_key > prefix
+}
+
+/// Id of the resource file `file_name` belonging to the entry file
+/// `basename`, "" for `<basename><ext>` and "1" for `<basename>.1<ext>`.
+/// `None` for unrelated files, e.g. `cats.beld` next to `cat.bel`.
+fn resource_id<'a>(file_name: &'a str, basename: &str, ext: &str) -> Option<&'a str> {
+    let res_name = file_name.strip_suffix(ext)?;
+    if res_name == basename {
+        return Some("");
+    }
+    res_name
+        .strip_prefix(basename)?
+        .strip_prefix('.')
+        .filter(|id| !id.is_empty())
+}
+
+/// What `DictFile::copy_value_range` found
+enum ValueCopy {
+    Missing,
+    /// Bytes copied
+    Copied(u64),
+    /// Name the value redirects to
+    Redirect(String),
+}
+
 #[derive(Debug)]
 struct DictFile {
     id: String,
     metadata: Metadata,
-    file: File,
+    file: Arc<std::fs::File>,
     entry_root: (u64, u32),
     token_root: (u64, u32),
+    suffix_root: (u64, u32),
+    /// Resources embedded in an entry file, (0, 0) if none
+    resource_root: (u64, u32),
     cache_id: u32,
+    codec: NodeCodec,
+    /// The whole file mapped into memory, nodes are sliced from it
+    #[cfg(feature = "mmap")]
+    mmap: Option<Arc<memmap2::Mmap>>,
+    /// Longest time a single scan may take before returning what it found
+    scan_timeout: Option<Duration>,
+    collator: Arc<dyn Collator>,
 }
 
 impl DictFile {
-    async fn new(filepath: &str, cache_id: u32) -> Result<Self> {
+    async fn new(filepath: &str, cache_id: u32, key: Option<&[u8; 32]>) -> Result<Self> {
         let mut file = File::open(filepath).await?;
-        let spec = file.read_u16().await?;
-        if spec == SPEC {
-            let metadata_length = file.read_u32().await?;
-            info!("Read metadata: {}B", metadata_length);
-            let mut buf = vec![0; metadata_length as usize];
-            file.read_exact(&mut buf).await?;
-            let metadata = match serde_json::from_slice(&buf[..]) {
-                Ok(r) => r,
-                Err(_) => {
-                    error!("Fail to parse metadata");
-                    return Err(Error::Msg("fail to parse metadata".to_string()));
-                }
-            };
-            file.seek(SeekFrom::End(-24)).await?;
-            let mut buf = vec![0; 24];
-            file.read_exact(&mut buf).await?;
-            let mut scanner = Scanner::new(&buf);
-            let entry_root_offset = scanner.read_u64();
-            let entry_root_size = scanner.read_u32();
-            let token_root_offset = scanner.read_u64();
-            let token_root_size = scanner.read_u32();
-            info!(
-                entry_root_offset,
-                entry_root_size, token_root_offset, token_root_size
-            );
-            Ok(Self {
-                id: String::from(""),
-                metadata,
-                file,
-                entry_root: (entry_root_offset, entry_root_size),
-                token_root: (token_root_offset, token_root_size),
-                cache_id,
-            })
-        } else {
-            Err(Error::Msg("invalid beluga spec".to_string()))
-        }
+        let metadata = Beluga::read_metadata_from(&mut file).await?;
+        let footer = Footer::read_from(&mut file, metadata.suffix_index).await?;
+        let (entry_root, token_root) = footer.main_roots()?;
+        let suffix_root = footer.root(RootKind::Suffix).unwrap_or((0, 0));
+        let resource_root = footer.root(RootKind::Resource).unwrap_or((0, 0));
+        info!(
+            footer.version,
+            ?entry_root,
+            ?token_root,
+            ?suffix_root,
+            ?resource_root,
+            "Read footer"
+        );
+        let codec = node_codec(&metadata, key)?;
+        let collator = collator(&metadata.collation)?;
+        let file = file.into_std().await;
+        // Safety: dictionary files are not modified while they are open
+        #[cfg(feature = "mmap")]
+        let mmap = match unsafe { memmap2::Mmap::map(&file) } {
+            Ok(m) => Some(Arc::new(m)),
+            Err(e) => {
+                warn!("Fail to map file, fall back to reading it. {}", e);
+                None
+            }
+        };
+        Ok(Self {
+            id: String::from(""),
+            metadata,
+            file: Arc::new(file),
+            entry_root,
+            token_root,
+            suffix_root,
+            resource_root,
+            cache_id,
+            codec,
+            #[cfg(feature = "mmap")]
+            mmap,
+            scan_timeout: None,
+            collator,
+        })
     }
 
     #[instrument(skip(self, cache))]
     async fn get_node(
-        &mut self,
+        &self,
         cache: Arc<RwLock<NodeCache>>,
         offset: u64,
         size: u32,
     ) -> Option<DictNode> {
-        let cache_lock = cache.read().await;
-        if let Some(node) = cache_lock.get(&(self.cache_id, offset)) {
+        // a hit only needs the shared lock, see `LruCache::get`
+        let cached = cache.read().await.get(&(self.cache_id, offset));
+        if let Some(node) = cached {
             info!("Found in cache");
             return Some(node);
         }
-        drop(cache_lock);
-        if let Err(e) = self.file.seek(SeekFrom::Start(offset)).await {
-            error!("File Seeking error. {}", e);
-            return None;
+        let buf = self.read_node(offset, size).await?;
+        self.put_node(cache, offset, &buf).await
+    }
+
+    /// Bytes of the node at `offset`, sliced from the map or read from the
+    /// file
+    async fn read_node(&self, offset: u64, size: u32) -> Option<Cow<'_, [u8]>> {
+        #[cfg(feature = "mmap")]
+        if let Some(mmap) = &self.mmap {
+            return match mmap.get(offset as usize..offset as usize + size as usize) {
+                Some(buf) => Some(Cow::Borrowed(buf)),
+                None => {
+                    error!("Node is outside of the file. offset: {}", offset);
+                    None
+                }
+            };
         }
-        let mut buf = vec![0; size as usize];
-        match self.file.read_exact(&mut buf).await {
-            Ok(_) => {
-                let mut decode = DeflateDecoder::new(&buf[..]);
-                let mut data: Vec<u8> = vec![];
-                decode.read_to_end(&mut data).unwrap();
-                let (node, children) = Node::<EntryKey, EntryValue>::from_bytes(&data);
-                let mut dnode = DictNode::new(*node);
-                dnode.children = children;
-                let mut cache_lock = cache.write().await;
-                let value = cache_lock.put((self.cache_id, offset), dnode);
-                drop(cache_lock);
-                Some(value)
+        // positioned read, so concurrent lookups do not share a file cursor
+        let file = self.file.clone();
+        let read = tokio::task::spawn_blocking(move || {
+            let mut buf = vec![0; size as usize];
+            utils::read_exact_at(&file, &mut buf, offset).map(|_| buf)
+        })
+        .await;
+        match read {
+            Ok(Ok(buf)) => Some(Cow::Owned(buf)),
+            Ok(Err(e)) => {
+                error!("File Reading Error. {}", e);
+                None
             }
             Err(e) => {
                 error!("File Reading Error. {}", e);
@@ -138,96 +233,153 @@ impl DictFile {
         }
     }
 
+    /// Decode the node read at `offset` and keep it in the cache.
+    async fn put_node(
+        &self,
+        cache: Arc<RwLock<NodeCache>>,
+        offset: u64,
+        buf: &[u8],
+    ) -> Option<DictNode> {
+        let decoded = self
+            .codec
+            .split_links(buf)
+            .and_then(|(_, links)| Ok((self.codec.decode(buf, offset)?, links)));
+        let (data, links) = match decoded {
+            Ok(d) => d,
+            Err(e) => {
+                error!("Invalid node. {}", e);
+                return None;
+            }
+        };
+        let (node, mut children) = match Node::<EntryKey, EntryValue>::from_bytes(&data) {
+            Ok(r) => r,
+            Err(e) => {
+                error!("Invalid node. {}", e);
+                return None;
+            }
+        };
+        if let (true, Some(next)) = (node.is_leaf, links.next) {
+            children[0] = next;
+        }
+        let mut dnode = DictNode::new(*node);
+        dnode.children = children;
+        dnode.size = data.len() as u64;
+        dnode.zip_size = buf.len() as u32;
+        dnode.prev = links.prev;
+        let mut cache_lock = cache.write().await;
+        let value = cache_lock.put((self.cache_id, offset), dnode);
+        drop(cache_lock);
+        Some(value)
+    }
+
     #[instrument(skip(self, cache))]
+    /// Headwords starting with `name`, up to `prefix_limit`. How case is
+    /// compared depends on `fold_case`, `strict` and `smart_case`, see
+    /// `SearchOptions`. With `fold_diacritics`
+    /// accents are ignored as well, e.g. "resume" finds "résumé"; the
+    /// candidates are then the headwords sharing the first letter of `name`,
+    /// in tree order, so accented first letters are not widened. Repeated
+    /// headwords are listed once. With `after` set the scan starts right
+    /// behind that headword, so pages can be fetched one after another.
     pub async fn search(
-        &mut self,
+        &self,
         cache: Arc<RwLock<NodeCache>>,
         name: &str,
-        strict: bool,
-        prefix_limit: usize,
+        options: &SearchOptions,
     ) -> Vec<String> {
         let mut result: Vec<String> = Vec::new();
-        let mut offset = self.entry_root.0;
-        let mut size = self.entry_root.1;
-        loop {
-            let dict_node = match self.get_node(cache.clone(), offset, size).await {
-                Some(nd) => nd,
-                None => {
-                    error!("Node not exists: offset: {}, size: {}", offset, size);
-                    return result;
-                }
-            };
-            let dn = dict_node;
-            let node = &dn.node;
-            let key = EntryKey(name.to_string());
-            let (wi, cr) = dn.node.index_of(&key);
-            if node.is_leaf {
-                info!("Node is LEAF");
-                let lower_name = name.to_lowercase();
-                let idx = if cr.is_le() { wi } else { wi + 1 };
-                for i in idx..node.records.len() {
-                    let k = &node.records[i].key;
-                    info!("Checking match: {}", k,);
-                    if k.0.to_lowercase().starts_with(lower_name.as_str()) {
-                        if (strict && k.0.starts_with(name)) || !strict {
-                            result.push(k.0.clone());
-                        }
-                    } else {
-                        return result;
-                    }
-                    if result.len() >= prefix_limit {
-                        return result;
-                    }
+        let prefix_limit = options.prefix_limit;
+        if prefix_limit == 0 {
+            return result;
+        }
+        let fold_diacritics = options.fold_diacritics;
+        // the collator folds case, finer checks are made on the headword
+        let exact_case = options.strict || !options.fold_case;
+        let smart_case = options.smart_case && !exact_case;
+        let check_case = exact_case || smart_case;
+        let fold = |s: &str| {
+            if fold_diacritics {
+                utils::fold_diacritics(s)
+            } else {
+                s.to_string()
+            }
+        };
+        let key = EntryKey::normalized(name);
+        let lower_name = fold(&key.smooth(&*self.collator).0);
+        let strict_name = fold(&key.0);
+        let start: String = if fold_diacritics {
+            lower_name.chars().take(1).collect()
+        } else {
+            lower_name.clone()
+        };
+        let root = self.entry_root;
+        let after = options.after.as_ref().map(|a| EntryKey::normalized(a));
+        let after_smooth = after.as_ref().map(|a| a.smooth(&*self.collator));
+        let mut skipping = after.is_some();
+        let mut passed = false;
+        let from = after
+            .as_ref()
+            .map(|a| a.0.clone())
+            .unwrap_or_else(|| start.clone());
+        self.scan(cache, root, &from, |k, _| {
+            info!("Checking match: {}", k);
+            if skipping {
+                // Equal keys in different case sit side by side, skip up to
+                // and including the one the previous page ended with.
+                if Some(k) == after.as_ref() {
+                    passed = true;
+                    return true;
                 }
-                let mut next_offset = dn.children[0].0;
-                let mut next_size = dn.children[0].1;
-                loop {
-                    info!("Searching from next sibling");
-                    if next_offset == 0 {
-                        info!("No next sibling");
-                        return result;
-                    }
-                    if let Some(dn) = self.get_node(cache.clone(), next_offset, next_size).await {
-                        for rec in &dn.node.records {
-                            let k = &rec.key.0;
-                            info!("Checking match: {}", k);
-                            if k.to_lowercase().starts_with(lower_name.as_str()) {
-                                if (strict && k.starts_with(name)) || !strict {
-                                    result.push(k.clone());
-                                }
-                            } else {
-                                return result;
-                            }
-                            if result.len() >= prefix_limit {
-                                return result;
-                            }
-                        }
-                        next_offset = dn.children[0].0;
-                        next_size = dn.children[0].1;
-                    } else {
-                        return result;
-                    }
+                if !passed && Some(k.smooth(&*self.collator)) == after_smooth {
+                    return true;
                 }
-            } else {
-                info!("Node is INDEX");
-                if cr.is_le() {
-                    (offset, size) = dn.children[wi];
-                } else {
-                    (offset, size) = dn.children[wi + 1];
-                };
+                skipping = false;
             }
-        }
+            let lower = k.smooth(&*self.collator).0;
+            if !lower.starts_with(start.as_str()) {
+                // keep going over keys sorting before the prefix range
+                return !past_prefix(&lower, &start);
+            }
+            if fold(&lower).starts_with(lower_name.as_str())
+                && (!check_case || starts_with_case(&fold(&k.0), &strict_name, smart_case))
+                && result.last() != Some(&k.0)
+            {
+                result.push(k.0.clone());
+            }
+            result.len() < prefix_limit
+        })
+        .await;
+        result
     }
 
-    #[instrument(skip(self, cache))]
-    pub async fn search_entry(
-        &mut self,
+    /// Descend to the leaf holding the first record equal to `name`, returning
+    /// the leaf and the record index to start scanning from.
+    async fn seek_leaf(
+        &self,
         cache: Arc<RwLock<NodeCache>>,
         root: (u64, u32),
         name: &str,
-    ) -> Option<Vec<u8>> {
-        let mut offset = root.0;
-        let mut size = root.1;
+    ) -> Option<(DictNode, usize)> {
+        self.seek_path(cache, root, name).await?.pop()
+    }
+
+    /// `seek_leaf` keeping the nodes passed on the way down, each with the
+    /// index of the child taken, so `prev_leaf` can go back up.
+    async fn seek_path(
+        &self,
+        cache: Arc<RwLock<NodeCache>>,
+        root: (u64, u32),
+        name: &str,
+    ) -> Option<Vec<(DictNode, usize)>> {
+        // an empty tree, e.g. the token tree of a file without tokens, is
+        // written as a zero root
+        if root.1 == 0 {
+            return None;
+        }
+        let key = EntryKey::normalized(name);
+        let smooth_key = key.smooth(&*self.collator);
+        let (mut offset, mut size) = root;
+        let mut path: Vec<(DictNode, usize)> = vec![];
         loop {
             let dict_node = match self.get_node(cache.clone(), offset, size).await {
                 Some(nd) => nd,
@@ -236,261 +388,2825 @@ impl DictFile {
                     return None;
                 }
             };
-            let node = dict_node.node;
-            let key = EntryKey(name.to_string());
-            let (index, cr) = node.index_of(&key);
-            if node.is_leaf {
-                info!("Node is LEAF");
-                let records = &node.records;
-                if cr.is_ge() {
-                    for i in index..records.len() {
-                        let rec = &records[i];
-                        info!("Checking match. {}", rec.key);
-                        if rec.key == key {
-                            return Some(rec.value.as_ref().unwrap().bytes());
-                        }
-                    }
-                    let mut next_offset = dict_node.children[0].0;
-                    let mut next_size = dict_node.children[0].1;
-                    loop {
-                        if next_offset == 0 {
-                            return None;
-                        }
-                        if let Some(dict_node) =
-                            self.get_node(cache.clone(), next_offset, next_size).await
-                        {
-                            let node = dict_node.node;
-                            for rec in &node.records {
-                                let k = &rec.key.0;
-                                info!("Checking match: {}", k);
-                                if k == name {
-                                    return Some(rec.value.as_ref().unwrap().bytes());
-                                }
-                                if k.to_lowercase() != name {
-                                    return None;
-                                }
-                            }
-                            next_offset = dict_node.children[0].0;
-                            next_size = dict_node.children[0].1;
-                        } else {
-                            return None;
-                        }
-                    }
-                }
-                warn!("Entry not exists");
-                return None;
+            let index = self.seek_index(&dict_node.node, &key, &smooth_key);
+            if dict_node.node.is_leaf {
+                path.push((dict_node, index));
+                return Some(path);
             }
-            info!("Node is INDEX");
-            (offset, size) = if cr.is_le() {
-                dict_node.children[index]
-            } else {
-                dict_node.children[index + 1]
-            };
+            (offset, size) = dict_node.children[index];
+            path.push((dict_node, index));
         }
     }
-}
-
-pub struct Dictionary {
-    dir: String,
-    basename: String,
-    entry: DictFile,
-    resources: Vec<DictFile>,
-    css_js: Option<(String, String)>,
-}
 
-impl Dictionary {
-    pub async fn new(filepath: &str, mut cache_id: u32) -> Result<(Self, u32)> {
-        let file_type = parse_file_type(filepath)?;
-        if !matches!(file_type, BelFileType::Entry) {
-            error!("invalid entry file extension");
-            return Err(Error::Msg("not a entry file".to_string()));
+    /// Index of the first record of `node` not less than `key`, or of the
+    /// child to descend to for it
+    fn seek_index(&self, node: &EntryNode, key: &EntryKey, smooth_key: &EntryKey) -> usize {
+        let (mut index, cr) = node.index_of(key, &*self.collator);
+        if cr.is_le() {
+            while index > 0 && node.records[index - 1].key.smooth(&*self.collator) == *smooth_key {
+                index -= 1;
+            }
+        } else {
+            index += 1;
         }
-        let p = Path::new(filepath);
-        if !p.exists() || p.is_dir() {
-            error!("File not exists or it is a directory");
-            return Err(Error::Msg(format!("invalid path. {:?}", p)));
+        index
+    }
+
+    /// Copy bytes `start..end` of the value stored exactly under `name` in
+    /// the tree at `root` to `w`, clamped to its length. Index nodes are
+    /// read through `cache`, but a leaf not cached yet is decoded as a
+    /// stream and not cached, so neither the leaf nor the value is held in
+    /// memory whole. Names with equal sort keys, e.g. "A.png" and "a.png",
+    /// may spill over into the next leaves, which are followed as long as
+    /// they end with such names. A value starting with `marker` is returned
+    /// as a redirect instead.
+    #[allow(clippy::too_many_arguments)]
+    async fn copy_value_range(
+        &self,
+        cache: Arc<RwLock<NodeCache>>,
+        root: (u64, u32),
+        name: &str,
+        start: u64,
+        end: u64,
+        marker: &str,
+        w: &mut dyn Write,
+    ) -> Result<ValueCopy> {
+        let (mut offset, mut size) = root;
+        if size == 0 {
+            return Ok(ValueCopy::Missing);
         }
-        info!("Load entry file");
-        let entry = DictFile::new(filepath, cache_id).await?;
-        let basename = p.file_stem().unwrap().to_str().unwrap();
-        let mut resources: Vec<DictFile> = Vec::new();
-        let dir = match p.parent() {
-            Some(d) => d,
-            None => {
-                error!("File has no parent directory, weird???");
-                return Err(Error::Msg("invalid file path".to_string()));
+        let key = EntryKey::normalized(name);
+        let smooth_key = key.smooth(&*self.collator);
+        let mut copy = |value: &mut dyn Read, len: u64| -> Result<ValueCopy> {
+            let mut head: Vec<u8> = Vec::with_capacity(marker.len());
+            value.take(marker.len() as u64).read_to_end(&mut head)?;
+            if head == marker.as_bytes() {
+                let mut target: Vec<u8> = vec![];
+                value.read_to_end(&mut target)?;
+                let target = String::from_utf8_lossy(&target).trim().to_string();
+                return Ok(ValueCopy::Redirect(target));
             }
+            let mut value = head.as_slice().chain(value);
+            let end = end.min(len);
+            let start = start.min(end);
+            std::io::copy(&mut (&mut value).take(start), &mut std::io::sink())?;
+            let copied = std::io::copy(&mut value.take(end - start), &mut *w)?;
+            Ok(ValueCopy::Copied(copied))
         };
-        let res_ext = String::from(".") + EXT_RESOURCE;
-        info!("Search related resource files");
-        for ret in dir.read_dir().expect("Fail to read dictionary directory") {
-            if let Ok(entry) = ret {
-                if !entry.metadata().unwrap().is_file() {
-                    continue;
-                }
-                let name = entry.file_name().into_string().unwrap();
-                if name.ends_with(res_ext.as_str()) {
-                    let res_name = &name[0..name.len() - res_ext.len()];
-                    if Some(0) == res_name.find(basename) {
-                        let mut res_id = "";
-                        let mut is_res = false;
-                        if res_name.len() == basename.len() {
-                            is_res = true
-                        } else if res_name.len() > basename.len() + 1
-                            && res_name.as_bytes()[basename.len() + 1] as char == '.'
-                        {
-                            is_res = true;
-                            res_id = &res_name[basename.len() + 1..];
-                        }
-                        if is_res {
-                            cache_id += 1;
-                            info!("Load resource file. {}", name);
-                            let mut res =
-                                DictFile::new(dir.join(&name).to_str().unwrap(), cache_id).await?;
-                            res.id = String::from(res_id);
-                            resources.push(res);
+        loop {
+            let cached = cache.read().await.get(&(self.cache_id, offset));
+            let dict_node = match cached {
+                Some(n) => n,
+                None => {
+                    let buf = self
+                        .read_node(offset, size)
+                        .await
+                        .ok_or(Error::Corrupt { offset, size })?;
+                    let reader = self.codec.decoder(&buf, offset)?;
+                    match scan_leaf(reader, &key.bytes(), self.codec.checksum, &mut copy)? {
+                        LeafScan::Found(r) => return Ok(r),
+                        LeafScan::Missing { last_key, next } => {
+                            let last_key = last_key
+                                .map(|k| EntryKey(String::from_utf8_lossy(&k).into_owned()));
+                            let next = self.codec.split_links(&buf)?.1.next.unwrap_or(next);
+                            if next.1 == 0
+                                || last_key.is_none_or(|k| k.smooth(&*self.collator) > smooth_key)
+                            {
+                                return Ok(ValueCopy::Missing);
+                            }
+                            (offset, size) = next;
+                            continue;
                         }
+                        LeafScan::Index => {}
                     }
+                    self.put_node(cache.clone(), offset, &buf)
+                        .await
+                        .ok_or(Error::Corrupt { offset, size })?
+                }
+            };
+            if dict_node.node.is_leaf {
+                let records = &dict_node.node.records;
+                if let Some(r) = records.iter().find(|r| r.key == key) {
+                    let value = &r.value.as_ref().unwrap().0;
+                    return copy(&mut value.as_slice(), value.len() as u64);
+                }
+                let next = dict_node.children[0];
+                if next.1 == 0
+                    || records
+                        .last()
+                        .is_none_or(|r| r.key.smooth(&*self.collator) > smooth_key)
+                {
+                    return Ok(ValueCopy::Missing);
                 }
+                (offset, size) = next;
+                continue;
             }
+            let index = self.seek_index(&dict_node.node, &key, &smooth_key);
+            (offset, size) = dict_node.children[index];
         }
-        Ok((
-            Self {
-                dir: dir.to_str().unwrap().to_string(),
-                basename: basename.to_string(),
-                entry,
-                resources,
-                css_js: None,
-            },
-            cache_id,
-        ))
     }
 
-    pub async fn get_css_js(&mut self, disable_cache: bool) -> Result<(String, String)> {
-        if let Some(v) = &self.css_js {
-            if disable_cache {
-                self.css_js = None;
-            } else {
-                return Ok(v.clone());
+    /// Move `path` from its leaf to the leaf on the left, the index set past
+    /// its last record; `None` at the first leaf. A file with `prev_links`
+    /// links there directly, the rest of `path` is then left as it was.
+    /// Otherwise leaves only link to the right, so this goes up to the
+    /// nearest node with a child further left and down the rightmost
+    /// children of that child.
+    async fn prev_leaf(
+        &self,
+        cache: Arc<RwLock<NodeCache>>,
+        path: &mut Vec<(DictNode, usize)>,
+    ) -> Option<()> {
+        let (leaf, _) = path.pop()?;
+        if self.codec.prev_links {
+            if leaf.prev.1 == 0 {
+                return None;
             }
+            let dict_node = self.get_node(cache, leaf.prev.0, leaf.prev.1).await?;
+            let len = dict_node.node.records.len();
+            path.push((dict_node, len));
+            return Some(());
         }
-        let dir = Path::new(&self.dir);
-        let mut js = String::new();
-        let js_file = dir.join(format!("{}.js", self.basename));
-        if js_file.is_file() {
-            info!("Load JavaScript file. {:?}", js_file);
-            match fs::read_to_string(js_file).await {
-                Ok(text) => js = text,
-                Err(e) => {
-                    error!("Fail to read file. {}", e);
-                    return Err(Error::Msg("invalid javascript file".to_string()));
-                }
+        loop {
+            let (node, index) = path.last_mut()?;
+            if *index == 0 {
+                path.pop();
+                continue;
             }
-        }
-        let mut css = String::new();
-        let css_file = dir.join(format!("{}.css", self.basename));
-        if css_file.is_file() {
-            info!("Load CSS file. {:?}", css_file);
-            match fs::read_to_string(css_file).await {
-                Ok(text) => css = text,
-                Err(e) => {
-                    error!("Fail to read file. {}", e);
-                    return Err(Error::Msg("invalid css file".to_string()));
+            *index -= 1;
+            let mut child = node.children[*index];
+            loop {
+                let dict_node = self.get_node(cache.clone(), child.0, child.1).await?;
+                if dict_node.node.is_leaf {
+                    let len = dict_node.node.records.len();
+                    path.push((dict_node, len));
+                    return Some(());
                 }
+                let last = dict_node.children.len() - 1;
+                child = dict_node.children[last];
+                path.push((dict_node, last));
             }
         }
-        if !cfg!(debug_assertions) || !disable_cache {
-            self.css_js = Some((css.clone(), js.clone()));
-        }
-        Ok((css, js))
     }
 
-    pub fn metadata(&self) -> Metadata {
-        self.entry.metadata.clone()
+    /// Walk leaf records in order, starting from the first one not less than
+    /// `name` and following sibling links, until `walk` returns false or the
+    /// scan timeout is up.
+    async fn scan<F>(
+        &self,
+        cache: Arc<RwLock<NodeCache>>,
+        root: (u64, u32),
+        name: &str,
+        mut walk: F,
+    ) where
+        F: FnMut(&EntryKey, &EntryValue) -> bool,
+    {
+        let deadline = self.scan_timeout.map(|t| Instant::now() + t);
+        let (mut dict_node, mut index) = match self.seek_leaf(cache.clone(), root, name).await {
+            Some(r) => r,
+            None => return,
+        };
+        loop {
+            for rec in dict_node.node.records.iter().skip(index) {
+                if !walk(&rec.key, rec.value.as_ref().unwrap()) {
+                    return;
+                }
+            }
+            let (next_offset, next_size) = dict_node.children[0];
+            if next_offset == 0 {
+                info!("No next sibling");
+                return;
+            }
+            if deadline.is_some_and(|d| Instant::now() >= d) {
+                warn!("Scan timed out, stop at {}", name);
+                return;
+            }
+            dict_node = match self.get_node(cache.clone(), next_offset, next_size).await {
+                Some(nd) => nd,
+                None => return,
+            };
+            index = 0;
+        }
     }
 
-    #[instrument(skip(self, cache))]
-    pub async fn search(
-        &mut self,
+    /// Headwords around `name`: up to `before` headwords preceding the first
+    /// one not less than `name`, that one and up to `after` following it. If
+    /// every headword sorts before `name` the window ends at the last one.
+    /// Each headword is listed once.
+    pub async fn around(
+        &self,
         cache: Arc<RwLock<NodeCache>>,
         name: &str,
-        strict: bool,
-        prefix_limit: usize,
-        phrase_limit: usize,
+        before: usize,
+        after: usize,
     ) -> Vec<String> {
-        info!("Search entry");
-        let mut result = self
-            .entry
-            .search(cache.clone(), name, strict, prefix_limit)
-            .await;
-        if phrase_limit > 0 && self.entry.token_root.1 != 0 {
-            info!("Search TOKEN entries");
-            if let Some(data) = self
-                .entry
-                .search_entry(cache.clone(), self.entry.token_root, name)
-                .await
-            {
-                let entries = Beluga::parse_token_entries(&data);
-                info!("Found {} entry(ies) by TOKEN", entries.len());
-                let mut token_count = 0;
-                for entry_name in entries {
-                    if !result.contains(&entry_name) {
-                        if token_count >= phrase_limit {
-                            break;
+        let root = self.entry_root;
+        let mut following: Vec<String> = vec![];
+        self.scan(cache.clone(), root, name, |k, _| {
+            if following.last() != Some(&k.0) {
+                following.push(k.0.clone());
+            }
+            following.len() <= after
+        })
+        .await;
+        let wanted = before + usize::from(following.is_empty());
+        let mut preceding: Vec<String> = vec![];
+        if wanted > 0 {
+            if let Some(mut path) = self.seek_path(cache.clone(), root, name).await {
+                'leaves: loop {
+                    let (leaf, index) = path.last().unwrap();
+                    for rec in leaf.node.records[..*index].iter().rev() {
+                        if preceding.last() != Some(&rec.key.0) {
+                            if preceding.len() == wanted {
+                                break 'leaves;
+                            }
+                            preceding.push(rec.key.0.clone());
                         }
-                        result.push(entry_name);
-                        token_count += 1;
+                    }
+                    if self.prev_leaf(cache.clone(), &mut path).await.is_none() {
+                        break;
                     }
                 }
             }
         }
-        result
+        preceding.reverse();
+        preceding.extend(following);
+        preceding
     }
 
+    /// All values stored under `name`, in insertion order.
     #[instrument(skip(self, cache))]
-    pub async fn search_entry(
-        &mut self,
+    pub async fn search_entry_all(
+        &self,
         cache: Arc<RwLock<NodeCache>>,
+        root: (u64, u32),
         name: &str,
-    ) -> Option<String> {
-        let max_redirects = 3;
-        let mut keyword = name.to_string();
-        for _ in 0..max_redirects {
-            if let Some(data) = self
-                .entry
-                .search_entry(cache.clone(), self.entry.entry_root, &keyword)
-                .await
-            {
-                if let Ok(content) = String::from_utf8(data) {
-                    let s = content.trim();
-                    if s.starts_with(REDIRECT) {
-                        let (_, kw) = s.split_at(REDIRECT.len());
-                        keyword = kw.to_string();
-                    } else {
-                        return Some(content);
-                    }
-                }
+    ) -> Vec<Vec<u8>> {
+        let mut result: Vec<Vec<u8>> = Vec::new();
+        let key = EntryKey::normalized(name);
+        let smooth_key = key.smooth(&*self.collator);
+        self.scan(cache, root, name, |k, v| {
+            info!("Checking match. {}", k);
+            if k.smooth(&*self.collator) != smooth_key {
+                return false;
             }
-        }
-        None
+            if *k == key {
+                result.push(v.bytes());
+            }
+            true
+        })
+        .await;
+        result
     }
 
+    /// Headwords matching a glob `pattern` where `*` matches any run of
+    /// characters and `?` a single one, compared case-insensitively.
     #[instrument(skip(self, cache))]
-    pub async fn search_resource(
-        &mut self,
+    pub async fn search_glob(
+        &self,
         cache: Arc<RwLock<NodeCache>>,
-        name: &str,
-    ) -> Option<Vec<u8>> {
-        info!("Resource name: {}", name);
-        for (_, dict) in self.resources.iter_mut().enumerate() {
-            if let Some(v) = dict
-                .search_entry(cache.clone(), dict.entry_root, name)
-                .await
-            {
-                return Some(v);
+        pattern: &str,
+        limit: usize,
+    ) -> Vec<String> {
+        let mut result: Vec<String> = Vec::new();
+        if limit == 0 {
+            return result;
+        }
+        let pattern = EntryKey::normalized(pattern).smooth(&*self.collator).0;
+        let prefix = match pattern.find(['*', '?']) {
+            Some(i) => &pattern[..i],
+            None => pattern.as_str(),
+        };
+        let root = self.entry_root;
+        self.scan(cache, root, prefix, |k, _| {
+            let name = k.smooth(&*self.collator).0;
+            if !name.starts_with(prefix) {
+                return !past_prefix(&name, prefix);
+            }
+            if glob_match(&pattern, &name) && result.last() != Some(&k.0) {
+                result.push(k.0.clone());
             }
+            result.len() < limit
+        })
+        .await;
+        result
+    }
+
+    /// Headwords for which `pred` holds, in tree order, scanning from the
+    /// first leaf until `limit` are found or the scan timeout is up.
+    pub async fn filter_headwords<F>(
+        &self,
+        cache: Arc<RwLock<NodeCache>>,
+        pred: F,
+        limit: usize,
+    ) -> Vec<String>
+    where
+        F: Fn(&str) -> bool,
+    {
+        let mut result: Vec<String> = Vec::new();
+        if limit == 0 {
+            return result;
         }
-        info!("Invalid resource ID");
-        None
+        let root = self.entry_root;
+        self.scan(cache, root, "", |k, _| {
+            if result.last() != Some(&k.0) && pred(&k.0) {
+                result.push(k.0.clone());
+            }
+            result.len() < limit
+        })
+        .await;
+        result
+    }
+
+    /// Headwords within `max_distance` edits of `name` and at least
+    /// `min_similarity` similar, with their similarity, most similar first.
+    /// Words are compared by their sort keys, as `search` compares them.
+    /// Candidates are the headwords whose sort key starts with the same
+    /// character as that of `name`; a name of `max_distance` characters or
+    /// less may be edited away entirely, so every headword is a candidate.
+    #[instrument(skip(self, cache))]
+    pub async fn search_fuzzy(
+        &self,
+        cache: Arc<RwLock<NodeCache>>,
+        name: &str,
+        max_distance: usize,
+        min_similarity: f64,
+        limit: usize,
+    ) -> Vec<(String, f64)> {
+        let target: Vec<char> = EntryKey::normalized(name)
+            .smooth(&*self.collator)
+            .0
+            .chars()
+            .collect();
+        let prefix: String = if target.len() > max_distance {
+            target[..1].iter().collect()
+        } else {
+            String::new()
+        };
+        let mut candidates: Vec<(String, f64)> = Vec::new();
+        let root = self.entry_root;
+        self.scan(cache, root, &prefix, |k, _| {
+            let key = k.smooth(&*self.collator).0;
+            if !key.starts_with(prefix.as_str()) {
+                return !past_prefix(&key, &prefix);
+            }
+            if candidates.last().map(|c| &c.0) == Some(&k.0) {
+                return true;
+            }
+            let chars: Vec<char> = key.chars().collect();
+            if let Some(d) = bounded_levenshtein(&target, &chars, max_distance) {
+                let score = similarity(d, target.len().max(chars.len()));
+                if score >= min_similarity {
+                    candidates.push((k.0.clone(), score));
+                }
+            }
+            true
+        })
+        .await;
+        candidates.sort_by(|a, b| b.1.total_cmp(&a.1));
+        candidates.truncate(limit);
+        candidates
+    }
+
+    /// Headwords ending with `suffix`, found by a prefix scan of the reversed
+    /// headword tree. Empty when the file was built without it.
+    #[instrument(skip(self, cache))]
+    pub async fn search_suffix(
+        &self,
+        cache: Arc<RwLock<NodeCache>>,
+        suffix: &str,
+        limit: usize,
+    ) -> Vec<String> {
+        let mut result: Vec<String> = Vec::new();
+        if limit == 0 || self.suffix_root.1 == 0 {
+            return result;
+        }
+        let reversed: String = EntryKey::normalized(suffix)
+            .smooth(&*self.collator)
+            .0
+            .chars()
+            .rev()
+            .collect();
+        let root = self.suffix_root;
+        self.scan(cache, root, &reversed, |k, _| {
+            let name = k.smooth(&*self.collator).0;
+            if !name.starts_with(reversed.as_str()) {
+                return !past_prefix(&name, &reversed);
+            }
+            result.push(k.0.chars().rev().collect());
+            result.len() < limit
+        })
+        .await;
+        result
+    }
+
+    /// The value stored under `name`. Without `fold_case` the stored key must
+    /// match byte for byte; with it any key equal ignoring case matches, an
+    /// exact match being preferred.
+    #[instrument(skip(self, cache))]
+    pub async fn search_entry(
+        &self,
+        cache: Arc<RwLock<NodeCache>>,
+        root: (u64, u32),
+        name: &str,
+        fold_case: bool,
+    ) -> Option<Vec<u8>> {
+        self.search_entry_map(cache, root, name, fold_case, |v| v.to_vec())
+            .await
+    }
+
+    /// Like `search_entry` but hands the stored bytes to `map` instead of
+    /// cloning them, so callers needing a part only copy that part.
+    pub async fn search_entry_map<T, F>(
+        &self,
+        cache: Arc<RwLock<NodeCache>>,
+        root: (u64, u32),
+        name: &str,
+        fold_case: bool,
+        map: F,
+    ) -> Option<T>
+    where
+        F: Fn(&[u8]) -> T,
+    {
+        let key = EntryKey::normalized(name);
+        let smooth_key = key.smooth(&*self.collator);
+        let mut exact: Option<T> = None;
+        let mut folded: Option<T> = None;
+        self.scan(cache, root, name, |k, v| {
+            info!("Checking match. {}", k);
+            if k.smooth(&*self.collator) != smooth_key {
+                return false;
+            }
+            if *k == key {
+                exact = Some(map(&v.0));
+                return false;
+            }
+            if fold_case && folded.is_none() {
+                folded = Some(map(&v.0));
+            }
+            true
+        })
+        .await;
+        if exact.is_none() && folded.is_none() {
+            warn!("Entry not exists");
+        }
+        exact.or(folded)
+    }
+
+    /// Exact lookups for many names at once. The names are visited in tree
+    /// order and a name falling inside the leaf of the previous one is looked
+    /// up there without descending from the root again. Results follow the
+    /// order of `names`.
+    pub async fn search_entries(
+        &self,
+        cache: Arc<RwLock<NodeCache>>,
+        root: (u64, u32),
+        names: &[&str],
+    ) -> Vec<Option<Vec<u8>>> {
+        let keys: Vec<EntryKey> = names.iter().map(|n| EntryKey::normalized(n)).collect();
+        let smooth_keys: Vec<EntryKey> = keys.iter().map(|k| k.smooth(&*self.collator)).collect();
+        let mut order: Vec<usize> = (0..names.len()).collect();
+        order.sort_by(|a, b| smooth_keys[*a].cmp(&smooth_keys[*b]));
+        let mut result: Vec<Option<Vec<u8>>> = vec![None; names.len()];
+        let mut leaf: Option<DictNode> = None;
+        for i in order {
+            let smooth_key = &smooth_keys[i];
+            // Equal keys may spill into a neighbour leaf, so the cached leaf
+            // is only reused when its first and last keys enclose the name.
+            let inside = leaf.as_ref().is_some_and(|nd| {
+                let records = &nd.node.records;
+                !records.is_empty()
+                    && records[0].key.smooth(&*self.collator) < *smooth_key
+                    && *smooth_key < records[records.len() - 1].key.smooth(&*self.collator)
+            });
+            let mut start = 0;
+            if !inside {
+                leaf = match self.seek_leaf(cache.clone(), root, names[i]).await {
+                    Some((nd, index)) => {
+                        start = index;
+                        Some(nd)
+                    }
+                    None => None,
+                };
+            }
+            let records = match &leaf {
+                Some(nd) => &nd.node.records,
+                None => continue,
+            };
+            if records
+                .last()
+                .is_some_and(|r| r.key.smooth(&*self.collator) <= *smooth_key)
+            {
+                result[i] = self
+                    .search_entry(cache.clone(), root, names[i], false)
+                    .await;
+                continue;
+            }
+            result[i] = records
+                .iter()
+                .skip(start)
+                .take_while(|r| r.key.smooth(&*self.collator) <= *smooth_key)
+                .find(|r| r.key == keys[i])
+                .map(|r| r.value.as_ref().unwrap().0.clone());
+        }
+        result
+    }
+}
+
+/// Flags of a prefix search. Case is compared as follows:
+///
+/// | `strict` | `fold_case` | `smart_case` | "Par" finds                   |
+/// | -------- | ----------- | ------------ | ----------------------------- |
+/// | off      | on          | off          | "paris", "Paris", "PARIS"     |
+/// | off      | on          | on           | "Paris", "PARIS", not "paris" |
+/// | off      | off         | either       | "Paris" only                  |
+/// | on       | either      | either       | "Paris" only                  |
+///
+/// So `strict` keeps the headwords starting with the query as typed,
+/// whatever the other flags say, while `smart_case` makes only the capitals
+/// typed count, handy for proper nouns.
+#[derive(Debug, Clone)]
+pub struct SearchOptions {
+    /// Only keep headwords starting with the query in its exact case
+    pub strict: bool,
+    /// Match the prefix ignoring case, on by default. Off, the case of every
+    /// letter has to match.
+    pub fold_case: bool,
+    /// With `fold_case`, capitals in the query only match capitals while
+    /// other letters still match either case. Off by default.
+    pub smart_case: bool,
+    /// Ignore accents, so "resume" finds "résumé"
+    pub fold_diacritics: bool,
+    /// Maximum number of headwords found by prefix
+    pub prefix_limit: usize,
+    /// Maximum number of extra headwords found through the token tree
+    pub phrase_limit: usize,
+    /// Resume a prefix search after this headword, usually the last one of
+    /// the previous page
+    pub after: Option<String>,
+    /// Drop the token results when a token value holds an entry name that is
+    /// not UTF-8, instead of decoding it lossily. Off by default.
+    pub strict_tokens: bool,
+}
+
+impl Default for SearchOptions {
+    fn default() -> Self {
+        Self {
+            strict: false,
+            fold_case: true,
+            smart_case: false,
+            fold_diacritics: false,
+            prefix_limit: 10,
+            phrase_limit: 0,
+            after: None,
+            strict_tokens: false,
+        }
+    }
+}
+
+impl SearchOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    pub fn fold_case(mut self, fold_case: bool) -> Self {
+        self.fold_case = fold_case;
+        self
+    }
+
+    pub fn smart_case(mut self, smart_case: bool) -> Self {
+        self.smart_case = smart_case;
+        self
+    }
+
+    pub fn fold_diacritics(mut self, fold_diacritics: bool) -> Self {
+        self.fold_diacritics = fold_diacritics;
+        self
+    }
+
+    pub fn prefix_limit(mut self, prefix_limit: usize) -> Self {
+        self.prefix_limit = prefix_limit;
+        self
+    }
+
+    pub fn phrase_limit(mut self, phrase_limit: usize) -> Self {
+        self.phrase_limit = phrase_limit;
+        self
+    }
+
+    pub fn after(mut self, after: Option<String>) -> Self {
+        self.after = after;
+        self
+    }
+
+    pub fn strict_tokens(mut self, strict_tokens: bool) -> Self {
+        self.strict_tokens = strict_tokens;
+        self
+    }
+}
+
+/// Lazy walk over the entries of a dictionary in key order, reading one leaf
+/// at a time through the node cache.
+pub struct EntryIter<'a> {
+    dict: &'a DictFile,
+    cache: Arc<RwLock<NodeCache>>,
+    node: Option<DictNode>,
+    index: usize,
+    started: bool,
+}
+
+impl EntryIter<'_> {
+    pub async fn next_entry(&mut self) -> Option<(String, Vec<u8>)> {
+        if !self.started {
+            self.started = true;
+            let root = self.dict.entry_root;
+            if root.1 == 0 {
+                return None;
+            }
+            let (node, index) = self.dict.seek_leaf(self.cache.clone(), root, "").await?;
+            self.node = Some(node);
+            self.index = index;
+        }
+        loop {
+            let dict_node = self.node.as_ref()?;
+            if let Some(rec) = dict_node.node.records.get(self.index) {
+                self.index += 1;
+                return Some((rec.key.0.clone(), rec.value.as_ref().unwrap().bytes()));
+            }
+            let (next_offset, next_size) = dict_node.children[0];
+            if next_offset == 0 {
+                self.node = None;
+                return None;
+            }
+            self.node = self
+                .dict
+                .get_node(self.cache.clone(), next_offset, next_size)
+                .await;
+            self.index = 0;
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RedirectConfig {
+    /// Prefix marking a definition as a redirect to the keyword following it
+    pub marker: String,
+    /// Maximum number of redirects followed for one lookup
+    pub max_depth: usize,
+}
+
+impl Default for RedirectConfig {
+    fn default() -> Self {
+        Self {
+            marker: REDIRECT.to_string(),
+            max_depth: MAX_REDIRECTS,
+        }
+    }
+}
+
+/// Templates rewriting references in definitions for a web frontend, e.g.
+/// `src="audio.mp3"` to `src="/resource/oald/audio.mp3"`. In a template
+/// `{dict}` stands for the dictionary basename and `{name}` for the
+/// referenced resource or headword.
+#[derive(Debug, Clone)]
+pub struct UrlRewrite {
+    /// For `src` and `href` values that are relative paths or `sound://`
+    /// URLs, e.g. "/resource/{dict}/{name}"
+    pub resource: String,
+    /// For `entry://` links to other headwords, left alone when `None`
+    pub entry: Option<String>,
+}
+
+pub struct Dictionary {
+    dir: String,
+    basename: String,
+    entry: DictFile,
+    resources: Vec<DictFile>,
+    css_js: Option<(String, String)>,
+    redirect: RedirectConfig,
+    url_rewrite: Option<UrlRewrite>,
+}
+
+impl Dictionary {
+    /// Open an entry file and the resource files next to it. Only the header
+    /// and trailer are read here; nodes are read on demand by the lookups and
+    /// kept in the shared `NodeCache`, so memory stays within the cache
+    /// capacity, counted in decoded node bytes, however large the files are,
+    /// at the cost of a disk read and an inflate for every node missing from
+    /// the cache.
+    pub async fn new(filepath: &str, cache_id: u32) -> Result<(Self, u32)> {
+        Self::open(filepath, cache_id, None).await
+    }
+
+    /// `new` for a dictionary encrypted with `key`, its resource files
+    /// included. Needs the `encryption` feature.
+    pub async fn new_with_key(
+        filepath: &str,
+        cache_id: u32,
+        key: &[u8; 32],
+    ) -> Result<(Self, u32)> {
+        Self::open(filepath, cache_id, Some(key)).await
+    }
+
+    async fn open(
+        filepath: &str,
+        mut cache_id: u32,
+        key: Option<&[u8; 32]>,
+    ) -> Result<(Self, u32)> {
+        let file_type = parse_file_type(filepath)?;
+        if !matches!(file_type, BelFileType::Entry) {
+            error!("invalid entry file extension");
+            return Err(Error::InvalidExtension(filepath.to_string()));
+        }
+        let p = Path::new(filepath);
+        if !p.exists() || p.is_dir() {
+            error!("File not exists or it is a directory");
+            return Err(Error::NotFound(filepath.to_string()));
+        }
+        info!("Load entry file");
+        let entry = DictFile::new(filepath, cache_id, key).await?;
+        let basename = match p.file_stem().and_then(|s| s.to_str()) {
+            Some(b) => b,
+            None => return Err(Error::Msg(format!("invalid path. {:?}", p))),
+        };
+        let mut resources: Vec<DictFile> = Vec::new();
+        let dir = match p.parent() {
+            Some(d) => d,
+            None => {
+                error!("File has no parent directory, weird???");
+                return Err(Error::Msg("invalid file path".to_string()));
+            }
+        };
+        let res_ext = String::from(".") + EXT_RESOURCE;
+        info!("Search related resource files");
+        for entry in dir.read_dir()?.flatten() {
+            if !entry.metadata().is_ok_and(|m| m.is_file()) {
+                continue;
+            }
+            let name = match entry.file_name().into_string() {
+                Ok(n) => n,
+                Err(_) => continue,
+            };
+            let res_id = match resource_id(&name, basename, &res_ext) {
+                Some(id) => id,
+                None => continue,
+            };
+            let res_path = match dir.join(&name).to_str() {
+                Some(p) => p.to_string(),
+                None => continue,
+            };
+            cache_id += 1;
+            info!("Load resource file. {}", name);
+            let mut res = DictFile::new(&res_path, cache_id, key).await?;
+            res.id = String::from(res_id);
+            resources.push(res);
+        }
+        Ok((
+            Self {
+                dir: dir.to_string_lossy().to_string(),
+                basename: basename.to_string(),
+                entry,
+                resources,
+                css_js: None,
+                redirect: RedirectConfig::default(),
+                url_rewrite: None,
+            },
+            cache_id,
+        ))
+    }
+
+    pub async fn get_css_js(&mut self, disable_cache: bool) -> Result<(String, String)> {
+        if let Some(v) = &self.css_js {
+            if disable_cache {
+                self.css_js = None;
+            } else {
+                return Ok(v.clone());
+            }
+        }
+        let dir = Path::new(&self.dir);
+        let mut js = String::new();
+        let js_file = dir.join(format!("{}.js", self.basename));
+        if js_file.is_file() {
+            info!("Load JavaScript file. {:?}", js_file);
+            match fs::read_to_string(js_file).await {
+                Ok(text) => js = text,
+                Err(e) => {
+                    error!("Fail to read file. {}", e);
+                    return Err(Error::Msg("invalid javascript file".to_string()));
+                }
+            }
+        }
+        let mut css = String::new();
+        let css_file = dir.join(format!("{}.css", self.basename));
+        if css_file.is_file() {
+            info!("Load CSS file. {:?}", css_file);
+            match fs::read_to_string(css_file).await {
+                Ok(text) => css = text,
+                Err(e) => {
+                    error!("Fail to read file. {}", e);
+                    return Err(Error::Msg("invalid css file".to_string()));
+                }
+            }
+        }
+        if !cfg!(debug_assertions) || !disable_cache {
+            self.css_js = Some((css.clone(), js.clone()));
+        }
+        Ok((css, js))
+    }
+
+    /// `get_css_js` made safe to put on a page next to other dictionaries:
+    /// the CSS rules only apply inside an element of class
+    /// `beluga-dict-{id}` and the JavaScript runs in its own function scope.
+    pub async fn get_scoped_css_js(
+        &mut self,
+        disable_cache: bool,
+        id: &str,
+    ) -> Result<(String, String)> {
+        let (css, js) = self.get_css_js(disable_cache).await?;
+        let css = utils::scope_css(&css, &format!(".beluga-dict-{}", id));
+        let js = if js.trim().is_empty() {
+            js
+        } else {
+            format!("(function () {{\n{}\n}})();\n", js)
+        };
+        Ok((css, js))
+    }
+
+    pub fn metadata(&self) -> Metadata {
+        self.entry.metadata.clone()
+    }
+
+    /// Number of entries recorded in the metadata.
+    pub fn entry_count(&self) -> u64 {
+        self.entry.metadata.entry_num
+    }
+
+    /// Prefix search on headwords followed by up to `phrase_limit` entries
+    /// found through the token tree. Token entries only come with the first
+    /// page, i.e. when `after` is not set.
+    #[instrument(skip(self, cache))]
+    pub async fn search(
+        &self,
+        cache: Arc<RwLock<NodeCache>>,
+        name: &str,
+        options: &SearchOptions,
+    ) -> Vec<String> {
+        info!("Search entry");
+        let mut result = self.entry.search(cache.clone(), name, options).await;
+        let phrase_limit = options.phrase_limit;
+        if phrase_limit > 0 && options.after.is_none() && self.entry.token_root.1 != 0 {
+            info!("Search TOKEN entries");
+            if let Some(data) = self
+                .entry
+                .search_entry(cache.clone(), self.entry.token_root, name, false)
+                .await
+            {
+                let strict = options.strict_tokens;
+                let entries = Beluga::parse_token_entries_with(&data, strict).unwrap_or_else(|e| {
+                    error!("Invalid token entries. {}", e);
+                    vec![]
+                });
+                info!("Found {} entry(ies) by TOKEN", entries.len());
+                let mut token_count = 0;
+                for entry_name in entries {
+                    if !result.contains(&entry_name) {
+                        if token_count >= phrase_limit {
+                            break;
+                        }
+                        result.push(entry_name);
+                        token_count += 1;
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    /// Prefix search for several prefixes at once, e.g. the normalized and
+    /// the raw form of a query. A headword found by more than one prefix is
+    /// listed once; headwords are ordered by how many characters they add to
+    /// their longest matching prefix, fewest first, and then as found.
+    #[instrument(skip(self, cache))]
+    pub async fn search_multi(
+        &self,
+        cache: Arc<RwLock<NodeCache>>,
+        prefixes: &[&str],
+        limit: usize,
+    ) -> Vec<String> {
+        let options = SearchOptions::new().prefix_limit(limit);
+        let mut found: Vec<(usize, String)> = Vec::new();
+        let mut index: HashMap<String, usize> = HashMap::new();
+        for prefix in prefixes {
+            let prefix_len = prefix.chars().count();
+            for name in self.entry.search(cache.clone(), prefix, &options).await {
+                let distance = name.chars().count().saturating_sub(prefix_len);
+                match index.get(&name) {
+                    Some(&i) => found[i].0 = found[i].0.min(distance),
+                    None => {
+                        index.insert(name.clone(), found.len());
+                        found.push((distance, name));
+                    }
+                }
+            }
+        }
+        found.sort_by_key(|f| f.0);
+        found.into_iter().take(limit).map(|f| f.1).collect()
+    }
+
+    pub fn redirect_config(&self) -> &RedirectConfig {
+        &self.redirect
+    }
+
+    pub fn set_redirect_config(&mut self, config: RedirectConfig) {
+        self.redirect = config;
+    }
+
+    /// Rewrite resource references in the definitions returned by the
+    /// `search_entry` family, see `UrlRewrite`. Off by default.
+    pub fn set_url_rewrite(&mut self, rewrite: Option<UrlRewrite>) {
+        self.url_rewrite = rewrite;
+    }
+
+    fn rewrite_definition(&self, content: String) -> String {
+        let rewrite = match &self.url_rewrite {
+            Some(r) => r,
+            None => return content,
+        };
+        utils::rewrite_urls(&content, |url| {
+            let (template, name) = if let Some(name) = url.strip_prefix("sound://") {
+                (&rewrite.resource, name)
+            } else if let Some(name) = url.strip_prefix("entry://") {
+                (rewrite.entry.as_ref()?, name)
+            } else if !url.is_empty()
+                && !url.contains(':')
+                && !url.starts_with(['/', '\\', '#', '?'])
+            {
+                (&rewrite.resource, url.strip_prefix("./").unwrap_or(url))
+            } else {
+                return None;
+            };
+            Some(
+                template
+                    .replace("{dict}", &self.basename)
+                    .replace("{name}", name),
+            )
+        })
+    }
+
+    /// Bound the time of any single scan, e.g. a glob with a leading
+    /// wildcard. It is checked between leaf reads; a search running out of
+    /// time returns the results found so far. `None`, the default, means no
+    /// bound.
+    pub fn set_scan_timeout(&mut self, timeout: Option<Duration>) {
+        self.entry.scan_timeout = timeout;
+        for res in self.resources.iter_mut() {
+            res.scan_timeout = timeout;
+        }
+    }
+
+    /// Look up the headword matching `name` byte for byte and follow
+    /// redirects. A broken chain is logged and reported as not found, see
+    /// `resolve_entry` for the distinct errors.
+    #[instrument(skip(self, cache))]
+    pub async fn search_entry(&self, cache: Arc<RwLock<NodeCache>>, name: &str) -> Option<String> {
+        match self.resolve(cache, name, false).await {
+            Ok(r) => r,
+            Err(e) => {
+                warn!("{}", e);
+                None
+            }
+        }
+    }
+
+    /// `search_entry` for each of `names`, sharing the tree descent between
+    /// neighbouring names. Redirects are followed in rounds, each looking up
+    /// the targets of all names still redirected at once. Results follow the
+    /// order of `names`.
+    #[instrument(skip(self, cache, names))]
+    pub async fn search_words(
+        &self,
+        cache: Arc<RwLock<NodeCache>>,
+        names: &[&str],
+    ) -> Vec<Option<String>> {
+        let mut result: Vec<Option<String>> = vec![None; names.len()];
+        // index in `names` and keywords visited of each name not found yet
+        let mut pending: Vec<(usize, Vec<String>)> = names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| (i, vec![name.to_string()]))
+            .collect();
+        while !pending.is_empty() {
+            let keywords: Vec<&str> = pending
+                .iter()
+                .map(|(_, chain)| chain.last().unwrap().as_str())
+                .collect();
+            let values = self
+                .entry
+                .search_entries(cache.clone(), self.entry.entry_root, &keywords)
+                .await;
+            let mut redirected = vec![];
+            for ((i, mut chain), data) in pending.into_iter().zip(values) {
+                let Some(content) = data.and_then(|d| String::from_utf8(d).ok()) else {
+                    continue;
+                };
+                let target = match content.trim().strip_prefix(self.redirect.marker.as_str()) {
+                    Some(kw) => kw.to_string(),
+                    None => {
+                        result[i] = Some(self.rewrite_definition(content));
+                        continue;
+                    }
+                };
+                let looped = chain.contains(&target);
+                chain.push(target);
+                if looped {
+                    warn!("{}", Error::RedirectLoop(chain.join(" -> ")));
+                } else if chain.len() > self.redirect.max_depth + 1 {
+                    warn!("{}", Error::TooManyRedirects(chain.join(" -> ")));
+                } else {
+                    redirected.push((i, chain));
+                }
+            }
+            pending = redirected;
+        }
+        result
+    }
+
+    /// Like `search_entry` but "apple" also finds "Apple". A headword
+    /// matching exactly is preferred over one differing in case.
+    #[instrument(skip(self, cache))]
+    pub async fn search_entry_ignore_case(
+        &self,
+        cache: Arc<RwLock<NodeCache>>,
+        name: &str,
+    ) -> Option<String> {
+        match self.resolve(cache, name, true).await {
+            Ok(r) => r,
+            Err(e) => {
+                warn!("{}", e);
+                None
+            }
+        }
+    }
+
+    /// Look up `name` exactly and follow redirects, failing with
+    /// `RedirectLoop` when a redirect points back to a keyword already
+    /// visited and with `TooManyRedirects` when the chain is longer than
+    /// `max_depth`.
+    #[instrument(skip(self, cache))]
+    pub async fn resolve_entry(
+        &self,
+        cache: Arc<RwLock<NodeCache>>,
+        name: &str,
+    ) -> Result<Option<String>> {
+        self.resolve(cache, name, false).await
+    }
+
+    async fn resolve(
+        &self,
+        cache: Arc<RwLock<NodeCache>>,
+        name: &str,
+        fold_case: bool,
+    ) -> Result<Option<String>> {
+        let mut chain: Vec<String> = vec![name.to_string()];
+        loop {
+            let keyword = chain.last().unwrap();
+            let data = match self
+                .entry
+                .search_entry(cache.clone(), self.entry.entry_root, keyword, fold_case)
+                .await
+            {
+                Some(d) => d,
+                None => return Ok(None),
+            };
+            let content = match String::from_utf8(data) {
+                Ok(c) => c,
+                Err(_) => return Ok(None),
+            };
+            let target = match content.trim().strip_prefix(self.redirect.marker.as_str()) {
+                Some(kw) => kw.to_string(),
+                None => return Ok(Some(self.rewrite_definition(content))),
+            };
+            let looped = chain.contains(&target);
+            chain.push(target);
+            if looped {
+                return Err(Error::RedirectLoop(chain.join(" -> ")));
+            }
+            if chain.len() > self.redirect.max_depth + 1 {
+                return Err(Error::TooManyRedirects(chain.join(" -> ")));
+            }
+        }
+    }
+
+    /// Every definition stored under `name`, e.g. the entries of a homograph.
+    /// Redirects are followed for each of them.
+    #[instrument(skip(self, cache))]
+    pub async fn search_entry_all(&self, cache: Arc<RwLock<NodeCache>>, name: &str) -> Vec<String> {
+        let mut result: Vec<String> = Vec::new();
+        let values = self
+            .entry
+            .search_entry_all(cache.clone(), self.entry.entry_root, name)
+            .await;
+        for data in values {
+            if let Ok(content) = String::from_utf8(data) {
+                let s = content.trim();
+                if let Some(kw) = s.strip_prefix(self.redirect.marker.as_str()) {
+                    if let Some(content) = self.search_entry(cache.clone(), kw).await {
+                        result.push(content);
+                    }
+                } else {
+                    result.push(self.rewrite_definition(content));
+                }
+            }
+        }
+        result
+    }
+
+    /// Headwords whose definitions contain `term`, read from the token tree.
+    /// Only dictionaries built with token data for their definitions (see
+    /// `Beluga::input_token`) return anything; `term` must match the token as
+    /// it was stored.
+    #[instrument(skip(self, cache))]
+    pub async fn search_fulltext(
+        &self,
+        cache: Arc<RwLock<NodeCache>>,
+        term: &str,
+        limit: usize,
+    ) -> Vec<String> {
+        let mut result: Vec<String> = Vec::new();
+        if self.entry.token_root.1 == 0 {
+            return result;
+        }
+        let values = self
+            .entry
+            .search_entry_all(cache, self.entry.token_root, term)
+            .await;
+        for data in values {
+            let names = Beluga::parse_token_entries_with(&data, false).unwrap_or_else(|e| {
+                error!("Invalid token entries. {}", e);
+                vec![]
+            });
+            for name in names {
+                if result.len() >= limit {
+                    return result;
+                }
+                if !result.contains(&name) {
+                    result.push(name);
+                }
+            }
+        }
+        result
+    }
+
+    /// Iterate over every entry as (name, value), leaf by leaf, so a whole
+    /// dictionary can be exported with bounded memory.
+    pub fn iter_entries(&self, cache: Arc<RwLock<NodeCache>>) -> EntryIter<'_> {
+        EntryIter {
+            dict: &self.entry,
+            cache,
+            node: None,
+            index: 0,
+            started: false,
+        }
+    }
+
+    /// Call `f` with every entry as (name, value) in key order and return how
+    /// many there were. Leaves are read through `cache` one after another
+    /// following the sibling links, the tree is never loaded as a whole.
+    pub async fn for_each_entry<F>(&self, cache: Arc<RwLock<NodeCache>>, mut f: F) -> usize
+    where
+        F: FnMut(&str, &[u8]),
+    {
+        let mut iter = self.iter_entries(cache);
+        let mut count = 0;
+        while let Some((name, value)) = iter.next_entry().await {
+            f(&name, &value);
+            count += 1;
+        }
+        count
+    }
+
+    /// A window of headwords around `name`, e.g. to scroll a word list to it:
+    /// `before` headwords, the first one not less than `name` and `after`
+    /// more. Unless the file has `prev_links`, leaves only link forward and
+    /// the headwords before are found by walking back up the tree from the
+    /// leaf of `name`.
+    #[instrument(skip(self, cache))]
+    pub async fn around(
+        &self,
+        cache: Arc<RwLock<NodeCache>>,
+        name: &str,
+        before: usize,
+        after: usize,
+    ) -> Vec<String> {
+        self.entry.around(cache, name, before, after).await
+    }
+
+    /// Glob search over headwords, see `DictFile::search_glob`. The literal
+    /// part before the first wildcard narrows the scan; a pattern starting
+    /// with a wildcard scans every entry from the first leaf, which is O(n).
+    #[instrument(skip(self, cache))]
+    pub async fn search_glob(
+        &self,
+        cache: Arc<RwLock<NodeCache>>,
+        pattern: &str,
+        limit: usize,
+    ) -> Vec<String> {
+        self.entry.search_glob(cache, pattern, limit).await
+    }
+
+    /// Headwords matching an arbitrary predicate, e.g. longer than 20
+    /// characters or containing a digit, up to `limit`. Every entry may be
+    /// visited, which is O(n); meant for tools and rare queries.
+    pub async fn filter_headwords<F>(
+        &self,
+        cache: Arc<RwLock<NodeCache>>,
+        pred: F,
+        limit: usize,
+    ) -> Vec<String>
+    where
+        F: Fn(&str) -> bool,
+    {
+        self.entry.filter_headwords(cache, pred, limit).await
+    }
+
+    /// Typo-tolerant lookup, e.g. "recieve" finds "receive". Returns headwords
+    /// within `max_distance` edits of `name` along with their similarity,
+    /// `1 - distance / length of the longer word`, most similar first.
+    /// Headwords less similar than `min_similarity` are left out, a cutoff
+    /// that means the same for short and long words.
+    #[instrument(skip(self, cache))]
+    pub async fn search_fuzzy(
+        &self,
+        cache: Arc<RwLock<NodeCache>>,
+        name: &str,
+        max_distance: usize,
+        min_similarity: f64,
+        limit: usize,
+    ) -> Vec<(String, f64)> {
+        self.entry
+            .search_fuzzy(cache, name, max_distance, min_similarity, limit)
+            .await
+    }
+
+    /// "Did you mean" list for `name`, e.g. after `search_entry` found
+    /// nothing: headwords starting with `name` first, then, if fewer than
+    /// `limit`, headwords within 2 edits (1 for names under 5 characters)
+    /// most similar first, each listed once. Fuzzy candidates share the first
+    /// character of `name`, and names of 2 characters or less only get
+    /// prefix matches, which keeps the scan near `name`.
+    #[instrument(skip(self, cache))]
+    pub async fn suggest(
+        &self,
+        cache: Arc<RwLock<NodeCache>>,
+        name: &str,
+        limit: usize,
+    ) -> Vec<String> {
+        if limit == 0 {
+            return vec![];
+        }
+        let options = SearchOptions::new().prefix_limit(limit);
+        let mut result = self.entry.search(cache.clone(), name, &options).await;
+        let len = name.chars().count();
+        if result.len() >= limit || len <= 2 {
+            return result;
+        }
+        let max_distance = if len < 5 { 1 } else { 2 };
+        let fuzzy = self
+            .entry
+            .search_fuzzy(cache, name, max_distance, 0.0, limit + result.len())
+            .await;
+        for (candidate, _) in fuzzy {
+            if result.len() >= limit {
+                break;
+            }
+            if !result.contains(&candidate) {
+                result.push(candidate);
+            }
+        }
+        result
+    }
+
+    /// Headwords ending with `suffix`, case-insensitively. Requires the
+    /// dictionary to be built with `Metadata::suffix_index`.
+    #[instrument(skip(self, cache))]
+    pub async fn search_suffix(
+        &self,
+        cache: Arc<RwLock<NodeCache>>,
+        suffix: &str,
+        limit: usize,
+    ) -> Vec<String> {
+        self.entry.search_suffix(cache, suffix, limit).await
+    }
+
+    /// Resource data of `name`, or the part of it picked by `map`.
+    /// Redirects, e.g. left by deduplication, are followed.
+    #[instrument(skip(self, cache))]
+    pub async fn search_resource(
+        &self,
+        cache: Arc<RwLock<NodeCache>>,
+        name: &str,
+    ) -> Option<Vec<u8>> {
+        self.find_resource(cache, name, |v| v.to_vec()).await
+    }
+
+    /// Nodes of this dictionary in `cache` as (cache id, offset, size), from
+    /// the most to the least recently used. Saved across a restart they can
+    /// be read again with `prefetch`, provided the dictionary is opened with
+    /// the same cache id.
+    pub async fn hot_nodes(&self, cache: Arc<RwLock<NodeCache>>) -> Vec<(u32, u64, u32)> {
+        let cache_lock = cache.read().await;
+        cache_lock
+            .keys_in_lru_order()
+            .into_iter()
+            .filter(|key| self.file_by_cache_id(key.0).is_some())
+            .filter_map(|key| cache_lock.peek(&key).map(|n| (key.0, key.1, n.zip_size)))
+            .collect()
+    }
+
+    /// Read `nodes` listed by `hot_nodes` into `cache`, keeping their order
+    /// of use, and return how many were loaded. Nodes of other dictionaries
+    /// and nodes that fail to read are skipped.
+    pub async fn prefetch(
+        &self,
+        cache: Arc<RwLock<NodeCache>>,
+        nodes: &[(u32, u64, u32)],
+    ) -> usize {
+        let mut loaded = 0;
+        for (cache_id, offset, size) in nodes.iter().rev() {
+            if let Some(file) = self.file_by_cache_id(*cache_id) {
+                if file.get_node(cache.clone(), *offset, *size).await.is_some() {
+                    loaded += 1;
+                }
+            }
+        }
+        loaded
+    }
+
+    /// Offset and size of the root node of the entry tree, where a walk with
+    /// `read_node_raw` starts
+    pub fn entry_root(&self) -> (u64, u32) {
+        self.entry.entry_root
+    }
+
+    /// Records and child pointers of the entry file node at `offset` of
+    /// `size` bytes, read through `cache`, e.g. for a file inspector. Index
+    /// nodes have one more child than records; a leaf has a single pointer
+    /// to its right sibling, (0, 0) for the last leaf. `None` if no valid
+    /// node is there.
+    pub async fn read_node_raw(
+        &self,
+        cache: Arc<RwLock<NodeCache>>,
+        offset: u64,
+        size: u32,
+    ) -> Option<(Vec<Record<EntryKey, EntryValue>>, Vec<(u64, u32)>)> {
+        let node = self.entry.get_node(cache, offset, size).await?;
+        Some((node.node.records, node.children))
+    }
+
+    fn file_by_cache_id(&self, cache_id: u32) -> Option<&DictFile> {
+        std::iter::once(&self.entry)
+            .chain(&self.resources)
+            .find(|f| f.cache_id == cache_id)
+    }
+
+    /// Ids of the resource files loaded next to the entry file, in lookup
+    /// order. The id is the part between the basename and `.beld`, e.g. "1"
+    /// for `oald.1.beld`, empty for `oald.beld`.
+    pub fn resource_ids(&self) -> Vec<String> {
+        self.resources.iter().map(|r| r.id.clone()).collect()
+    }
+
+    /// Whether any resource file holds `name`, following redirects, without
+    /// copying its data.
+    #[instrument(skip(self, cache))]
+    pub async fn has_resource(&self, cache: Arc<RwLock<NodeCache>>, name: &str) -> bool {
+        self.find_resource(cache, name, |_| ()).await.is_some()
+    }
+
+    /// Length in bytes of the resource data of `name`, following redirects,
+    /// e.g. for the Content-Length of an HTTP response. The node holding it
+    /// is read through `cache` but the data is not copied.
+    #[instrument(skip(self, cache))]
+    pub async fn resource_size(&self, cache: Arc<RwLock<NodeCache>>, name: &str) -> Option<u64> {
+        self.find_resource(cache, name, |v| v.len() as u64).await
+    }
+
+    /// `search_resource` along with the MIME type of the data, guessed from
+    /// the extension of `name` or else the leading bytes, e.g. to set the
+    /// Content-Type of an HTTP response. Unknown types are
+    /// "application/octet-stream".
+    #[instrument(skip(self, cache))]
+    pub async fn search_resource_typed(
+        &self,
+        cache: Arc<RwLock<NodeCache>>,
+        name: &str,
+    ) -> Option<(Vec<u8>, &'static str)> {
+        let data = self.search_resource(cache, name).await?;
+        let mime = utils::mime_type(name, &data);
+        Some((data, mime))
+    }
+
+    /// Bytes `start..end` of a resource, clamped to its length, e.g. to
+    /// answer an HTTP Range request without copying the whole file.
+    #[instrument(skip(self, cache))]
+    pub async fn search_resource_range(
+        &self,
+        cache: Arc<RwLock<NodeCache>>,
+        name: &str,
+        start: usize,
+        end: usize,
+    ) -> Option<Vec<u8>> {
+        let slice = |v: &[u8]| {
+            let end = end.min(v.len());
+            v[start.min(end)..end].to_vec()
+        };
+        self.find_resource(cache, name, slice).await
+    }
+
+    /// `search_resource_range` written to `w` instead of returned, for large
+    /// resources: the leaf holding the resource, unless already cached, is
+    /// decompressed as it is read and only `start..end` is copied, so memory
+    /// stays small whatever the sizes of the node and the resource. Returns
+    /// the number of bytes written, `None` if there is no such resource.
+    #[instrument(skip(self, cache, w))]
+    pub async fn copy_resource_range<W: Write>(
+        &self,
+        cache: Arc<RwLock<NodeCache>>,
+        name: &str,
+        start: u64,
+        end: u64,
+        w: &mut W,
+    ) -> Result<Option<u64>> {
+        let marker = self.redirect.marker.as_str();
+        let mut name = name.to_string();
+        for _ in 0..=self.redirect.max_depth {
+            let mut target: Option<String> = None;
+            for (dict, root) in self.resource_trees() {
+                match dict
+                    .copy_value_range(cache.clone(), root, &name, start, end, marker, w)
+                    .await?
+                {
+                    ValueCopy::Copied(n) => return Ok(Some(n)),
+                    ValueCopy::Redirect(t) => {
+                        target = Some(t);
+                        break;
+                    }
+                    ValueCopy::Missing => {}
+                }
+            }
+            name = match target {
+                Some(t) => t,
+                None => return Ok(None),
+            };
+        }
+        warn!("Too many resource redirects. {}", name);
+        Ok(None)
+    }
+
+    /// Trees holding resources, in lookup order: those embedded in the entry
+    /// file, then the entry trees of the resource files
+    fn resource_trees(&self) -> impl Iterator<Item = (&DictFile, (u64, u32))> {
+        let embedded = Some((&self.entry, self.entry.resource_root)).filter(|(_, r)| r.1 != 0);
+        embedded
+            .into_iter()
+            .chain(self.resources.iter().map(|f| (f, f.entry_root)))
+    }
+
+    async fn find_resource<T, F>(
+        &self,
+        cache: Arc<RwLock<NodeCache>>,
+        name: &str,
+        map: F,
+    ) -> Option<T>
+    where
+        F: Fn(&[u8]) -> T,
+    {
+        info!("Resource name: {}", name);
+        let marker = self.redirect.marker.clone();
+        let mut name = name.to_string();
+        for _ in 0..=self.redirect.max_depth {
+            let mut target: Option<String> = None;
+            for (dict, root) in self.resource_trees() {
+                let found = dict
+                    .search_entry_map(cache.clone(), root, &name, false, |v| {
+                        match v.strip_prefix(marker.as_bytes()) {
+                            Some(t) => Err(String::from_utf8_lossy(t).trim().to_string()),
+                            None => Ok(map(v)),
+                        }
+                    })
+                    .await;
+                match found {
+                    Some(Ok(v)) => return Some(v),
+                    Some(Err(t)) => {
+                        target = Some(t);
+                        break;
+                    }
+                    None => {}
+                }
+            }
+            name = match target {
+                Some(t) => t,
+                None => {
+                    info!("Invalid resource ID");
+                    return None;
+                }
+            };
+        }
+        warn!("Too many resource redirects. {}", name);
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::*;
+
+    #[tokio::test]
+    async fn homographs_keep_every_value() {
+        let dir = temp_dir("homographs_keep_every_value");
+        let values: Vec<String> = (0..30).map(|i| format!("bow sense {:02}", i)).collect();
+        let mut entries: Vec<(&str, &str)> = vec![("arrow", "a"), ("cello", "c")];
+        entries.extend(values.iter().map(|v| ("bow", v.as_str())));
+        // enough homographs to span several leaves
+        let path = save_entries(&dir, "bow.bel", small_nodes(256), &entries).await;
+        let (dict, cache) = open(&path).await;
+        assert_eq!(dict.search_entry_all(cache.clone(), "bow").await, values);
+        assert_eq!(
+            dict.search_entry(cache.clone(), "bow").await.as_deref(),
+            Some("bow sense 00")
+        );
+        assert_eq!(
+            dict.search_entry_all(cache.clone(), "cello").await,
+            vec!["c"]
+        );
+        assert!(dict.search_entry_all(cache, "bows").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn glob_search_with_and_without_prefix() {
+        let dir = temp_dir("glob_search_with_and_without_prefix");
+        let words = [
+            "Cat", "coat", "cot", "cut", "word", "weird", "wild", "world",
+        ];
+        let entries: Vec<(&str, &str)> = words.iter().map(|w| (*w, "x")).collect();
+        let path = save_entries(&dir, "glob.bel", small_nodes(64), &entries).await;
+        let (dict, cache) = open(&path).await;
+        assert_eq!(
+            dict.search_glob(cache.clone(), "c?t", 10).await,
+            vec!["Cat", "cot", "cut"]
+        );
+        assert_eq!(
+            dict.search_glob(cache.clone(), "w*rd", 10).await,
+            vec!["weird", "word"]
+        );
+        assert_eq!(dict.search_glob(cache.clone(), "c?t", 2).await.len(), 2);
+        // a leading wildcard scans from the first leaf
+        assert_eq!(
+            dict.search_glob(cache.clone(), "*ld", 10).await,
+            vec!["wild", "world"]
+        );
+        assert!(dict.search_glob(cache, "x*", 10).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn suffix_search_uses_reversed_tree() {
+        let dir = temp_dir("suffix_search_uses_reversed_tree");
+        let entries = [
+            ("geology", "x"),
+            ("apple", "x"),
+            ("Ecology", "x"),
+            ("biology", "x"),
+            ("logy", "x"),
+        ];
+        let mut metadata = small_nodes(256);
+        metadata.suffix_index = true;
+        let path = save_entries(&dir, "suffix.bel", metadata, &entries).await;
+        let (dict, cache) = open(&path).await;
+        assert_eq!(
+            dict.search_suffix(cache.clone(), "ology", 10).await,
+            vec!["Ecology", "geology", "biology"],
+            "in the order of the reversed headwords"
+        );
+        assert_eq!(
+            dict.search_suffix(cache.clone(), "OLOGY", 2).await,
+            vec!["Ecology", "geology"]
+        );
+        assert_eq!(dict.search_suffix(cache.clone(), "logy", 10).await.len(), 4);
+        assert!(dict
+            .search_suffix(cache.clone(), "pear", 10)
+            .await
+            .is_empty());
+
+        let path = save_entries(&dir, "plain.bel", Metadata::new(), &entries).await;
+        let (dict, cache) = open(&path).await;
+        assert!(dict.search_suffix(cache, "ology", 10).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn fuzzy_finds_typos() {
+        let dir = temp_dir("fuzzy_finds_typos");
+        let words = ["deceive", "receipt", "receive", "recipe", "relieve"];
+        let entries: Vec<(&str, &str)> = words.iter().map(|w| (*w, "x")).collect();
+        let path = save_entries(&dir, "fuzzy.bel", small_nodes(64), &entries).await;
+        let (dict, cache) = open(&path).await;
+        let found = dict
+            .search_fuzzy(cache.clone(), "recieve", 2, 0.0, 10)
+            .await;
+        let names: Vec<&str> = found.iter().map(|f| f.0.as_str()).collect();
+        // a swap is two edits, one more than "relieve" needs
+        assert_eq!(names, vec!["relieve", "receive", "recipe"]);
+        // the second letter is wrong, the candidates still start with "r"
+        let found = dict
+            .search_fuzzy(cache.clone(), "rwlieve", 1, 0.0, 10)
+            .await;
+        assert_eq!(found, vec![("relieve".to_string(), 1.0 - 1.0 / 7.0)]);
+        // short enough to be replaced entirely, every headword is a candidate
+        let found = dict.search_fuzzy(cache, "x", 1, 0.0, 10).await;
+        assert!(found.is_empty());
+    }
+
+    #[tokio::test]
+    async fn fuzzy_compares_sort_keys() {
+        let dir = temp_dir("fuzzy_compares_sort_keys");
+        let mut metadata = Metadata::new();
+        metadata.collation = "de".to_string();
+        let path = save_entries(
+            &dir,
+            "de.bel",
+            metadata,
+            &[("Strand", "x"), ("Straße", "x")],
+        )
+        .await;
+        let (dict, cache) = open(&path).await;
+        let found = dict.search_fuzzy(cache, "strasse", 0, 0.0, 10).await;
+        assert_eq!(found, vec![("Straße".to_string(), 1.0)]);
+    }
+
+    /// Entries `w0` to `w<n>`, each redirecting to the next, the last one
+    /// holding the definition
+    fn redirect_chain(n: usize) -> Vec<(String, String)> {
+        let mut entries: Vec<(String, String)> = (0..n)
+            .map(|i| (format!("w{}", i), format!("{}w{}", REDIRECT, i + 1)))
+            .collect();
+        entries.push((format!("w{}", n), "the end".to_string()));
+        entries
+    }
+
+    #[tokio::test]
+    async fn redirect_chains_and_loops_are_told_apart() {
+        let dir = temp_dir("redirect_chains_and_loops_are_told_apart");
+        let mut entries = redirect_chain(MAX_REDIRECTS + 1);
+        entries.push(("ping".to_string(), format!("{}pong", REDIRECT)));
+        entries.push(("pong".to_string(), format!("{}ping", REDIRECT)));
+        let entries: Vec<(&str, &str)> = entries
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+        let path = save_entries(&dir, "chain.bel", Metadata::new(), &entries).await;
+        let (dict, cache) = open(&path).await;
+        // a chain longer than the old limit of 3 resolves
+        assert_eq!(
+            dict.resolve_entry(cache.clone(), "w1")
+                .await
+                .unwrap()
+                .as_deref(),
+            Some("the end")
+        );
+        assert!(matches!(
+            dict.resolve_entry(cache.clone(), "w0").await,
+            Err(Error::TooManyRedirects(_))
+        ));
+        assert!(matches!(
+            dict.resolve_entry(cache.clone(), "ping").await,
+            Err(Error::RedirectLoop(_))
+        ));
+        assert_eq!(dict.search_entry(cache.clone(), "ping").await, None);
+        assert_eq!(dict.resolve_entry(cache, "nothing").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn redirects_follow_the_configured_marker() {
+        let dir = temp_dir("redirects_follow_the_configured_marker");
+        let entries = [
+            ("colour", ">>>SEE:color"),
+            ("color", "hue"),
+            ("hue", "@@@LINK=color"),
+            ("a", ">>>SEE:b"),
+            ("b", ">>>SEE:c"),
+            ("c", "letter"),
+        ];
+        let path = save_entries(&dir, "marker.bel", Metadata::new(), &entries).await;
+        let (mut dict, cache) = open(&path).await;
+        assert_eq!(
+            dict.search_entry(cache.clone(), "colour").await.as_deref(),
+            Some(">>>SEE:color")
+        );
+        dict.set_redirect_config(RedirectConfig {
+            marker: ">>>SEE:".to_string(),
+            max_depth: 1,
+        });
+        assert_eq!(
+            dict.search_entry(cache.clone(), "colour").await.as_deref(),
+            Some("hue")
+        );
+        // the default marker is no longer special
+        assert_eq!(
+            dict.search_entry(cache.clone(), "hue").await.as_deref(),
+            Some("@@@LINK=color")
+        );
+        assert!(matches!(
+            dict.resolve_entry(cache, "a").await,
+            Err(Error::TooManyRedirects(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn entry_iter_walks_every_leaf() {
+        let dir = temp_dir("entry_iter_walks_every_leaf");
+        let words: Vec<(String, String)> = (0..300)
+            .map(|i| (format!("w{:03}", i), format!("value {}", i)))
+            .collect();
+        let entries: Vec<(&str, &str)> = words
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+        let path = save_entries(&dir, "iter.bel", small_nodes(256), &entries).await;
+        let (dict, cache) = open(&path).await;
+        let mut iter = dict.iter_entries(cache.clone());
+        let mut seen = vec![];
+        while let Some((name, value)) = iter.next_entry().await {
+            seen.push((name, String::from_utf8(value).unwrap()));
+        }
+        assert_eq!(seen.len(), dict.metadata().entry_num as usize);
+        assert_eq!(seen, words);
+        assert!(iter.next_entry().await.is_none());
+        // the leaves went through the cache
+        let cached = cache.read().await.keys_in_lru_order().len();
+        assert!(cached > 1);
+        let count = dict.for_each_entry(cache.clone(), |_, _| {}).await;
+        assert_eq!(count, 300);
+        assert_eq!(cache.read().await.keys_in_lru_order().len(), cached);
+    }
+
+    #[tokio::test]
+    async fn fulltext_reads_token_tree() {
+        let dir = temp_dir("fulltext_reads_token_tree");
+        let path = file_path(&dir, "tokens.bel");
+        let mut po = Beluga::new(Metadata::new(), BelFileType::Entry).unwrap();
+        for (name, value) in [
+            ("apple", "red fruit"),
+            ("banana", "yellow fruit"),
+            ("oak", "tree"),
+        ] {
+            po.input_entry(name.to_string(), value.as_bytes().to_vec());
+        }
+        po.input_token(
+            "fruit".to_string(),
+            vec!["apple".to_string(), "banana".to_string()],
+        );
+        po.input_token("tree".to_string(), vec!["oak".to_string()]);
+        po.save(&path).await.unwrap();
+        let (dict, cache) = open(&path).await;
+        assert_eq!(
+            dict.search_fulltext(cache.clone(), "fruit", 10).await,
+            vec!["apple", "banana"]
+        );
+        assert_eq!(
+            dict.search_fulltext(cache.clone(), "fruit", 1).await,
+            vec!["apple"]
+        );
+        assert_eq!(
+            dict.search_fulltext(cache.clone(), "tree", 10).await,
+            vec!["oak"]
+        );
+        assert!(dict.search_fulltext(cache, "red", 10).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn exact_and_ignore_case_lookups() {
+        let dir = temp_dir("exact_and_ignore_case_lookups");
+        let entries = [
+            ("Apple", "company"),
+            ("apple", "fruit"),
+            ("Banana", "plant"),
+        ];
+        let path = save_entries(&dir, "case.bel", Metadata::new(), &entries).await;
+        let (dict, cache) = open(&path).await;
+        for (name, found) in [
+            ("Apple", Some("company")),
+            ("apple", Some("fruit")),
+            ("APPLE", None),
+            ("banana", None),
+        ] {
+            let exact = dict.search_entry(cache.clone(), name).await;
+            assert_eq!(exact.as_deref(), found, "{}", name);
+        }
+        for (name, found) in [
+            ("Apple", Some("company")),
+            ("apple", Some("fruit")),
+            ("banana", Some("plant")),
+            ("cherry", None),
+        ] {
+            let folded = dict.search_entry_ignore_case(cache.clone(), name).await;
+            assert_eq!(folded.as_deref(), found, "{}", name);
+        }
+        let folded = dict.search_entry_ignore_case(cache, "APPLE").await;
+        assert!(folded.is_some());
+    }
+
+    #[cfg(feature = "nfc")]
+    #[tokio::test]
+    async fn composed_and_decomposed_meet() {
+        let dir = temp_dir("composed_and_decomposed_meet");
+        let composed = "caf\u{e9}";
+        let decomposed = "cafe\u{301}";
+        let entries = [(decomposed, "coffee"), ("cafeteria", "canteen")];
+        let path = save_entries(&dir, "nfc.bel", Metadata::new(), &entries).await;
+        let (dict, cache) = open(&path).await;
+        for name in [composed, decomposed] {
+            assert_eq!(
+                dict.search_entry(cache.clone(), name).await.as_deref(),
+                Some("coffee")
+            );
+        }
+        assert_eq!(
+            dict.search(cache.clone(), decomposed, &SearchOptions::new())
+                .await,
+            vec![composed]
+        );
+        assert_eq!(
+            dict.search(cache, "caf", &SearchOptions::new()).await,
+            vec!["cafeteria", composed]
+        );
+    }
+
+    #[tokio::test]
+    async fn prefix_search_folds_diacritics_on_request() {
+        let dir = temp_dir("prefix_search_folds_diacritics_on_request");
+        let entries = [("rest", "x"), ("résumé", "x"), ("rôle", "x")];
+        let path = save_entries(&dir, "accents.bel", Metadata::new(), &entries).await;
+        let (dict, cache) = open(&path).await;
+        let folded = SearchOptions::new().fold_diacritics(true);
+        assert_eq!(
+            dict.search(cache.clone(), "resu", &folded).await,
+            vec!["résumé"]
+        );
+        assert_eq!(
+            dict.search(cache.clone(), "résu", &folded).await,
+            vec!["résumé"]
+        );
+        assert_eq!(
+            dict.search(cache.clone(), "role", &folded).await,
+            vec!["rôle"]
+        );
+        let plain = SearchOptions::new();
+        assert!(dict.search(cache.clone(), "resu", &plain).await.is_empty());
+        assert_eq!(dict.search(cache, "résu", &plain).await, vec!["résumé"]);
+    }
+
+    #[tokio::test]
+    async fn search_options_limit_prefix_and_phrase_results() {
+        let defaults = SearchOptions::default();
+        assert!(!defaults.strict && defaults.fold_case && !defaults.fold_diacritics);
+        assert_eq!((defaults.prefix_limit, defaults.phrase_limit), (10, 0));
+
+        let dir = temp_dir("search_options_limit_prefix_and_phrase_results");
+        let path = file_path(&dir, "limits.bel");
+        let mut po = Beluga::new(Metadata::new(), BelFileType::Entry).unwrap();
+        for name in ["car", "card", "care", "carp", "vehicle"] {
+            po.input_entry(name.to_string(), b"x".to_vec());
+        }
+        po.input_token("car".to_string(), vec!["vehicle".to_string()]);
+        po.save(&path).await.unwrap();
+        let (dict, cache) = open(&path).await;
+        let options = SearchOptions::new().prefix_limit(2);
+        assert_eq!(
+            dict.search(cache.clone(), "car", &options).await,
+            vec!["car", "card"]
+        );
+        let options = options.phrase_limit(1);
+        assert_eq!(
+            dict.search(cache.clone(), "car", &options).await,
+            vec!["car", "card", "vehicle"]
+        );
+        let options = SearchOptions::new().strict(true).prefix_limit(10);
+        assert_eq!(
+            dict.search(cache, "CAR", &options).await,
+            Vec::<String>::new()
+        );
+    }
+
+    #[tokio::test]
+    async fn prefix_search_pages_reassemble() {
+        let dir = temp_dir("prefix_search_pages_reassemble");
+        let mut words: Vec<String> = (0..120).map(|i| format!("bank{:03}", i)).collect();
+        // case variants of one headword, possibly split over a page boundary
+        words.extend(["Bank050", "BANK050", "Bank099"].map(String::from));
+        words.extend(["bam", "bar", "ban"].map(String::from));
+        let entries: Vec<(&str, &str)> = words.iter().map(|w| (w.as_str(), "x")).collect();
+        let path = save_entries(&dir, "pages.bel", small_nodes(256), &entries).await;
+        let (dict, cache) = open(&path).await;
+        let all = dict
+            .search(
+                cache.clone(),
+                "bank",
+                &SearchOptions::new().prefix_limit(1000),
+            )
+            .await;
+        assert_eq!(all.len(), 123);
+        for page_size in [1, 7, 50] {
+            let mut pages = vec![];
+            let mut after = None;
+            loop {
+                let options = SearchOptions::new().prefix_limit(page_size).after(after);
+                let page = dict.search(cache.clone(), "bank", &options).await;
+                if page.is_empty() {
+                    break;
+                }
+                assert!(page.len() <= page_size);
+                after = page.last().cloned();
+                pages.extend(page);
+            }
+            assert_eq!(pages, all, "page size {}", page_size);
+        }
+    }
+
+    #[tokio::test]
+    async fn resource_range_is_clamped() {
+        let dir = temp_dir("resource_range_is_clamped");
+        let path = file_path(&dir, "audio.bel");
+        let mut po = Beluga::new(Metadata::new(), BelFileType::Entry).unwrap();
+        po.input_entry("apple".to_string(), b"x".to_vec());
+        let audio: Vec<u8> = (0..5000u32).map(|i| (i % 251) as u8).collect();
+        po.input_resource("apple.mp3".to_string(), audio.clone());
+        po.save(&path).await.unwrap();
+        let (dict, cache) = open(&path).await;
+        let range = |start, end| dict.search_resource_range(cache.clone(), "apple.mp3", start, end);
+        assert_eq!(range(100, 356).await.unwrap(), audio[100..356]);
+        assert_eq!(range(4990, 9000).await.unwrap(), audio[4990..]);
+        assert_eq!(range(0, usize::MAX).await.unwrap(), audio);
+        assert!(range(6000, 7000).await.unwrap().is_empty());
+        assert!(range(300, 200).await.unwrap().is_empty());
+        assert_eq!(
+            dict.search_resource_range(cache.clone(), "pear.mp3", 0, 10)
+                .await,
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn batch_lookup_matches_single_lookups() {
+        let dir = temp_dir("batch_lookup_matches_single_lookups");
+        let mut entries: Vec<(String, String)> = (0..200)
+            .map(|i| (format!("word{:03}", i), format!("definition {}", i)))
+            .collect();
+        entries.push(("Apple".to_string(), "fruit".to_string()));
+        entries.push(("alias".to_string(), format!("{}word007", REDIRECT)));
+        entries.push(("alias2".to_string(), format!("{}alias", REDIRECT)));
+        entries.push(("loop1".to_string(), format!("{}loop2", REDIRECT)));
+        entries.push(("loop2".to_string(), format!("{}loop1", REDIRECT)));
+        let entries: Vec<(&str, &str)> = entries
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+        let path = save_entries(&dir, "batch.bel", small_nodes(256), &entries).await;
+        let (dict, cache) = open(&path).await;
+        let names = [
+            "word150", "alias", "missing", "word000", "apple", "Apple", "word199", "word150",
+            "word099", "word100", "zzz", "alias2", "loop1",
+        ];
+        let batch = dict.search_words(cache.clone(), &names).await;
+        assert_eq!(batch.len(), names.len());
+        for (name, found) in names.iter().zip(&batch) {
+            assert_eq!(
+                *found,
+                dict.search_entry(cache.clone(), name).await,
+                "{}",
+                name
+            );
+        }
+        assert_eq!(batch[1], Some("definition 7".to_string()));
+        assert_eq!(batch[2], None);
+        assert_eq!(batch[11], Some("definition 7".to_string()));
+        assert_eq!(batch[12], None);
+    }
+
+    fn numbered_words(n: usize) -> Vec<(String, String)> {
+        (0..n)
+            .map(|i| (format!("word{:04}", i), format!("definition {}", i)))
+            .collect()
+    }
+
+    async fn save_numbered(dir: &Path, file: &str, n: usize) -> String {
+        let entries = numbered_words(n);
+        let entries: Vec<(&str, &str)> = entries
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+        save_entries(dir, file, small_nodes(256), &entries).await
+    }
+
+    #[tokio::test]
+    async fn cache_stays_within_capacity() {
+        let dir = temp_dir("cache_stays_within_capacity");
+        let path = save_numbered(&dir, "bounded.bel", 500).await;
+        let (dict, _) = open(&path).await;
+        let cache = Arc::new(RwLock::new(NodeCache::new(4096)));
+        for (name, value) in numbered_words(500) {
+            let found = dict.search_entry(cache.clone(), &name).await;
+            assert_eq!(found, Some(value));
+            assert!(cache.read().await.size() <= 4096);
+        }
+        assert!(cache.read().await.size() > 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn lookups_run_concurrently() {
+        let dir = temp_dir("lookups_run_concurrently");
+        let words: Vec<(String, String)> = (0..500)
+            .map(|i| (format!("w{:03}", i), format!("value {}", i)))
+            .collect();
+        let entries: Vec<(&str, &str)> = words
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+        let path = save_entries(&dir, "shared.bel", small_nodes(256), &entries).await;
+        let (dict, cache) = open(&path).await;
+        let dict = Arc::new(dict);
+        let words = Arc::new(words);
+        let tasks: Vec<_> = (0..8)
+            .map(|t| {
+                let (dict, cache, words) = (dict.clone(), cache.clone(), words.clone());
+                tokio::spawn(async move {
+                    // each task starts somewhere else, so leaves are read in
+                    // different orders
+                    for (name, value) in words.iter().skip(t * 60).chain(words.iter()) {
+                        let found = dict.search_entry(cache.clone(), name).await;
+                        assert_eq!(found.as_ref(), Some(value));
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        // cache hits share the lock: a lookup of cached nodes goes through
+        // while another reader holds it
+        let held = cache.read().await;
+        let found = tokio::time::timeout(
+            std::time::Duration::from_secs(5),
+            dict.search_entry(cache.clone(), "w250"),
+        )
+        .await
+        .expect("cache hit blocked by a reader");
+        assert_eq!(found.as_deref(), Some("value 250"));
+        drop(held);
+    }
+
+    #[cfg(feature = "mmap")]
+    #[tokio::test]
+    async fn mmap_and_file_backends_agree() {
+        let dir = temp_dir("mmap_and_file_backends_agree");
+        let words: Vec<(String, String)> = (0..400)
+            .map(|i| (format!("w{:03}", i), format!("value {}", i)))
+            .collect();
+        let entries: Vec<(&str, &str)> = words
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+        let path = save_entries(&dir, "mapped.bel", small_nodes(256), &entries).await;
+        let (mut dict, _) = open(&path).await;
+        assert!(dict.entry.mmap.is_some());
+        let mut results = vec![];
+        for _ in ["mmap", "file"] {
+            let cache = new_cache();
+            let mut found = vec![];
+            for (name, _) in &words {
+                found.push(dict.search_entry(cache.clone(), name).await);
+            }
+            found.push(dict.search_entry(cache.clone(), "missing").await);
+            let prefix = dict
+                .search(
+                    cache.clone(),
+                    "w1",
+                    &SearchOptions::new().prefix_limit(1000),
+                )
+                .await;
+            let count = dict.for_each_entry(cache, |_, _| {}).await;
+            results.push((found, prefix, count));
+            dict.entry.mmap = None;
+        }
+        assert_eq!(results[0], results[1]);
+        assert_eq!(results[0].0[399].as_deref(), Some("value 399"));
+        assert_eq!(results[0].1.len(), 100);
+    }
+
+    #[tokio::test]
+    async fn scan_stops_at_timeout() {
+        let dir = temp_dir("scan_stops_at_timeout");
+        let path = save_numbered(&dir, "timeout.bel", 300).await;
+        let (mut dict, cache) = open(&path).await;
+        assert_eq!(dict.search_glob(cache.clone(), "*", 1000).await.len(), 300);
+        dict.set_scan_timeout(Some(Duration::ZERO));
+        // the first leaf is scanned before the deadline is checked
+        let partial = dict.search_glob(cache.clone(), "*", 1000).await;
+        assert!(
+            !partial.is_empty() && partial.len() < 300,
+            "{}",
+            partial.len()
+        );
+        assert_eq!(
+            partial,
+            numbered_words(partial.len())
+                .into_iter()
+                .map(|w| w.0)
+                .collect::<Vec<_>>()
+        );
+        dict.set_scan_timeout(None);
+        assert_eq!(dict.search_glob(cache, "*", 1000).await.len(), 300);
+    }
+
+    #[tokio::test]
+    async fn prefix_scan_crosses_leaves_of_mixed_case() {
+        let dir = temp_dir("prefix_scan_crosses_leaves_of_mixed_case");
+        // with German order "Straße" sorts among "strasse", so its lowercase
+        // form differs from the sort key in the middle of the prefix range
+        let mut words: Vec<String> = vec![];
+        for i in 0..30 {
+            for stem in ["Strasse", "STRASSE", "Straße", "straße"] {
+                words.push(format!("{}{:02}", stem, i));
+            }
+        }
+        words.extend(["Strand", "Stroh", "Strassburg"].map(String::from));
+        let entries: Vec<(&str, &str)> = words.iter().map(|w| (w.as_str(), "x")).collect();
+        let mut metadata = small_nodes(256);
+        metadata.collation = "de".to_string();
+        let path = save_entries(&dir, "strasse.bel", metadata, &entries).await;
+        let (dict, cache) = open(&path).await;
+        let options = SearchOptions::new().fold_case(true).prefix_limit(1000);
+        let mut found = dict.search(cache.clone(), "strasse", &options).await;
+        found.sort();
+        let mut expected = words[..120].to_vec();
+        expected.sort();
+        assert_eq!(found, expected);
+
+        let options = SearchOptions::new().fold_case(false).prefix_limit(1000);
+        let found = dict.search(cache.clone(), "Straße", &options).await;
+        assert_eq!(found.len(), 30);
+        assert!(found.iter().all(|w| w.starts_with("Straße")));
+        let options = SearchOptions::new().fold_case(true).prefix_limit(1000);
+        assert_eq!(dict.search(cache, "strasse2", &options).await.len(), 40);
+    }
+
+    #[tokio::test]
+    async fn suggest_ranks_prefix_before_fuzzy() {
+        let dir = temp_dir("suggest_ranks_prefix_before_fuzzy");
+        let words = [
+            "deceive", "receipt", "receive", "received", "receiver", "relieve",
+        ];
+        let entries: Vec<(&str, &str)> = words.iter().map(|w| (*w, "x")).collect();
+        let path = save_entries(&dir, "suggest.bel", small_nodes(64), &entries).await;
+        let (dict, cache) = open(&path).await;
+        // "deceive" is one edit away but starts with another letter
+        assert_eq!(
+            dict.suggest(cache.clone(), "receive", 5).await,
+            vec!["receive", "received", "receiver", "receipt"]
+        );
+        assert_eq!(
+            dict.suggest(cache.clone(), "receive", 2).await,
+            vec!["receive", "received"]
+        );
+        assert_eq!(
+            dict.suggest(cache.clone(), "recieve", 5).await,
+            vec!["relieve", "receive"]
+        );
+        assert!(dict.suggest(cache.clone(), "xy", 5).await.is_empty());
+        assert!(dict.suggest(cache, "receive", 0).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn typed_resources_carry_their_mime_type() {
+        let dir = temp_dir("typed_resources_carry_their_mime_type");
+        let path = file_path(&dir, "typed.bel");
+        let mut po = Beluga::new(Metadata::new(), BelFileType::Entry).unwrap();
+        po.input_entry("apple".to_string(), b"fruit".to_vec());
+        po.input_resource("a.png".to_string(), b"\x89PNG\r\n".to_vec());
+        po.input_resource("style.css".to_string(), b"b { }".to_vec());
+        po.input_resource("sound".to_string(), b"OggS\0".to_vec());
+        po.input_resource("blob".to_string(), vec![1, 2, 3]);
+        po.save(&path).await.unwrap();
+        let (dict, cache) = open(&path).await;
+        for (name, mime) in [
+            ("a.png", "image/png"),
+            ("style.css", "text/css"),
+            ("sound", "audio/ogg"),
+            ("blob", "application/octet-stream"),
+        ] {
+            let (_, found) = dict
+                .search_resource_typed(cache.clone(), name)
+                .await
+                .unwrap();
+            assert_eq!(found, mime, "{}", name);
+        }
+        assert_eq!(dict.search_resource_typed(cache, "b.png").await, None);
+    }
+
+    #[tokio::test]
+    async fn definitions_point_at_the_resource_endpoint() {
+        let dir = temp_dir("definitions_point_at_the_resource_endpoint");
+        let html = r#"<img src="./a.png"><a href="sound://b.mp3">b</a><a href="entry://pear">pear</a><a href="https://x.org/c.png">c</a>"#;
+        let path = save_entries(&dir, "urls.bel", Metadata::new(), &[("apple", html)]).await;
+        let (mut dict, cache) = open(&path).await;
+        assert_eq!(
+            dict.search_entry(cache.clone(), "apple").await.as_deref(),
+            Some(html)
+        );
+        dict.set_url_rewrite(Some(UrlRewrite {
+            resource: "/resource/{dict}/{name}".to_string(),
+            entry: None,
+        }));
+        assert_eq!(
+            dict.search_entry(cache.clone(), "apple").await.as_deref(),
+            Some(
+                r#"<img src="/resource/urls/a.png"><a href="/resource/urls/b.mp3">b</a><a href="entry://pear">pear</a><a href="https://x.org/c.png">c</a>"#
+            )
+        );
+        dict.set_url_rewrite(Some(UrlRewrite {
+            resource: "/r/{name}".to_string(),
+            entry: Some("/word/{dict}/{name}".to_string()),
+        }));
+        assert_eq!(
+            dict.search_entry(cache, "apple").await.as_deref(),
+            Some(
+                r#"<img src="/r/a.png"><a href="/r/b.mp3">b</a><a href="/word/urls/pear">pear</a><a href="https://x.org/c.png">c</a>"#
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn css_and_js_are_scoped_per_dictionary() {
+        let dir = temp_dir("css_and_js_are_scoped_per_dictionary");
+        let path = save_entries(&dir, "style.bel", Metadata::new(), &[("a", "b")]).await;
+        let (mut dict, _cache) = open(&path).await;
+        assert_eq!(
+            dict.get_scoped_css_js(true, "7").await.unwrap(),
+            (String::new(), String::new())
+        );
+        std::fs::write(dir.join("style.css"), "b { color: red }").unwrap();
+        std::fs::write(dir.join("style.js"), "var x = 1;").unwrap();
+        let (css, js) = dict.get_scoped_css_js(true, "7").await.unwrap();
+        assert_eq!(css, ".beluga-dict-7 b { color: red }\n");
+        assert_eq!(js, "(function () {\nvar x = 1;\n})();\n");
+        let (css, js) = dict.get_css_js(true).await.unwrap();
+        assert_eq!(
+            (css.as_str(), js.as_str()),
+            ("b { color: red }", "var x = 1;")
+        );
+    }
+
+    async fn save_resources(dir: &Path, file: &str, resources: &[(&str, &[u8])]) {
+        let mut po = Beluga::new(Metadata::new(), BelFileType::Resource).unwrap();
+        for (name, data) in resources {
+            po.input_entry(name.to_string(), data.to_vec());
+        }
+        po.save(&file_path(dir, file)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn resource_files_are_listed() {
+        let dir = temp_dir("resource_files_are_listed");
+        let path = save_entries(&dir, "dict.bel", Metadata::new(), &[("a", "b")]).await;
+        let (dict, cache) = open(&path).await;
+        assert!(dict.resource_ids().is_empty());
+        assert!(!dict.has_resource(cache, "a.png").await);
+
+        save_resources(&dir, "dict.beld", &[("a.png", b"png")]).await;
+        save_resources(&dir, "dict.1.beld", &[("b.mp3", b"mp3")]).await;
+        let (dict, cache) = open(&path).await;
+        let mut ids = dict.resource_ids();
+        ids.sort();
+        assert_eq!(ids, vec!["", "1"]);
+        assert!(dict.has_resource(cache.clone(), "a.png").await);
+        assert!(dict.has_resource(cache.clone(), "b.mp3").await);
+        assert!(!dict.has_resource(cache.clone(), "c.png").await);
+        assert_eq!(
+            dict.search_resource(cache, "b.mp3").await,
+            Some(b"mp3".to_vec())
+        );
+    }
+
+    #[tokio::test]
+    async fn only_matching_resource_files_are_loaded() {
+        assert_eq!(resource_id("cat.beld", "cat", ".beld"), Some(""));
+        assert_eq!(resource_id("cat.2.beld", "cat", ".beld"), Some("2"));
+        assert_eq!(resource_id("cats.beld", "cat", ".beld"), None);
+        assert_eq!(resource_id("cat..beld", "cat", ".beld"), None);
+        assert_eq!(resource_id("cat.bel", "cat", ".beld"), None);
+        assert_eq!(resource_id("日本.音.beld", "日本", ".beld"), Some("音"));
+        assert_eq!(resource_id("日.beld", "日本", ".beld"), None);
+
+        let dir = temp_dir("only_matching_resource_files_are_loaded");
+        let path = save_entries(&dir, "cat.bel", Metadata::new(), &[("a", "b")]).await;
+        save_resources(&dir, "cat.beld", &[("a.png", b"png")]).await;
+        save_resources(&dir, "cats.beld", &[("b.png", b"png")]).await;
+        save_resources(&dir, "ca.beld", &[("c.png", b"png")]).await;
+        std::fs::write(dir.join("cat.x.txt"), "x").unwrap();
+        let (dict, cache) = open(&path).await;
+        assert_eq!(dict.resource_ids(), vec![""]);
+        assert!(dict.has_resource(cache.clone(), "a.png").await);
+        assert!(!dict.has_resource(cache, "b.png").await);
+    }
+
+    #[tokio::test]
+    async fn prefix_case_follows_options() {
+        let dir = temp_dir("prefix_case_follows_options");
+        let entries = [
+            ("paris", "x"),
+            ("Paris", "x"),
+            ("PARIS", "x"),
+            ("parade", "x"),
+        ];
+        let path = save_entries(&dir, "case.bel", Metadata::new(), &entries).await;
+        let (dict, cache) = open(&path).await;
+        let cases = [
+            (
+                false,
+                true,
+                false,
+                vec!["parade", "paris", "Paris", "PARIS"],
+            ),
+            (false, true, true, vec!["Paris", "PARIS"]),
+            (false, false, false, vec!["Paris"]),
+            (false, false, true, vec!["Paris"]),
+            (true, true, false, vec!["Paris"]),
+            (true, true, true, vec!["Paris"]),
+            (true, false, false, vec!["Paris"]),
+        ];
+        for (strict, fold_case, smart_case, expected) in cases {
+            let options = SearchOptions::new()
+                .strict(strict)
+                .fold_case(fold_case)
+                .smart_case(smart_case);
+            assert_eq!(
+                dict.search(cache.clone(), "Par", &options).await,
+                expected,
+                "strict {} fold_case {} smart_case {}",
+                strict,
+                fold_case,
+                smart_case
+            );
+        }
+        let options = SearchOptions::new().smart_case(true);
+        assert_eq!(
+            dict.search(cache.clone(), "par", &options).await,
+            vec!["parade", "paris", "Paris", "PARIS"],
+            "lowercase matches either case"
+        );
+    }
+
+    #[tokio::test]
+    async fn hot_nodes_follow_use_and_survive_prefetch() {
+        let dir = temp_dir("hot_nodes_follow_use_and_survive_prefetch");
+        let path = save_numbered(&dir, "hot.bel", 500).await;
+        let (dict, cache) = open(&path).await;
+        dict.search_entry(cache.clone(), "word0000").await;
+        let first_leaf = dict.hot_nodes(cache.clone()).await[0];
+        dict.search_entry(cache.clone(), "word0499").await;
+        assert_ne!(dict.hot_nodes(cache.clone()).await[0], first_leaf);
+        // a hit moves the leaf to the front again
+        dict.search_entry(cache.clone(), "word0000").await;
+        let snapshot = dict.hot_nodes(cache.clone()).await;
+        assert_eq!(snapshot[0], first_leaf);
+
+        let (dict, cache) = open(&path).await;
+        assert_eq!(
+            dict.prefetch(cache.clone(), &snapshot).await,
+            snapshot.len()
+        );
+        assert_eq!(dict.hot_nodes(cache).await, snapshot);
+    }
+
+    #[tokio::test]
+    async fn for_each_entry_matches_loaded_traversal() {
+        let dir = temp_dir("for_each_entry_matches_loaded_traversal");
+        let path = save_numbered(&dir, "walk.bel", 400).await;
+        let mut loaded: Vec<(String, Vec<u8>)> = vec![];
+        Beluga::from_file(&path)
+            .await
+            .unwrap()
+            .traverse_entry(&mut |k, v| loaded.push((k.0.clone(), v.0.clone())));
+        let (dict, cache) = open(&path).await;
+        let mut walked: Vec<(String, Vec<u8>)> = vec![];
+        let count = dict
+            .for_each_entry(cache, |name, value| {
+                walked.push((name.to_string(), value.to_vec()))
+            })
+            .await;
+        assert_eq!(count, 400);
+        assert_eq!(walked, loaded);
+    }
+
+    #[tokio::test]
+    async fn embedded_resources_stay_out_of_entries() {
+        let dir = temp_dir("embedded_resources_stay_out_of_entries");
+        let path = file_path(&dir, "single.bel");
+        let image: Vec<u8> = (0..=255).collect();
+        let mut po = Beluga::new(Metadata::new(), BelFileType::Entry).unwrap();
+        po.input_entry("apple".to_string(), b"<img src=\"a.png\">".to_vec());
+        po.input_entry("banana".to_string(), b"yellow".to_vec());
+        po.input_resource("a.png".to_string(), image.clone());
+        po.input_resource(
+            "b.png".to_string(),
+            format!("{}a.png", REDIRECT).into_bytes(),
+        );
+        po.save(&path).await.unwrap();
+        let report = Beluga::verify(&path).await.unwrap();
+        assert_eq!(report.anomaly, None);
+        assert_eq!(report.entry_num, 2);
+
+        let (dict, cache) = open(&path).await;
+        let names = dict.filter_headwords(cache.clone(), |_| true, 10).await;
+        assert_eq!(names, vec!["apple", "banana"]);
+        assert_eq!(dict.for_each_entry(cache.clone(), |_, _| {}).await, 2);
+        let options = SearchOptions::default();
+        assert_eq!(
+            dict.search(cache.clone(), "a", &options).await,
+            vec!["apple"]
+        );
+        assert_eq!(
+            dict.search(cache.clone(), "b", &options).await,
+            vec!["banana"]
+        );
+        assert_eq!(
+            dict.search_resource(cache.clone(), "b.png").await,
+            Some(image.clone())
+        );
+        let mut copied: Vec<u8> = vec![];
+        let n = dict
+            .copy_resource_range(cache.clone(), "a.png", 10, 20, &mut copied)
+            .await
+            .unwrap();
+        assert_eq!(n, Some(10));
+        assert_eq!(copied, image[10..20]);
+        assert_eq!(dict.search_resource(cache, "c.png").await, None);
+
+        let compacted = file_path(&dir, "compacted.bel");
+        Beluga::compact(&path, &compacted).await.unwrap();
+        let (dict, cache) = open(&compacted).await;
+        assert_eq!(dict.search_resource(cache, "a.png").await, Some(image));
+    }
+
+    #[tokio::test]
+    async fn file_without_tokens_round_trips() {
+        let dir = temp_dir("file_without_tokens_round_trips");
+        let entries = [("apple", "fruit"), ("apply", "use"), ("banana", "fruit")];
+        let path = save_entries(&dir, "plain.bel", Metadata::new(), &entries).await;
+        let (dict, cache) = open(&path).await;
+        assert_eq!(dict.entry.token_root, (0, 0));
+        let options = SearchOptions::new().phrase_limit(5);
+        assert_eq!(
+            dict.search(cache.clone(), "app", &options).await,
+            vec!["apple", "apply"]
+        );
+        assert!(dict
+            .search_fulltext(cache.clone(), "fruit", 5)
+            .await
+            .is_empty());
+        let token_root = dict.entry.token_root;
+        assert_eq!(
+            dict.entry
+                .search_entry(cache.clone(), token_root, "fruit", true)
+                .await,
+            None
+        );
+
+        let mut po = Beluga::from_file(&path).await.unwrap();
+        let copy = file_path(&dir, "copy.bel");
+        po.save(&copy).await.unwrap();
+        Beluga::append_entries(&copy, vec![("cherry".to_string(), b"red".to_vec())])
+            .await
+            .unwrap();
+        let (dict, cache) = open(&copy).await;
+        assert_eq!(dict.entry.token_root, (0, 0));
+        assert_eq!(
+            dict.search_entry(cache.clone(), "cherry").await.as_deref(),
+            Some("red")
+        );
+        assert_eq!(dict.search(cache, "b", &options).await, vec!["banana"]);
+    }
+
+    #[tokio::test]
+    async fn fuzzy_similarity_cuts_off_short_words() {
+        let dir = temp_dir("fuzzy_similarity_cuts_off_short_words");
+        let entries = [
+            ("car", "x"),
+            ("cat", "x"),
+            ("internationalisation", "x"),
+            ("internationalization", "x"),
+        ];
+        let path = save_entries(&dir, "similar.bel", Metadata::new(), &entries).await;
+        let (dict, cache) = open(&path).await;
+        let found = dict.search_fuzzy(cache.clone(), "cas", 1, 0.0, 10).await;
+        assert_eq!(
+            found,
+            vec![
+                ("car".to_string(), 1.0 - 1.0 / 3.0),
+                ("cat".to_string(), 1.0 - 1.0 / 3.0)
+            ]
+        );
+        assert!(dict
+            .search_fuzzy(cache.clone(), "cas", 1, 0.7, 10)
+            .await
+            .is_empty());
+        let found = dict
+            .search_fuzzy(cache.clone(), "internationalizatio", 2, 0.7, 10)
+            .await;
+        assert_eq!(
+            found,
+            vec![
+                ("internationalization".to_string(), 1.0 - 1.0 / 20.0),
+                ("internationalisation".to_string(), 1.0 - 2.0 / 20.0)
+            ]
+        );
+        let found = dict
+            .search_fuzzy(cache, "internationalizatio", 2, 0.92, 10)
+            .await;
+        assert_eq!(
+            found,
+            vec![("internationalization".to_string(), 1.0 - 1.0 / 20.0)]
+        );
+    }
+
+    #[tokio::test]
+    async fn resource_size_matches_the_data() {
+        let dir = temp_dir("resource_size_matches_the_data");
+        let path = save_entries(&dir, "sizes.bel", Metadata::new(), &[("a", "b")]).await;
+        let big: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        let link = format!("{}big.bin", REDIRECT).into_bytes();
+        let resources: [(&str, &[u8]); 4] = [
+            ("big.bin", &big),
+            ("empty.bin", b""),
+            ("link.bin", &link),
+            ("small.txt", b"hello"),
+        ];
+        save_resources(&dir, "sizes.beld", &resources).await;
+        let (dict, cache) = open(&path).await;
+        for name in ["big.bin", "empty.bin", "link.bin", "small.txt"] {
+            let data = dict.search_resource(cache.clone(), name).await.unwrap();
+            assert_eq!(
+                dict.resource_size(cache.clone(), name).await,
+                Some(data.len() as u64),
+                "{}",
+                name
+            );
+        }
+        assert_eq!(
+            dict.resource_size(cache.clone(), "link.bin").await,
+            Some(100_000)
+        );
+        assert_eq!(dict.resource_size(cache, "missing.bin").await, None);
+    }
+
+    #[tokio::test]
+    async fn multi_prefix_results_merge_by_distance() {
+        let dir = temp_dir("multi_prefix_results_merge_by_distance");
+        let entries = [
+            ("car", "x"),
+            ("card", "x"),
+            ("cardigan", "x"),
+            ("care", "x"),
+            ("carton", "x"),
+            ("cat", "x"),
+        ];
+        let path = save_entries(&dir, "multi.bel", small_nodes(64), &entries).await;
+        let (dict, cache) = open(&path).await;
+        // "card" is found by both prefixes and listed once, as close as "car"
+        assert_eq!(
+            dict.search_multi(cache.clone(), &["card", "car"], 10).await,
+            vec!["card", "car", "care", "carton", "cardigan"]
+        );
+        assert_eq!(
+            dict.search_multi(cache.clone(), &["card", "car"], 2).await,
+            vec!["card", "car"]
+        );
+        assert_eq!(
+            dict.search_multi(cache.clone(), &["cat", "dog"], 10).await,
+            vec!["cat"]
+        );
+        assert!(dict.search_multi(cache, &[], 10).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn raw_nodes_walk_the_tree() {
+        let dir = temp_dir("raw_nodes_walk_the_tree");
+        let path = save_numbered(&dir, "raw.bel", 200).await;
+        let (dict, cache) = open(&path).await;
+        let (mut offset, mut size) = dict.entry_root();
+        let mut depth = 0;
+        loop {
+            let (records, children) = dict
+                .read_node_raw(cache.clone(), offset, size)
+                .await
+                .unwrap();
+            if records[0].value.is_some() {
+                break;
+            }
+            assert_eq!(children.len(), records.len() + 1);
+            (offset, size) = children[0];
+            depth += 1;
+        }
+        assert!(depth >= 2);
+        let mut names = vec![];
+        while offset != 0 {
+            let (records, children) = dict
+                .read_node_raw(cache.clone(), offset, size)
+                .await
+                .unwrap();
+            assert_eq!(children.len(), 1);
+            names.extend(records.into_iter().map(|r| r.key.0));
+            (offset, size) = children[0];
+        }
+        let words: Vec<String> = numbered_words(200).into_iter().map(|w| w.0).collect();
+        assert_eq!(names, words);
+        let (root, root_size) = dict.entry_root();
+        assert!(dict
+            .read_node_raw(cache, root + 1, root_size)
+            .await
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn around_spans_leaf_boundaries() {
+        let dir = temp_dir("around_spans_leaf_boundaries");
+        let words: Vec<String> = (0..300).map(|i| format!("w{:03}", i)).collect();
+        let entries: Vec<(&str, &str)> = words.iter().map(|w| (w.as_str(), "x")).collect();
+        for prev_links in [false, true] {
+            let mut metadata = small_nodes(256);
+            metadata.prev_links = prev_links;
+            let file = format!("around-{}.bel", prev_links);
+            let path = save_entries(&dir, &file, metadata, &entries).await;
+            let (dict, cache) = open(&path).await;
+            // every position, so some windows cross one or more leaves
+            for i in 0..words.len() {
+                let window = dict.around(cache.clone(), &words[i], 7, 4).await;
+                let expected = &words[i.saturating_sub(7)..(i + 5).min(words.len())];
+                assert_eq!(window, expected, "{} {}", prev_links, words[i]);
+            }
+            assert_eq!(
+                dict.around(cache.clone(), "w100a", 1, 1).await,
+                vec!["w100", "w101", "w102"]
+            );
+            assert_eq!(
+                dict.around(cache.clone(), "a", 3, 1).await,
+                vec!["w000", "w001"]
+            );
+            assert_eq!(
+                dict.around(cache, "z", 2, 3).await,
+                vec!["w297", "w298", "w299"]
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn resource_range_follows_case_variants_across_leaves() {
+        let dir = temp_dir("resource_range_follows_case_variants_across_leaves");
+        for next_links in [false, true] {
+            let path = file_path(&dir, &format!("images-{}.bel", next_links));
+            let mut metadata = small_nodes(256);
+            metadata.next_links = next_links;
+            let mut po = Beluga::new(metadata, BelFileType::Entry).unwrap();
+            po.input_entry("apple".to_string(), b"x".to_vec());
+            let mut images: Vec<(String, Vec<u8>)> = vec![];
+            for i in 0..20 {
+                for name in [
+                    format!("IMG{:02}.png", i),
+                    format!("img{:02}.png", i),
+                    format!("Img{:02}.png", i),
+                ] {
+                    let data = format!("{} {}", name, "#".repeat(60)).into_bytes();
+                    po.input_resource(name.clone(), data.clone());
+                    images.push((name, data));
+                }
+            }
+            let big: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+            po.input_resource("big.bin".to_string(), big.clone());
+            po.save(&path).await.unwrap();
+
+            let (dict, cache) = open(&path).await;
+            let mut copied: Vec<u8> = vec![];
+            let n = dict
+                .copy_resource_range(cache.clone(), "big.bin", 1000, 1010, &mut copied)
+                .await
+                .unwrap();
+            assert_eq!(n, Some(10));
+            assert_eq!(copied, big[1000..1010]);
+            // the leaf holding the value was streamed, not decoded into the cache
+            assert!(cache.read().await.size() < 100_000);
+
+            // streamed leaves first, then the same leaves from the cache
+            for pass in ["streamed", "cached"] {
+                for (name, data) in &images {
+                    let mut copied: Vec<u8> = vec![];
+                    dict.copy_resource_range(cache.clone(), name, 0, u64::MAX, &mut copied)
+                        .await
+                        .unwrap();
+                    assert_eq!(&copied, data, "{} {} {}", next_links, pass, name);
+                }
+                for (name, data) in &images {
+                    assert_eq!(
+                        dict.search_resource(cache.clone(), name).await.as_ref(),
+                        Some(data)
+                    );
+                }
+            }
+            let mut copied: Vec<u8> = vec![];
+            let n = dict
+                .copy_resource_range(cache.clone(), "iMG05.png", 0, 10, &mut copied)
+                .await
+                .unwrap();
+            assert_eq!(n, None);
+        }
+    }
+
+    #[tokio::test]
+    async fn copied_resource_ranges_are_clamped() {
+        let dir = temp_dir("copied_resource_ranges_are_clamped");
+        let path = file_path(&dir, "ranges.bel");
+        let mut po = Beluga::new(Metadata::new(), BelFileType::Entry).unwrap();
+        po.input_entry("apple".to_string(), b"x".to_vec());
+        let big: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        po.input_resource("big.bin".to_string(), big.clone());
+        po.input_resource(
+            "link.bin".to_string(),
+            format!("{}big.bin", REDIRECT).into_bytes(),
+        );
+        po.save(&path).await.unwrap();
+        let (dict, cache) = open(&path).await;
+        // the first pass streams the leaf, the second reads it from the cache
+        for _ in 0..2 {
+            for name in ["big.bin", "link.bin"] {
+                for (start, end, expected) in [
+                    (0, 10, &big[..10]),
+                    (99_990, 200_000, &big[99_990..]),
+                    (0, u64::MAX, &big[..]),
+                    (100_000, 100_010, &big[..0]),
+                    (500, 400, &big[..0]),
+                ] {
+                    let mut copied: Vec<u8> = vec![];
+                    let n = dict
+                        .copy_resource_range(cache.clone(), name, start, end, &mut copied)
+                        .await
+                        .unwrap();
+                    assert_eq!(
+                        n,
+                        Some(expected.len() as u64),
+                        "{} {}..{}",
+                        name,
+                        start,
+                        end
+                    );
+                    assert_eq!(copied, expected);
+                }
+            }
+            dict.search_resource(cache.clone(), "big.bin")
+                .await
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn headwords_are_filtered_by_predicate() {
+        let dir = temp_dir("headwords_are_filtered_by_predicate");
+        let mut words: Vec<String> = (0..200).map(|i| format!("word{}", i)).collect();
+        words.extend(["alpha", "beta", "a-rather-long-headword", "zeta"].map(String::from));
+        let entries: Vec<(&str, &str)> = words.iter().map(|w| (w.as_str(), "x")).collect();
+        let path = save_entries(&dir, "filter.bel", small_nodes(256), &entries).await;
+        let (dict, cache) = open(&path).await;
+        let no_digit = |w: &str| !w.chars().any(|c| c.is_ascii_digit());
+        assert_eq!(
+            dict.filter_headwords(cache.clone(), no_digit, 100).await,
+            vec!["a-rather-long-headword", "alpha", "beta", "zeta"]
+        );
+        assert_eq!(
+            dict.filter_headwords(cache.clone(), |w| w.len() > 20, 100)
+                .await,
+            vec!["a-rather-long-headword"]
+        );
+        let nines = dict
+            .filter_headwords(cache.clone(), |w| w.ends_with('9'), 100)
+            .await;
+        assert_eq!(nines.len(), 20);
+        assert_eq!(
+            dict.filter_headwords(cache.clone(), |w| w.ends_with('9'), 3)
+                .await,
+            nines[..3]
+        );
+        assert!(dict.filter_headwords(cache, |_| true, 0).await.is_empty());
     }
 }
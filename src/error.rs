@@ -8,6 +8,28 @@ pub enum Error {
     FileError(#[from] io::Error),
     #[error("{0}")]
     Msg(String),
+    #[error("invalid file extension: {0}")]
+    InvalidExtension(String),
+    #[error("bad metadata: {0}")]
+    BadMetadata(String),
+    #[error("unsupported beluga spec {found}, expected {expected}")]
+    UnsupportedSpec { found: u16, expected: u16 },
+    /// A node that fails to decode, the reason is logged
+    #[error("corrupt node at {offset}, {size} bytes")]
+    Corrupt { offset: u64, size: u32 },
+    /// Bytes that do not decode as the format says, e.g. a truncated node
+    #[error("malformed data: {0}")]
+    Malformed(String),
+    #[error("{what} of {len} bytes is too large")]
+    TooLarge { what: &'static str, len: usize },
+    #[error("unknown collation {0}")]
+    UnknownCollation(String),
+    #[error("not found: {0}")]
+    NotFound(String),
+    #[error("redirect loop: {0}")]
+    RedirectLoop(String),
+    #[error("too many redirects: {0}")]
+    TooManyRedirects(String),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
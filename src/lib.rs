@@ -1,6 +1,14 @@
 pub mod beluga;
+#[cfg(feature = "encryption")]
+pub mod cipher;
+pub mod collation;
 pub mod dictionary;
 pub mod error;
 pub mod lru;
+pub mod stardict;
+#[cfg(test)]
+mod test_util;
 pub mod tree;
 mod utils;
+#[cfg(feature = "zstd")]
+pub mod zstd_codec;
@@ -1,5 +1,9 @@
 use core::hash::Hash;
-use std::{collections::HashMap, ptr::NonNull};
+use std::{
+    collections::HashMap,
+    ptr::NonNull,
+    sync::atomic::{AtomicU64, Ordering},
+};
 
 pub trait SizedValue {
     fn size(&self) -> u64;
@@ -10,92 +14,112 @@ struct LruNode<K, V: Clone> {
     key: K,
     val: V,
     size: u64,
+    /// Tick of the last use, moved forward by `get` without relinking
+    used: AtomicU64,
+    /// Tick of the last use when the node was moved to the front
+    placed: u64,
     prev: Option<NonNull<LruNode<K, V>>>,
     next: Option<NonNull<LruNode<K, V>>>,
 }
 
+/// Values by key, the least recently used ones evicted once their sizes add
+/// up to more than the capacity. `get` and `put` both count as a use.
+///
+/// `get` only takes `&self`, so hits can be served under a shared lock: a hit
+/// stamps the node with a tick and the list is put back in order lazily,
+/// when `put` evicts. A node used since it was last moved to the front is
+/// then moved there again instead of being evicted.
 #[derive(Debug)]
 pub struct LruCache<K, V: SizedValue + Clone> {
     cap: u64,
     len: u64,
-    map: NonNull<HashMap<K, NonNull<LruNode<K, V>>>>,
+    clock: AtomicU64,
+    map: HashMap<K, NonNull<LruNode<K, V>>>,
     head: Option<NonNull<LruNode<K, V>>>,
     tail: Option<NonNull<LruNode<K, V>>>,
 }
 
-unsafe impl<K, V: SizedValue + Clone + Send> Send for LruCache<K, V> {}
-unsafe impl<K, V: SizedValue + Clone + Sync> Sync for LruCache<K, V> {}
+unsafe impl<K: Send, V: SizedValue + Clone + Send> Send for LruCache<K, V> {}
+unsafe impl<K: Sync, V: SizedValue + Clone + Sync> Sync for LruCache<K, V> {}
 
 impl<K: Hash + Eq + Copy, V: SizedValue + Clone> LruCache<K, V> {
+    /// Cache holding values of `cap` in total, see `SizedValue`
     pub fn new(cap: u64) -> Self {
-        let map = Box::new(HashMap::new());
-        let map_ptr = NonNull::from(Box::leak(map));
         Self {
             cap,
             len: 0,
-            map: map_ptr,
+            clock: AtomicU64::new(0),
+            map: HashMap::new(),
             head: None,
             tail: None,
         }
     }
 
     pub fn put(&mut self, key: K, val: V) -> V {
-        match unsafe { self.map.as_mut().get_mut(&key) } {
-            Some(v) => {
-                let node = unsafe { v.as_mut() };
+        let size = val.size();
+        let result = val.clone();
+        let tick = self.tick();
+        match self.map.get(&key).copied() {
+            Some(mut node_ptr) => {
+                let node = unsafe { node_ptr.as_mut() };
+                self.len = self.len - node.size + size;
                 node.val = val;
-                match node.next {
-                    Some(mut n) => {
-                        if let Some(mut p) = node.prev {
-                            unsafe { p.as_mut().next = Some(n) };
-                            unsafe { n.as_mut().prev = Some(p) };
-                            node.prev = None;
-                            node.next = self.head;
-                            self.head = Some(*v);
-                        }
-                    }
-                    None => {
-                        if let Some(mut p) = node.prev {
-                            unsafe { p.as_mut().next = None };
-                            node.next = self.head;
-                            self.head = Some(*v);
-                            self.tail = Some(p);
-                        }
-                    }
-                }
+                node.size = size;
+                *node.used.get_mut() = tick;
+                node.placed = tick;
+                self.detach(node_ptr);
+                self.push_front(node_ptr);
             }
             None => {
-                let size = val.size();
                 let node = Box::new(LruNode {
                     key,
                     val,
                     size,
+                    used: AtomicU64::new(tick),
+                    placed: tick,
                     prev: None,
-                    next: self.head,
+                    next: None,
                 });
-                let mut node_ptr = NonNull::from(Box::leak(node));
-                match self.head {
-                    Some(mut h) => {
-                        unsafe { h.as_mut().prev = Some(node_ptr) };
-                        unsafe { node_ptr.as_mut().next = Some(h) };
-                    }
-                    None => {
-                        self.tail = Some(node_ptr);
-                    }
-                }
-                self.head = Some(node_ptr);
-                unsafe { self.map.as_mut().insert(key, node_ptr) };
+                let node_ptr = NonNull::from(Box::leak(node));
+                self.push_front(node_ptr);
+                self.map.insert(key, node_ptr);
+                self.len += size;
             }
         }
         self.shrink();
-        unsafe { self.head.unwrap().as_ref().val.clone() }
+        result
     }
 
+    /// Value of `key`, which becomes the most recently used
     pub fn get(&self, key: &K) -> Option<V> {
-        match unsafe { self.map.as_ref().get(key) } {
-            Some(v) => Some(unsafe { v.as_ref().val.clone() }),
-            None => None,
+        let node = unsafe { self.map.get(key)?.as_ref() };
+        node.used.store(self.tick(), Ordering::Relaxed);
+        Some(node.val.clone())
+    }
+
+    /// Value of `key`, leaving the order of use alone
+    pub fn peek(&self, key: &K) -> Option<V> {
+        self.map.get(key).map(|v| unsafe { v.as_ref().val.clone() })
+    }
+
+    /// Total size of the values kept
+    pub fn size(&self) -> u64 {
+        self.len
+    }
+
+    /// Keys from the most to the least recently used, e.g. to snapshot the
+    /// working set and load it again after a restart
+    pub fn keys_in_lru_order(&self) -> Vec<K> {
+        let mut keys = Vec::new();
+        let mut node = self.head;
+        while let Some(n) = node {
+            let n = unsafe { n.as_ref() };
+            keys.push((n.used.load(Ordering::Relaxed), n.key));
+            node = n.next;
         }
+        // hits since the last `put` are not reflected in the list yet
+        keys.sort_by_key(|k| std::cmp::Reverse(k.0));
+        keys.into_iter().map(|(_, k)| k).collect()
     }
 
     pub fn resize(&mut self, size: u64) {
@@ -103,17 +127,135 @@ impl<K: Hash + Eq + Copy, V: SizedValue + Clone> LruCache<K, V> {
         self.shrink();
     }
 
+    /// Unlink `node_ptr` from the list, leaving it in the map
+    fn detach(&mut self, mut node_ptr: NonNull<LruNode<K, V>>) {
+        let node = unsafe { node_ptr.as_mut() };
+        match node.prev {
+            Some(mut p) => unsafe { p.as_mut().next = node.next },
+            None => self.head = node.next,
+        }
+        match node.next {
+            Some(mut n) => unsafe { n.as_mut().prev = node.prev },
+            None => self.tail = node.prev,
+        }
+        node.prev = None;
+        node.next = None;
+    }
+
+    fn push_front(&mut self, mut node_ptr: NonNull<LruNode<K, V>>) {
+        unsafe { node_ptr.as_mut().next = self.head };
+        match self.head {
+            Some(mut h) => unsafe { h.as_mut().prev = Some(node_ptr) },
+            None => self.tail = Some(node_ptr),
+        }
+        self.head = Some(node_ptr);
+    }
+
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
     fn shrink(&mut self) {
         while self.len > self.cap {
-            if let Some(mut tail) = self.tail {
-                let tail_node = unsafe { tail.as_mut() };
-                let key = tail_node.key;
-                unsafe { self.map.as_mut().remove(&key) };
-                self.tail = tail_node.prev;
-                self.len -= tail_node.size;
-            } else {
+            let Some(mut tail) = self.tail else {
                 break;
+            };
+            // a node used since it was moved to the front gets another round
+            let node = unsafe { tail.as_mut() };
+            let used = *node.used.get_mut();
+            if used > node.placed {
+                node.placed = used;
+                self.detach(tail);
+                self.push_front(tail);
+                continue;
             }
+            self.detach(tail);
+            let node = unsafe { Box::from_raw(tail.as_ptr()) };
+            self.map.remove(&node.key);
+            self.len -= node.size;
+        }
+    }
+}
+
+impl<K, V: SizedValue + Clone> Drop for LruCache<K, V> {
+    fn drop(&mut self) {
+        let mut node = self.head.take();
+        while let Some(n) = node {
+            let n = unsafe { Box::from_raw(n.as_ptr()) };
+            node = n.next;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone)]
+    struct Bytes(u64);
+
+    impl SizedValue for Bytes {
+        fn size(&self) -> u64 {
+            self.0
+        }
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = LruCache::new(30);
+        for key in 0..3 {
+            cache.put(key, Bytes(10));
         }
+        assert!(cache.get(&0).is_some());
+        cache.put(3, Bytes(10));
+        assert_eq!(cache.keys_in_lru_order(), vec![3, 0, 2]);
+        assert!(cache.peek(&1).is_none());
+        assert_eq!(cache.size(), 30);
+    }
+
+    #[test]
+    fn shared_hits_count_as_use() {
+        let mut cache = LruCache::new(30);
+        for key in 0..3 {
+            cache.put(key, Bytes(10));
+        }
+        // hits through a shared reference, as under a read lock
+        let shared = &cache;
+        std::thread::scope(|s| {
+            s.spawn(|| assert!(shared.get(&1).is_some()));
+            s.spawn(|| assert!(shared.get(&0).is_some()));
+        });
+        assert_eq!(cache.keys_in_lru_order()[2], 2);
+        cache.put(3, Bytes(10));
+        cache.put(4, Bytes(10));
+        assert!(cache.peek(&2).is_none());
+        assert_eq!(cache.keys_in_lru_order()[0], 4);
+        assert_eq!(cache.size(), 30);
+    }
+
+    #[test]
+    fn stays_within_capacity() {
+        let mut cache = LruCache::new(100);
+        for key in 0..1000u32 {
+            cache.put(key, Bytes(7));
+            assert!(cache.size() <= 100);
+        }
+        assert_eq!(cache.keys_in_lru_order().len(), 14);
+        // replacing a value accounts for the new size
+        cache.put(999, Bytes(50));
+        assert!(cache.size() <= 100);
+        assert_eq!(cache.keys_in_lru_order()[0], 999);
+        cache.resize(10);
+        assert_eq!(cache.keys_in_lru_order(), Vec::<u32>::new());
+        assert_eq!(cache.size(), 0);
+    }
+
+    #[test]
+    fn keeps_value_larger_than_capacity_out() {
+        let mut cache = LruCache::new(10);
+        cache.put(1, Bytes(5));
+        assert_eq!(cache.put(2, Bytes(20)).0, 20);
+        assert!(cache.peek(&2).is_none());
+        assert_eq!(cache.size(), 0);
     }
 }
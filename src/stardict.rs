@@ -0,0 +1,252 @@
+use crate::beluga::{BelFileType, Beluga};
+use crate::dictionary::REDIRECT;
+use crate::error::{Error, Result};
+use crate::utils::u32_to_u8v;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use tracing::{info, warn};
+
+/// Writes a `Beluga` entry dictionary as a StarDict `.ifo`/`.idx`/`.dict`
+/// triple, e.g. for GoldenDict. Definitions are marked as HTML. Redirects
+/// become synonyms of the entry they lead to in a `.syn` file.
+pub struct StarDictExporter<'a> {
+    beluga: &'a Beluga,
+    bookname: String,
+}
+
+impl<'a> StarDictExporter<'a> {
+    pub fn new(beluga: &'a Beluga, bookname: &str) -> Self {
+        Self {
+            beluga,
+            bookname: bookname.to_string(),
+        }
+    }
+
+    /// Write `<base>.ifo`, `<base>.idx` and `<base>.dict`, and `<base>.syn`
+    /// if there are redirects. Definitions are streamed to the `.dict`, only
+    /// the index is kept in memory to be sorted. Redirects are followed to
+    /// the entry at the end of the chain; those ending nowhere are skipped.
+    pub fn export(&self, base: &str) -> Result<()> {
+        if matches!(self.beluga.file_type, BelFileType::Resource) {
+            return Err(Error::Msg("not a entry file".to_string()));
+        }
+        let mut dict = BufWriter::new(File::create(format!("{}.dict", base))?);
+        let mut index: Vec<(String, u32, u32)> = Vec::new();
+        let mut redirects: Vec<(String, String)> = Vec::new();
+        let mut offset: u64 = 0;
+        let mut failure: Option<Error> = None;
+        self.beluga.traverse_entry(&mut |k, v| {
+            if failure.is_some() {
+                return;
+            }
+            match std::str::from_utf8(&v.0) {
+                Ok(t) => {
+                    if let Some(target) = t.trim_start().strip_prefix(REDIRECT) {
+                        redirects.push((k.0.clone(), target.trim().to_string()));
+                        return;
+                    }
+                }
+                Err(_) => {
+                    warn!("Skip entry with non UTF-8 definition. {}", k);
+                    return;
+                }
+            }
+            if offset + v.0.len() as u64 > u32::MAX as u64 {
+                failure = Some(Error::Msg(
+                    "dictionary is too large for StarDict".to_string(),
+                ));
+                return;
+            }
+            if let Err(e) = dict.write_all(&v.0) {
+                failure = Some(e.into());
+                return;
+            }
+            index.push((k.0.clone(), offset as u32, v.0.len() as u32));
+            offset += v.0.len() as u64;
+        });
+        if let Some(e) = failure {
+            return Err(e);
+        }
+        dict.flush()?;
+        index.sort_by(|a, b| stardict_cmp(&a.0, &b.0));
+        let mut idx = BufWriter::new(File::create(format!("{}.idx", base))?);
+        let mut idx_size: u64 = 0;
+        for (name, offset, size) in &index {
+            idx.write_all(name.as_bytes())?;
+            idx.write_all(&[0])?;
+            idx.write_all(&u32_to_u8v(*offset))?;
+            idx.write_all(&u32_to_u8v(*size))?;
+            idx_size += name.len() as u64 + 9;
+        }
+        idx.flush()?;
+        let synonyms = resolve_synonyms(&index, &redirects);
+        if !synonyms.is_empty() {
+            let mut syn = BufWriter::new(File::create(format!("{}.syn", base))?);
+            for (name, word_index) in &synonyms {
+                syn.write_all(name.as_bytes())?;
+                syn.write_all(&[0])?;
+                syn.write_all(&u32_to_u8v(*word_index))?;
+            }
+            syn.flush()?;
+        }
+        let metadata = &self.beluga.metadata;
+        let mut ifo = String::from("StarDict's dict ifo file\nversion=2.4.2\n");
+        ifo += &format!("bookname={}\n", one_line(&self.bookname));
+        ifo += &format!("wordcount={}\n", index.len());
+        ifo += &format!("idxfilesize={}\n", idx_size);
+        if !synonyms.is_empty() {
+            ifo += &format!("synwordcount={}\n", synonyms.len());
+        }
+        ifo += "sametypesequence=h\n";
+        for (field, value) in [
+            ("author", &metadata.author),
+            ("email", &metadata.email),
+            ("date", &metadata.create_time),
+            ("description", &metadata.comment),
+        ] {
+            if !value.is_empty() {
+                ifo += &format!("{}={}\n", field, one_line(value));
+            }
+        }
+        std::fs::write(format!("{}.ifo", base), ifo)?;
+        info!("Exported {} entries to StarDict", index.len());
+        Ok(())
+    }
+}
+
+/// `redirects` as (name, index in the sorted `index` of the entry at the end
+/// of the chain), sorted for the `.syn`
+fn resolve_synonyms(
+    index: &[(String, u32, u32)],
+    redirects: &[(String, String)],
+) -> Vec<(String, u32)> {
+    let mut positions: HashMap<&str, u32> = HashMap::new();
+    for (i, (name, _, _)) in index.iter().enumerate().rev() {
+        positions.insert(name.as_str(), i as u32);
+    }
+    let targets: HashMap<&str, &str> = redirects
+        .iter()
+        .map(|(name, target)| (name.as_str(), target.as_str()))
+        .collect();
+    let mut synonyms: Vec<(String, u32)> = Vec::new();
+    for (name, target) in redirects {
+        let mut target = target.as_str();
+        let mut hops = 0;
+        let position = loop {
+            if let Some(p) = positions.get(target) {
+                break Some(*p);
+            }
+            match targets.get(target) {
+                Some(t) if hops < redirects.len() => {
+                    target = t;
+                    hops += 1;
+                }
+                _ => break None,
+            }
+        };
+        match position {
+            Some(p) => synonyms.push((name.clone(), p)),
+            None => warn!("Skip redirect to a missing entry. {}", name),
+        }
+    }
+    synonyms.sort_by(|a, b| stardict_cmp(&a.0, &b.0));
+    synonyms
+}
+
+/// StarDict sorts the `.idx` by ASCII case-insensitive comparison, falling
+/// back to plain byte order for names equal ignoring case.
+fn stardict_cmp(a: &str, b: &str) -> Ordering {
+    let folded = a
+        .bytes()
+        .map(|c| c.to_ascii_lowercase())
+        .cmp(b.bytes().map(|c| c.to_ascii_lowercase()));
+    folded.then_with(|| a.cmp(b))
+}
+
+fn one_line(s: &str) -> String {
+    s.replace(['\r', '\n'], " ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::beluga::Metadata;
+    use crate::test_util::*;
+    use crate::utils::u8v_to_u32;
+
+    /// (name, u32, u32) records of a `.idx`, or (name, u32) of a `.syn`
+    fn parse_records(bytes: &[u8], numbers: usize) -> Vec<(String, Vec<u32>)> {
+        let mut records = vec![];
+        let mut rest = bytes;
+        while !rest.is_empty() {
+            let end = rest.iter().position(|b| *b == 0).unwrap();
+            let name = String::from_utf8(rest[..end].to_vec()).unwrap();
+            rest = &rest[end + 1..];
+            let values = (0..numbers)
+                .map(|i| u8v_to_u32(&rest[i * 4..i * 4 + 4]).unwrap())
+                .collect();
+            rest = &rest[numbers * 4..];
+            records.push((name, values));
+        }
+        records
+    }
+
+    #[test]
+    fn export_writes_idx_into_dict() {
+        let dir = temp_dir("export_writes_idx_into_dict");
+        let mut po = Beluga::new(Metadata::new(), BelFileType::Entry).unwrap();
+        let definitions = [
+            ("b", "Beta"),
+            ("A", "alpha"),
+            ("a", "little a"),
+            ("Zeta", "last"),
+        ];
+        for (name, definition) in definitions {
+            po.input_entry(name.to_string(), definition.as_bytes().to_vec());
+        }
+        po.input_entry("alias".to_string(), format!("{}b", REDIRECT).into_bytes());
+        po.input_entry(
+            "alias2".to_string(),
+            format!(" {}alias", REDIRECT).into_bytes(),
+        );
+        po.input_entry(
+            "dangling".to_string(),
+            format!("{}nowhere", REDIRECT).into_bytes(),
+        );
+        po.input_resource("a.png".to_string(), vec![0xff, 0xd8]);
+        let base = file_path(&dir, "export");
+        StarDictExporter::new(&po, "Test").export(&base).unwrap();
+
+        let dict = std::fs::read(format!("{}.dict", base)).unwrap();
+        let idx = std::fs::read(format!("{}.idx", base)).unwrap();
+        let records = parse_records(&idx, 2);
+        let names: Vec<&str> = records.iter().map(|r| r.0.as_str()).collect();
+        assert_eq!(names, vec!["A", "a", "b", "Zeta"]);
+        for (name, values) in &records {
+            let (offset, size) = (values[0] as usize, values[1] as usize);
+            let definition = definitions.iter().find(|d| d.0 == name).unwrap().1;
+            assert_eq!(&dict[offset..offset + size], definition.as_bytes());
+        }
+        assert_eq!(
+            records.iter().map(|r| r.1[1] as usize).sum::<usize>(),
+            dict.len()
+        );
+
+        let syn = std::fs::read(format!("{}.syn", base)).unwrap();
+        let synonyms = parse_records(&syn, 1);
+        assert_eq!(
+            synonyms,
+            vec![
+                ("alias".to_string(), vec![2]),
+                ("alias2".to_string(), vec![2])
+            ]
+        );
+
+        let ifo = std::fs::read_to_string(format!("{}.ifo", base)).unwrap();
+        assert!(ifo.contains("wordcount=4\n"));
+        assert!(ifo.contains(&format!("idxfilesize={}\n", idx.len())));
+        assert!(ifo.contains("synwordcount=2\n"));
+    }
+}
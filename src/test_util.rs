@@ -0,0 +1,53 @@
+use crate::beluga::{BelFileType, Beluga, Metadata};
+use crate::dictionary::{Dictionary, NodeCache};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+/// Empty directory for the files of the test `name`
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir =
+        std::env::temp_dir().join(format!("beluga-core-test-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// `<dir>/<file>` as a string
+pub fn file_path(dir: &Path, file: &str) -> String {
+    dir.join(file).to_string_lossy().into_owned()
+}
+
+/// Entry file of `entries` saved as `<dir>/<file>` with `metadata`
+pub async fn save_entries(
+    dir: &Path,
+    file: &str,
+    metadata: Metadata,
+    entries: &[(&str, &str)],
+) -> String {
+    let path = file_path(dir, file);
+    let mut po = Beluga::new(metadata, BelFileType::Entry).unwrap();
+    for (name, value) in entries {
+        po.input_entry(name.to_string(), value.as_bytes().to_vec());
+    }
+    po.save(&path).await.unwrap();
+    path
+}
+
+/// Metadata with node size limits of `size` bytes, to get many nodes out of
+/// few entries
+pub fn small_nodes(size: usize) -> Metadata {
+    let mut metadata = Metadata::new();
+    metadata.leaf_node_size = size;
+    metadata.index_node_size = size;
+    metadata
+}
+
+pub fn new_cache() -> Arc<RwLock<NodeCache>> {
+    Arc::new(RwLock::new(NodeCache::new(64 * 1024 * 1024)))
+}
+
+pub async fn open(path: &str) -> (Dictionary, Arc<RwLock<NodeCache>>) {
+    let (dict, _) = Dictionary::new(path, 0).await.unwrap();
+    (dict, new_cache())
+}
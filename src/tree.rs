@@ -1,23 +1,412 @@
-use crate::error::Result;
-use crate::utils::{u32_to_u8v, u64_to_u8v, Scanner};
-use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
-use std::io::Seek;
+use crate::collation::Collator;
+use crate::error::{Error, Result};
+use crate::utils::{u32_to_u8v, u64_to_u8v, u8v_to_u32, u8v_to_u64, write_varint, Scanner};
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression, Crc, CrcReader};
 use std::{
     cmp::Ordering,
+    collections::HashMap,
     fmt::{Debug, Display},
     io::{Read, SeekFrom, Write},
+    ops::{Bound, RangeBounds},
     ptr::NonNull,
+    sync::Arc,
 };
-use tokio::{
-    fs::File,
-    io::{AsyncReadExt, AsyncSeekExt},
-};
-use tracing::{debug, info, instrument};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use tracing::{debug, info, instrument, warn};
+
+/// Set in the first byte of a node whose lengths and child pointers are
+/// LEB128 varints instead of fixed-width integers
+const NODE_FLAG_VARINT: u8 = 0b10;
+/// Bytes of a sibling link after an encoded node, the offset u64 and size
+/// u32 of the sibling of a leaf, zero otherwise. With `next_links` the link
+/// to the right sibling comes first, then with `prev_links` the one to the
+/// left sibling. They are outside compression so they can be filled in once
+/// the sibling is written, or patched in place when it moves.
+const LINK_SIZE: usize = 12;
+
+#[cfg(test)]
+thread_local! {
+    /// Lines printed by `Node::debug_print`, to check loading stays silent
+    static PRINTED_LINES: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+fn write_size(buf: &mut Vec<u8>, v: u32, varint: bool) {
+    if varint {
+        write_varint(v as u64, buf);
+    } else {
+        buf.append(&mut u32_to_u8v(v));
+    }
+}
+
+/// `len` as a u32 length prefix, failing rather than truncating a length
+/// the format cannot hold
+fn checked_size(len: usize, what: &'static str) -> Result<u32> {
+    u32::try_from(len).map_err(|_| Error::TooLarge { what, len })
+}
+
+/// Point the leaf at `leaf` (offset, size) to its left sibling `prev`,
+/// leaving `file` where it was
+async fn link_prev<W: AsyncWrite + AsyncSeek + Unpin>(
+    file: &mut W,
+    leaf: (u64, u32),
+    prev: (u64, u32),
+) -> Result<()> {
+    write_link(file, leaf.0 + leaf.1 as u64 - LINK_SIZE as u64, prev).await
+}
+
+/// Point the leaf at `leaf` (offset, size) to its right sibling `next`,
+/// leaving `file` where it was. Only for a codec with `next_links`.
+async fn link_next<W: AsyncWrite + AsyncSeek + Unpin>(
+    file: &mut W,
+    leaf: (u64, u32),
+    next: (u64, u32),
+    codec: &NodeCodec,
+) -> Result<()> {
+    let at = leaf.0 + leaf.1 as u64 - codec.links_size() as u64;
+    write_link(file, at, next).await
+}
+
+async fn write_link<W: AsyncWrite + AsyncSeek + Unpin>(
+    file: &mut W,
+    at: u64,
+    link: (u64, u32),
+) -> Result<()> {
+    let end = file.stream_position().await?;
+    file.seek(SeekFrom::Start(at)).await?;
+    file.write_all(&u64_to_u8v(link.0)).await?;
+    file.write_all(&u32_to_u8v(link.1)).await?;
+    file.seek(SeekFrom::Start(end)).await?;
+    Ok(())
+}
+
+fn write_offset(buf: &mut Vec<u8>, v: u64, varint: bool) {
+    if varint {
+        write_varint(v, buf);
+    } else {
+        buf.append(&mut u64_to_u8v(v));
+    }
+}
+
+fn read_size(scanner: &mut Scanner, varint: bool) -> Result<u32> {
+    if varint {
+        u32::try_from(scanner.try_read_varint()?)
+            .map_err(|_| Error::Malformed("size is out of range".to_string()))
+    } else {
+        scanner.try_read_u32()
+    }
+}
+
+fn read_offset(scanner: &mut Scanner, varint: bool) -> Result<u64> {
+    if varint {
+        scanner.try_read_varint()
+    } else {
+        scanner.try_read_u64()
+    }
+}
+
+/// `read_size` from a stream
+fn read_size_from<R: Read>(r: &mut R, varint: bool) -> Result<u32> {
+    if varint {
+        u32::try_from(read_varint_from(r)?)
+            .map_err(|_| Error::Malformed("size is out of range".to_string()))
+    } else {
+        let mut b = [0u8; 4];
+        r.read_exact(&mut b)?;
+        Ok(u32::from_be_bytes(b))
+    }
+}
+
+/// `read_offset` from a stream
+fn read_offset_from<R: Read>(r: &mut R, varint: bool) -> Result<u64> {
+    if varint {
+        read_varint_from(r)
+    } else {
+        let mut b = [0u8; 8];
+        r.read_exact(&mut b)?;
+        Ok(u64::from_be_bytes(b))
+    }
+}
+
+fn read_varint_from<R: Read>(r: &mut R) -> Result<u64> {
+    let mut v: u64 = 0;
+    for shift in (0..64).step_by(7) {
+        let mut b = [0u8; 1];
+        r.read_exact(&mut b)?;
+        v |= ((b[0] & 0x7f) as u64) << shift;
+        if b[0] & 0x80 == 0 {
+            return Ok(v);
+        }
+    }
+    Err(Error::Malformed("varint is too long".to_string()))
+}
+
+/// What `scan_leaf` found
+pub enum LeafScan<T> {
+    /// The node is an index node, nothing was read past its flags
+    Index,
+    /// No record has the key. A record with an equal sort key may still be
+    /// in the next leaf when the leaf ends with such keys, so the last key
+    /// and the link to the right sibling found inside the node are given.
+    Missing {
+        last_key: Option<Vec<u8>>,
+        next: (u64, u32),
+    },
+    Found(T),
+}
+
+/// Look for the record whose key bytes equal `key` in the leaf read from
+/// `reader`, e.g. a `NodeCodec::decoder`, one record at a time. Its value is
+/// handed to `on_value` along with its length as a reader limited to it;
+/// other values are skipped as they are read, so neither the node nor a
+/// value is held in memory whole. The node is read to its end to check the
+/// CRC32 if `checksum`.
+pub fn scan_leaf<R, T, F>(reader: R, key: &[u8], checksum: bool, on_value: F) -> Result<LeafScan<T>>
+where
+    R: Read,
+    F: FnOnce(&mut dyn Read, u64) -> Result<T>,
+{
+    let mut reader = CrcReader::new(reader);
+    let mut flags = [0u8; 1];
+    reader.read_exact(&mut flags)?;
+    if flags[0] & 1 != 0 {
+        return Ok(LeafScan::Index);
+    }
+    let varint = flags[0] & NODE_FLAG_VARINT != 0;
+    let rec_num = read_size_from(&mut reader, varint)?;
+    let mut on_value = Some(on_value);
+    let mut found: Option<T> = None;
+    let mut key_buf: Vec<u8> = vec![];
+    for _ in 0..rec_num {
+        let key_len = read_size_from(&mut reader, varint)? as usize;
+        key_buf.resize(key_len, 0);
+        reader.read_exact(&mut key_buf)?;
+        let value_len = read_size_from(&mut reader, varint)? as u64;
+        let mut value = (&mut reader).take(value_len);
+        if key_buf == key {
+            if let Some(f) = on_value.take() {
+                found = Some(f(&mut value, value_len)?);
+            }
+        }
+        std::io::copy(&mut value, &mut std::io::sink())?;
+    }
+    let next = (
+        read_offset_from(&mut reader, varint)?,
+        read_size_from(&mut reader, varint)?,
+    );
+    if checksum {
+        let crc = reader.crc().sum();
+        if read_size_from(reader.get_mut(), false)? != crc {
+            return Err(Error::Malformed("node checksum mismatch".to_string()));
+        }
+    }
+    Ok(match found {
+        Some(v) => LeafScan::Found(v),
+        None => LeafScan::Missing {
+            last_key: Some(key_buf).filter(|_| rec_num > 0),
+            next,
+        },
+    })
+}
 
 fn compress(buf: &[u8]) -> Vec<u8> {
     let mut e = DeflateEncoder::new(Vec::new(), Compression::default());
     e.write_all(buf).expect("DeflateEncoder: Fail to write");
-    return e.finish().expect("DeflateEncoder: Fail to finish");
+    e.finish().expect("DeflateEncoder: Fail to finish")
+}
+
+fn crc32(buf: &[u8]) -> u32 {
+    let mut crc = Crc::new();
+    crc.update(buf);
+    crc.sum()
+}
+
+/// Inflate a node read from disk. When `checksum` is set, the last 4 bytes of
+/// the inflated data are the CRC32 of the rest and are verified and stripped.
+pub fn decompress(buf: &[u8], checksum: bool) -> Result<Vec<u8>> {
+    let mut decode = DeflateDecoder::new(buf);
+    let mut data: Vec<u8> = vec![];
+    decode.read_to_end(&mut data)?;
+    strip_checksum(data, checksum)
+}
+
+fn strip_checksum(mut data: Vec<u8>, checksum: bool) -> Result<Vec<u8>> {
+    if checksum {
+        if data.len() < 4 {
+            return Err(Error::Malformed(
+                "node is too short for checksum".to_string(),
+            ));
+        }
+        let crc = u8v_to_u32(&data.split_off(data.len() - 4))?;
+        if crc != crc32(&data) {
+            return Err(Error::Malformed("node checksum mismatch".to_string()));
+        }
+    }
+    Ok(data)
+}
+
+/// How nodes are encoded on disk, shared by writing and reading a file.
+#[derive(Debug, Clone, Default)]
+pub struct NodeCodec {
+    /// Nodes end with the CRC32 of their uncompressed bytes
+    pub checksum: bool,
+    /// Write lengths and child pointers as varints; nodes flag it themselves
+    /// so reading does not need it
+    pub varint: bool,
+    /// Encrypt compressed nodes
+    #[cfg(feature = "encryption")]
+    pub cipher: Option<crate::cipher::NodeCipher>,
+    /// Compress nodes with zstd instead of Deflate
+    #[cfg(feature = "zstd")]
+    pub zstd: Option<crate::zstd_codec::ZstdCodec>,
+    /// Nodes are followed by a link from a leaf to its left sibling
+    pub prev_links: bool,
+    /// Nodes are followed by a link from a leaf to its right sibling, which
+    /// the node itself then leaves zero
+    pub next_links: bool,
+}
+
+/// Sibling links read after a node, see `NodeCodec::split_links`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NodeLinks {
+    /// Right sibling of a leaf, `None` without `next_links` as it is then
+    /// inside the node
+    pub next: Option<(u64, u32)>,
+    /// Left sibling of a leaf, (0, 0) without `prev_links`
+    pub prev: (u64, u32),
+}
+
+impl NodeCodec {
+    pub fn new(checksum: bool, varint: bool) -> Self {
+        Self {
+            checksum,
+            varint,
+            #[cfg(feature = "encryption")]
+            cipher: None,
+            #[cfg(feature = "zstd")]
+            zstd: None,
+            prev_links: false,
+            next_links: false,
+        }
+    }
+
+    /// Bytes of sibling links after each node
+    fn links_size(&self) -> usize {
+        LINK_SIZE * (self.next_links as usize + self.prev_links as usize)
+    }
+
+    /// Bytes to write at `offset` for a serialized node
+    #[cfg_attr(not(feature = "encryption"), allow(unused_variables))]
+    fn encode(&self, mut node_buf: Vec<u8>, offset: u64) -> Vec<u8> {
+        if self.checksum {
+            let mut crc_buf = u32_to_u8v(crc32(&node_buf));
+            node_buf.append(&mut crc_buf);
+        }
+        #[allow(unused_mut)]
+        let mut buf = self.compress(&node_buf);
+        #[cfg(feature = "encryption")]
+        if let Some(cipher) = &self.cipher {
+            buf = cipher.encrypt(offset, &buf);
+        }
+        buf.resize(buf.len() + self.links_size(), 0);
+        buf
+    }
+
+    /// `encode` for a serialized leaf still missing the link to its right
+    /// sibling `next`, which goes inside the node or, with `next_links`,
+    /// after it
+    fn encode_leaf(&self, mut node_buf: Vec<u8>, offset: u64, next: (u64, u32)) -> Vec<u8> {
+        let inside = if self.next_links { (0, 0) } else { next };
+        write_offset(&mut node_buf, inside.0, self.varint);
+        write_size(&mut node_buf, inside.1, self.varint);
+        let mut buf = self.encode(node_buf, offset);
+        if self.next_links {
+            let at = buf.len() - self.links_size();
+            buf[at..at + 8].copy_from_slice(&u64_to_u8v(next.0));
+            buf[at + 8..at + LINK_SIZE].copy_from_slice(&u32_to_u8v(next.1));
+        }
+        buf
+    }
+
+    /// Split the bytes read for a node into the encoded node and the
+    /// sibling links after it
+    pub fn split_links<'a>(&self, buf: &'a [u8]) -> Result<(&'a [u8], NodeLinks)> {
+        let at = buf
+            .len()
+            .checked_sub(self.links_size())
+            .ok_or_else(|| Error::Malformed("node is too short for its links".to_string()))?;
+        let mut links = NodeLinks::default();
+        let mut link = buf[at..].chunks(LINK_SIZE);
+        let mut read = || -> Result<(u64, u32)> {
+            let l = link.next().unwrap();
+            Ok((u8v_to_u64(&l[..8])?, u8v_to_u32(&l[8..])?))
+        };
+        if self.next_links {
+            links.next = Some(read()?);
+        }
+        if self.prev_links {
+            links.prev = read()?;
+        }
+        Ok((&buf[..at], links))
+    }
+
+    /// `decode` as a reader decompressing while it is read, for nodes too
+    /// large to inflate at once. A CRC32 is left at the end, unchecked.
+    #[cfg_attr(not(feature = "encryption"), allow(unused_variables))]
+    pub fn decoder<'a>(&'a self, buf: &'a [u8], offset: u64) -> Result<Box<dyn Read + 'a>> {
+        let (buf, _) = self.split_links(buf)?;
+        #[allow(unused_mut)]
+        let mut input: Box<dyn Read + 'a> = Box::new(buf);
+        #[cfg(feature = "encryption")]
+        if let Some(cipher) = &self.cipher {
+            input = Box::new(std::io::Cursor::new(cipher.decrypt(offset, buf)?));
+        }
+        #[cfg(feature = "zstd")]
+        if let Some(zstd) = &self.zstd {
+            return zstd.decoder(input);
+        }
+        Ok(Box::new(DeflateDecoder::new(input)))
+    }
+
+    /// Serialized node from the bytes read at `offset`
+    #[cfg_attr(not(feature = "encryption"), allow(unused_variables))]
+    pub fn decode(&self, buf: &[u8], offset: u64) -> Result<Vec<u8>> {
+        let (buf, _) = self.split_links(buf)?;
+        #[cfg(feature = "encryption")]
+        if let Some(cipher) = &self.cipher {
+            return self.decompress(&cipher.decrypt(offset, buf)?);
+        }
+        self.decompress(buf)
+    }
+
+    fn compress(&self, buf: &[u8]) -> Vec<u8> {
+        #[cfg(feature = "zstd")]
+        if let Some(zstd) = &self.zstd {
+            return zstd.compress(buf);
+        }
+        compress(buf)
+    }
+
+    fn decompress(&self, buf: &[u8]) -> Result<Vec<u8>> {
+        #[cfg(feature = "zstd")]
+        if let Some(zstd) = &self.zstd {
+            return strip_checksum(zstd.decompress(buf)?, self.checksum);
+        }
+        decompress(buf, self.checksum)
+    }
+}
+
+/// Progress callback for `Tree::write_to` printing a percentage line to stdout
+pub fn print_progress(done: u64, total: u64) {
+    print!(
+        "\r{} / {} {:.2}%",
+        done,
+        total,
+        (done as f64) / (total as f64) * 100.0
+    );
+    if done == total {
+        println!();
+    }
+    std::io::stdout().flush().unwrap();
 }
 
 fn create_non_null<T>(value: Box<T>) -> NonNull<T> {
@@ -31,7 +420,8 @@ pub trait Serializable {
 }
 
 pub trait Smoothable {
-    fn smooth(&self) -> Self;
+    /// Form the tree orders keys by under `collator`
+    fn smooth(&self, collator: &dyn Collator) -> Self;
 }
 
 #[derive(Debug, Clone)]
@@ -60,22 +450,51 @@ impl<K: Serializable, V: Serializable> Record<K, V> {
         size
     }
 
-    fn bytes(&self) -> Vec<u8> {
+    fn bytes(&self, varint: bool) -> Result<Vec<u8>> {
         let mut data: Vec<u8> = vec![];
-        let mut size_bytes = u32_to_u8v(self.key.size() as u32);
-        data.append(&mut size_bytes);
+        write_size(&mut data, checked_size(self.key.size(), "key")?, varint);
         let mut key_bytes = self.key.bytes();
         data.append(&mut key_bytes);
         if let Some(v) = &self.value {
-            size_bytes = u32_to_u8v(v.size() as u32);
-            data.append(&mut size_bytes);
+            write_size(&mut data, checked_size(v.size(), "value")?, varint);
             let mut value_bytes = v.bytes();
             data.append(&mut value_bytes);
         }
-        data
+        Ok(data)
+    }
+}
+
+/// Serialized node from its records and the pointers to its children. A leaf
+/// has no children here, its sibling pointer is appended once known.
+fn node_bytes<K: Serializable, V: Serializable>(
+    is_leaf: bool,
+    records: &[Record<K, V>],
+    children: &[(u64, u32)],
+    varint: bool,
+) -> Result<Vec<u8>> {
+    let mut buf: Vec<u8> = Vec::new();
+    let record_num = checked_size(records.len(), "node")?;
+    let mut flags = if is_leaf { 0u8 } else { 1u8 };
+    if varint {
+        flags |= NODE_FLAG_VARINT;
+    }
+    buf.push(flags);
+    write_size(&mut buf, record_num, varint);
+    for rec in records {
+        let mut rec_buf = rec.bytes(varint)?;
+        buf.append(&mut rec_buf);
+    }
+    for (offset, size) in children {
+        write_offset(&mut buf, *offset, varint);
+        write_size(&mut buf, *size, varint);
     }
+    Ok(buf)
 }
 
+/// Offset and compressed size of the child nodes, or of the next sibling for
+/// a leaf
+pub type Children = Vec<(u64, u32)>;
+
 #[derive(Debug, Clone)]
 pub struct Node<K, V> {
     pub is_leaf: bool,
@@ -84,10 +503,22 @@ pub struct Node<K, V> {
     parent: Option<NonNull<Node<K, V>>>,
     offset: u64,
     zip_size: u32,
+    /// Next sibling of a leaf read from disk
+    next: (u64, u32),
+    /// Previous sibling of a leaf read from a file linking leaves both ways
+    prev: (u64, u32),
+    /// Offset the node was last read from or written to, kept once `offset`
+    /// is cleared by a change; 0 for a new node
+    stored_at: u64,
+    /// False for a node of a lazy tree not read yet, which only knows where
+    /// it is, see `Tree::lazy`
+    loaded: bool,
 }
 
-unsafe impl<K, V> Send for Node<K, V> {}
-unsafe impl<K, V> Sync for Node<K, V> {}
+// Safety: a node owns the records and, through its tree, the children it
+// points to
+unsafe impl<K: Send, V: Send> Send for Node<K, V> {}
+unsafe impl<K: Sync, V: Sync> Sync for Node<K, V> {}
 
 impl<
         K: PartialOrd + Ord + Serializable + Smoothable + Display + Debug + Clone,
@@ -102,6 +533,10 @@ impl<
             parent: None,
             offset: 0,
             zip_size: 0,
+            next: (0, 0),
+            prev: (0, 0),
+            stored_at: 0,
+            loaded: true,
         }
     }
 
@@ -110,19 +545,21 @@ impl<
         NonNull::from(Box::leak(node))
     }
 
-    pub fn from_bytes(data: &[u8]) -> (Box<Self>, Vec<(u64, u32)>) {
-        let mut scanner = Scanner::new(&data);
-        let is_leaf = scanner.read_u8() == 0;
-        let rec_num = scanner.read_u32();
+    pub fn from_bytes(data: &[u8]) -> Result<(Box<Self>, Children)> {
+        let mut scanner = Scanner::new(data);
+        let flags = scanner.try_read_u8()?;
+        let is_leaf = flags & 1 == 0;
+        let varint = flags & NODE_FLAG_VARINT != 0;
+        let rec_num = read_size(&mut scanner, varint)?;
         let mut records: Vec<Record<K, V>> = vec![];
         for _ in 0..rec_num {
-            let key_len = scanner.read_u32() as usize;
-            let b = scanner.read(key_len);
-            let key = K::from_bytes(&b);
+            let key_len = read_size(&mut scanner, varint)? as usize;
+            let b = scanner.try_read(key_len)?;
+            let key = K::from_bytes(b);
             let rec = if is_leaf {
-                let value_length = scanner.read_u32() as usize;
-                let b = scanner.read(value_length);
-                let value = V::from_bytes(&b);
+                let value_length = read_size(&mut scanner, varint)? as usize;
+                let b = scanner.try_read(value_length)?;
+                let value = V::from_bytes(b);
                 Record::with_value(key, value)
             } else {
                 Record::new(key)
@@ -134,17 +571,17 @@ impl<
         let mut children: Vec<(u64, u32)> = vec![];
         let cc = if is_leaf { 1 } else { rec_num + 1 };
         for _ in 0..cc {
-            let offset = scanner.read_u64();
-            let size = scanner.read_u32();
+            let offset = read_offset(&mut scanner, varint)?;
+            let size = read_size(&mut scanner, varint)?;
             children.push((offset, size));
         }
-        (node, children)
+        Ok((node, children))
     }
 
     #[instrument(skip(self))]
-    pub fn index_of(&self, key: &K) -> (usize, Ordering) {
+    pub fn index_of(&self, key: &K, collator: &dyn Collator) -> (usize, Ordering) {
         info!("{} NODE", if self.is_leaf { "LEAF" } else { "INDEX" });
-        let key = key.smooth();
+        let key = key.smooth(collator);
         let mut hi = self.records.len() - 1;
         let mut li = 0;
         let ret: (usize, Ordering);
@@ -156,7 +593,7 @@ impl<
             );
             let mi_key = &self.records[mi].key.clone();
             let cr = if self.is_leaf {
-                key.cmp(&mi_key.smooth())
+                key.cmp(&mi_key.smooth(collator))
             } else {
                 key.cmp(mi_key)
             };
@@ -172,7 +609,7 @@ impl<
                     }
                     let hi_key = &self.records[hi].key;
                     let cr = if self.is_leaf {
-                        key.cmp(&hi_key.smooth())
+                        key.cmp(&hi_key.smooth(collator))
                     } else {
                         key.cmp(hi_key)
                     };
@@ -195,6 +632,30 @@ impl<
         ret
     }
 
+    /// Position just after every record equal to `key`, so records with equal
+    /// keys keep their insertion order.
+    fn upper_index_of(&self, key: &K, collator: &dyn Collator) -> usize {
+        let (mut idx, cr) = self.index_of(key, collator);
+        if cr.is_lt() {
+            return idx;
+        }
+        let key = key.smooth(collator);
+        idx += 1;
+        while idx < self.records.len() {
+            let rec_key = &self.records[idx].key;
+            let cr = if self.is_leaf {
+                key.cmp(&rec_key.smooth(collator))
+            } else {
+                key.cmp(rec_key)
+            };
+            if !cr.is_eq() {
+                break;
+            }
+            idx += 1;
+        }
+        idx
+    }
+
     fn size(&self) -> usize {
         let mut size: usize = 1/* is leaf */ + 4/* record number */;
         for i in 0..self.records.len() {
@@ -205,7 +666,7 @@ impl<
         } else {
             size += (8/* child offset */ + 4/* child size */) * self.children.len();
         }
-        return size;
+        size
     }
 
     fn child_index_of(&self, child: NonNull<Node<K, V>>) -> Option<usize> {
@@ -217,34 +678,24 @@ impl<
         None
     }
 
-    fn bytes(&self) -> Vec<u8> {
-        let mut buf: Vec<u8> = Vec::new();
-        if self.records.len() + 1 > 2u64.pow(32) as usize {
-            panic!("Node is too large");
-        }
-        if self.is_leaf {
-            buf.append(&mut vec![0u8]);
-        } else {
-            buf.append(&mut vec![1u8]);
-        }
-        let mut wc = u32_to_u8v(self.records.len() as u32);
-        buf.append(&mut wc);
-        for i in 0..self.records.len() {
-            let mut rec_buf = self.records[i].bytes();
-            buf.append(&mut rec_buf);
-        }
-        for i in 0..self.children.len() {
-            let child = unsafe { self.children[i].as_ref() };
-            let child_offset = child.offset;
-            let mut co_buf = u64_to_u8v(child_offset);
-            buf.append(&mut co_buf);
-            let mut child_size_buf = u32_to_u8v(child.zip_size);
-            buf.append(&mut child_size_buf);
-        }
-        buf
+    /// Serialized node. With `varint` the lengths and child pointers are
+    /// LEB128 varints, flagged in the first byte. Fails if a key or value
+    /// is too long for its length prefix.
+    fn bytes(&self, varint: bool) -> Result<Vec<u8>> {
+        let children: Children = self
+            .children
+            .iter()
+            .map(|c| {
+                let child = unsafe { c.as_ref() };
+                (child.offset, child.zip_size)
+            })
+            .collect();
+        node_bytes(self.is_leaf, &self.records, &children, varint)
     }
 
-    fn print(&self, level: usize) {
+    fn debug_print(&self, level: usize) {
+        #[cfg(test)]
+        PRINTED_LINES.with(|n| n.set(n.get() + 1));
         let flag = if self.is_leaf { "LEAF" } else { "INDEX" };
         println!(
             "{:5} ({:10}, {:5}) {:5} {:5} [{} ~ {}]",
@@ -257,34 +708,67 @@ impl<
             self.records.last().unwrap().key
         );
         for child in &self.children {
-            unsafe { child.as_ref().print(level + 1) };
+            unsafe { child.as_ref().debug_print(level + 1) };
         }
     }
 }
 
+/// Read and decode the node of `size` bytes at `offset` of `file` with its
+/// child pointers, the sibling links of a leaf set on the node.
+async fn read_node<
+    K: PartialOrd + Ord + Serializable + Smoothable + Clone + Display + Debug,
+    V: Serializable,
+    R: AsyncRead + AsyncSeek + Unpin,
+>(
+    file: &mut R,
+    offset: u64,
+    size: u32,
+    codec: &NodeCodec,
+) -> Result<(Box<Node<K, V>>, Children)> {
+    file.seek(SeekFrom::Start(offset)).await?;
+    let mut bytes = vec![0; size as usize];
+    file.read_exact(&mut bytes).await?;
+    let parsed = codec.split_links(&bytes).and_then(|(_, links)| {
+        let data = codec.decode(&bytes, offset)?;
+        Node::<K, V>::from_bytes(&data).map(|(node, children)| (node, children, links))
+    });
+    let (mut node, mut children, links) = match parsed {
+        Ok(r) => r,
+        Err(e) => {
+            warn!("Invalid node at {}. {}", offset, e);
+            return Err(Error::Corrupt { offset, size });
+        }
+    };
+    node.offset = offset;
+    node.zip_size = size;
+    node.stored_at = offset;
+    if node.is_leaf {
+        if let Some(next) = links.next {
+            children[0] = next;
+        }
+        node.next = children[0];
+        node.prev = links.prev;
+    }
+    Ok((node, children))
+}
+
 async fn parse_node<
     K: PartialOrd + Ord + Serializable + Smoothable + Clone + Display + Debug,
     V: Serializable,
+    R: AsyncRead + AsyncSeek + Unpin,
 >(
-    file: &mut File,
+    file: &mut R,
     offset: u64,
     size: u32,
     leaves: &mut Vec<NonNull<Node<K, V>>>,
-    level: usize,
+    codec: &NodeCodec,
+    on_node: &mut dyn FnMut(),
 ) -> Result<(NonNull<Node<K, V>>, usize)> {
     if size == 0 {
         return Ok((Node::new_ptr(true), 1));
     }
-    file.seek(SeekFrom::Start(offset)).await?;
-    let mut bytes = vec![0; size as usize];
-    file.read_exact(&mut bytes).await?;
-    let mut decode = DeflateDecoder::new(&bytes[..]);
-    let mut data: Vec<u8> = vec![];
-    decode.read_to_end(&mut data).unwrap();
-    let (mut node, children) = Node::<K, V>::from_bytes(&data);
-    node.offset = offset;
-    node.zip_size = size;
-    node.print(level);
+    let (node, children) = read_node(file, offset, size, codec).await?;
+    on_node();
     let is_leaf = node.is_leaf;
     let mut node_ptr = create_non_null(node);
     let mut node_num = 1;
@@ -296,7 +780,7 @@ async fn parse_node<
                 break;
             }
             let (mut child_node_ptr, child_node_num) =
-                Box::pin(parse_node(file, child.0, child.1, leaves, level + 1)).await?;
+                Box::pin(parse_node(file, child.0, child.1, leaves, codec, on_node)).await?;
             let child_node = unsafe { child_node_ptr.as_mut() };
             unsafe { node_ptr.as_mut().children.push(child_node_ptr) };
             child_node.parent = Some(node_ptr);
@@ -306,23 +790,49 @@ async fn parse_node<
     Ok((node_ptr, node_num))
 }
 
+#[derive(Debug, Clone)]
+pub struct TreeStats {
+    pub height: usize,
+    pub node_num: usize,
+    pub leaf_num: usize,
+    pub avg_leaf_records: f64,
+    /// Average compressed size of a node, 0 until the tree is written or loaded
+    pub avg_zip_size: f64,
+}
+
+/// What `Tree::check_file` found
+#[derive(Debug, Clone, Default)]
+pub struct FileCheck {
+    pub record_num: usize,
+    pub node_num: usize,
+    /// The first problem found, `None` if the tree is sound
+    pub anomaly: Option<String>,
+}
+
 pub struct Tree<K, V> {
     root: NonNull<Node<K, V>>,
     leaves: NonNull<Vec<NonNull<Node<K, V>>>>,
     node_num: usize,
     index_size_limit: usize,
     leaf_size_limit: usize,
+    collator: Arc<dyn Collator>,
 }
 
-unsafe impl<K, V> Send for Tree<K, V> {}
-unsafe impl<K, V> Sync for Tree<K, V> {}
+// Safety: the tree owns all of its nodes, so it can move to another thread
+// with them. It is not `Sync`: readers walk raw node pointers that `&mut`
+// methods change, share a `FrozenTree` instead.
+unsafe impl<K: Send, V: Send> Send for Tree<K, V> {}
 
 impl<
         K: PartialOrd + Ord + Serializable + Smoothable + Clone + Display + Debug,
         V: Serializable,
     > Tree<K, V>
 {
-    pub fn new(index_size_limit: usize, leaf_size_limit: usize) -> Self {
+    pub fn new(
+        index_size_limit: usize,
+        leaf_size_limit: usize,
+        collator: Arc<dyn Collator>,
+    ) -> Self {
         let root = Node::new_ptr(true);
         let leaves: Box<Vec<NonNull<Node<K, V>>>> = Box::new(vec![root]);
         let leaves_ptr = NonNull::from(Box::leak(leaves));
@@ -332,18 +842,62 @@ impl<
             node_num: 1,
             index_size_limit,
             leaf_size_limit,
+            collator,
         }
     }
 
-    pub async fn from_file(
-        file: &mut File,
+    pub async fn from_file<R: AsyncRead + AsyncSeek + Unpin>(
+        file: &mut R,
+        root_offset: u64,
+        root_size: u32,
+        index_size_limit: usize,
+        leaf_size_limit: usize,
+        codec: &NodeCodec,
+        collator: Arc<dyn Collator>,
+    ) -> Result<Self> {
+        Self::from_file_with_progress(
+            file,
+            root_offset,
+            root_size,
+            index_size_limit,
+            leaf_size_limit,
+            codec,
+            collator,
+            None,
+        )
+        .await
+    }
+
+    /// `from_file` calling `progress` with the number of nodes parsed so far
+    /// after each node, e.g. to keep a spinner going while a large tree loads
+    #[allow(clippy::too_many_arguments)]
+    pub async fn from_file_with_progress<R: AsyncRead + AsyncSeek + Unpin>(
+        file: &mut R,
         root_offset: u64,
         root_size: u32,
         index_size_limit: usize,
         leaf_size_limit: usize,
+        codec: &NodeCodec,
+        collator: Arc<dyn Collator>,
+        mut progress: Option<&mut dyn FnMut(u64)>,
     ) -> Result<Self> {
         let mut leaves = Box::<Vec<NonNull<Node<K, V>>>>::new(vec![]);
-        let (root, node_num) = parse_node(file, root_offset, root_size, &mut leaves, 1).await?;
+        let mut parsed = 0;
+        let mut on_node = || {
+            parsed += 1;
+            if let Some(progress) = progress.as_mut() {
+                progress(parsed);
+            }
+        };
+        let (root, node_num) = parse_node(
+            file,
+            root_offset,
+            root_size,
+            &mut leaves,
+            codec,
+            &mut on_node,
+        )
+        .await?;
         let leaves_ptr = NonNull::from(Box::leak(leaves));
         Ok(Self {
             root,
@@ -351,17 +905,169 @@ impl<
             node_num,
             index_size_limit,
             leaf_size_limit,
+            collator,
         })
     }
 
-    #[allow(dead_code)]
-    pub fn print(&self) {
-        unsafe { self.root.as_ref().print(1) };
+    /// Tree of a file with its root at `root`, no node read yet. Before a
+    /// key is inserted `load_path` reads the nodes on its way, so only the
+    /// paths to new keys are held in memory. Meant to `insert` and then
+    /// `write_changes_to` with a codec that has `next_links`.
+    pub fn lazy(
+        root: (u64, u32),
+        index_size_limit: usize,
+        leaf_size_limit: usize,
+        collator: Arc<dyn Collator>,
+    ) -> Self {
+        let mut tree = Self::new(index_size_limit, leaf_size_limit, collator);
+        if root.1 != 0 {
+            let root_node = unsafe { tree.root.as_mut() };
+            root_node.offset = root.0;
+            root_node.zip_size = root.1;
+            root_node.loaded = false;
+            unsafe { tree.leaves.as_mut().clear() };
+            tree.node_num = 0;
+        }
+        tree
+    }
+
+    /// Read the nodes of a `lazy` tree that inserting `key` passes through,
+    /// and the leaf left of where it lands, whose link to its right sibling
+    /// changes if that one is written again.
+    pub async fn load_path<R: AsyncRead + AsyncSeek + Unpin>(
+        &mut self,
+        file: &mut R,
+        key: &K,
+        codec: &NodeCodec,
+    ) -> Result<()> {
+        while let Err(node_ptr) = self.near_leaves(key) {
+            self.load(file, node_ptr, codec).await?;
+        }
+        Ok(())
+    }
+
+    /// Whether a record with exactly `key` is in the leaf it would be
+    /// inserted into or the leaf left of it, both read by `load_path`.
+    /// Records with keys equal to a separator may sit in either.
+    pub fn holds_near(&self, key: &K) -> bool {
+        let Ok((leaf, left)) = self.near_leaves(key) else {
+            return false;
+        };
+        [Some(leaf), left]
+            .into_iter()
+            .flatten()
+            .any(|l| unsafe { l.as_ref() }.records.iter().any(|r| r.key == *key))
+    }
+
+    /// Give the record with exactly `key` found by `holds_near` the value
+    /// `value`, which is handed back if there is none
+    pub fn replace_near(&mut self, key: &K, value: V) -> Option<V> {
+        let Ok((leaf, left)) = self.near_leaves(key) else {
+            return Some(value);
+        };
+        for mut node_ptr in [Some(leaf), left].into_iter().flatten() {
+            let node = unsafe { node_ptr.as_mut() };
+            if let Some(record) = node.records.iter_mut().find(|r| r.key == *key) {
+                record.value = Some(value);
+                // a zero offset marks the node as changed since it was read
+                node.offset = 0;
+                return None;
+            }
+        }
+        Some(value)
+    }
+
+    /// Leaf `key` would be inserted into and the leaf left of it, or the
+    /// first node on the way to them that is not read yet
+    #[allow(clippy::type_complexity)]
+    fn near_leaves(
+        &self,
+        key: &K,
+    ) -> std::result::Result<(NonNull<Node<K, V>>, Option<NonNull<Node<K, V>>>), NonNull<Node<K, V>>>
+    {
+        let mut leaf_ptr = self.root;
+        loop {
+            let node = unsafe { leaf_ptr.as_ref() };
+            if !node.loaded {
+                return Err(leaf_ptr);
+            }
+            if node.is_leaf {
+                break;
+            }
+            leaf_ptr = node.children[node.upper_index_of(key, &*self.collator)];
+        }
+        // up to the nearest node with a child further left, then down the
+        // rightmost children of that child
+        let mut child_ptr = leaf_ptr;
+        while let Some(parent_ptr) = unsafe { child_ptr.as_ref().parent } {
+            let parent = unsafe { parent_ptr.as_ref() };
+            let idx = parent.child_index_of(child_ptr).unwrap();
+            if idx == 0 {
+                child_ptr = parent_ptr;
+                continue;
+            }
+            let mut node_ptr = parent.children[idx - 1];
+            loop {
+                let node = unsafe { node_ptr.as_ref() };
+                if !node.loaded {
+                    return Err(node_ptr);
+                }
+                if node.is_leaf {
+                    return Ok((leaf_ptr, Some(node_ptr)));
+                }
+                node_ptr = *node.children.last().unwrap();
+            }
+        }
+        Ok((leaf_ptr, None))
+    }
+
+    /// Read the node of a `lazy` tree at `node_ptr` in place, its children
+    /// becoming nodes not read yet
+    async fn load<R: AsyncRead + AsyncSeek + Unpin>(
+        &mut self,
+        file: &mut R,
+        mut node_ptr: NonNull<Node<K, V>>,
+        codec: &NodeCodec,
+    ) -> Result<()> {
+        let node = unsafe { node_ptr.as_mut() };
+        let (read, children) =
+            read_node::<K, V, R>(file, node.offset, node.zip_size, codec).await?;
+        let read = *read;
+        node.is_leaf = read.is_leaf;
+        node.records = read.records;
+        node.next = read.next;
+        node.prev = read.prev;
+        node.stored_at = read.stored_at;
+        node.loaded = true;
+        if node.is_leaf {
+            unsafe { self.leaves.as_mut().push(node_ptr) };
+        } else {
+            for (offset, size) in children.into_iter().take_while(|c| c.1 != 0) {
+                let mut child_ptr = Node::new_ptr(false);
+                let child = unsafe { child_ptr.as_mut() };
+                child.offset = offset;
+                child.zip_size = size;
+                child.loaded = false;
+                child.parent = Some(node_ptr);
+                node.children.push(child_ptr);
+            }
+        }
+        self.node_num += 1;
+        Ok(())
+    }
+
+    /// Print the structure of the tree to stdout, one line per node. Nothing
+    /// else in the crate writes to stdout unless asked, e.g. `print_progress`.
+    pub fn debug_print(&self) {
+        if unsafe { self.root.as_ref().records.is_empty() } {
+            return;
+        }
+        unsafe { self.root.as_ref().debug_print(1) };
     }
 
     pub fn insert(&mut self, key: K, value: V) {
         let root = unsafe { self.root.as_mut() };
-        if root.records.len() == 0 {
+        if root.records.is_empty() {
             root.records.push(Record::with_value(key, value));
             return;
         }
@@ -371,19 +1077,16 @@ impl<
             if node.is_leaf {
                 break;
             }
-            let (idx, cr) = node.index_of(&key);
-            let child_idx = if cr.is_le() { idx } else { idx + 1 };
-            node_ptr = node.children[child_idx];
+            node_ptr = node.children[node.upper_index_of(&key, &*self.collator)];
         }
         {
             let leaf_node = unsafe { node_ptr.as_mut() };
-            let (idx, cr) = leaf_node.index_of(&key);
-            let rec = Record::with_value(key, value);
-            if cr.is_le() {
-                leaf_node.records.insert(idx, rec);
-            } else {
-                leaf_node.records.insert(idx + 1, rec);
-            }
+            let idx = leaf_node.upper_index_of(&key, &*self.collator);
+            leaf_node
+                .records
+                .insert(idx, Record::with_value(key, value));
+            // a zero offset marks the node as changed since it was read
+            leaf_node.offset = 0;
         }
         let mut div_node_ptr = node_ptr;
         loop {
@@ -396,13 +1099,18 @@ impl<
                     let mut new_node_ptr = Node::new_ptr(true);
                     let new_node = unsafe { new_node_ptr.as_mut() };
                     new_node.records = right_records;
+                    // the left half now links to the right half, whose
+                    // position is only known once it is written
+                    new_node.next = div_node.next;
+                    div_node.next = (0, 0);
                     unsafe { self.leaves.as_mut().push(new_node_ptr) };
-                    let new_parent_key = div_node.records[div_idx - 1].key.smooth();
+                    let new_parent_key = div_node.records[div_idx - 1].key.smooth(&*self.collator);
                     if let Some(mut parent) = div_node.parent {
                         let pnode = unsafe { parent.as_mut() };
                         new_node.parent = div_node.parent;
                         let child_idx = pnode.child_index_of(div_node_ptr).unwrap();
                         pnode.records.insert(child_idx, Record::new(new_parent_key));
+                        pnode.offset = 0;
                         pnode.children.insert(child_idx + 1, new_node_ptr);
                         div_node_ptr = parent;
                     } else {
@@ -422,23 +1130,30 @@ impl<
                 }
             } else if div_node.size() > self.index_size_limit && div_node.records.len() >= 3 {
                 self.node_num += 1;
+                // With n records and n + 1 children, the left node keeps
+                // div_idx - 1 records and div_idx children, record
+                // div_idx - 1 moves up and the right node takes the other
+                // n - div_idx records and n + 1 - div_idx children.
                 let div_idx = div_node.records.len() / 2 + 1;
                 let right_records = div_node.records.drain(div_idx..).collect();
                 let precord = div_node.records.pop().unwrap();
                 let mut new_node_ptr = Node::new_ptr(false);
                 let new_node = unsafe { new_node_ptr.as_mut() };
                 new_node.records = right_records;
-                let mut right_children: Vec<NonNull<Node<K, V>>> =
+                let right_children: Vec<NonNull<Node<K, V>>> =
                     div_node.children.drain(div_idx..).collect();
-                for i in 0..right_children.len() {
-                    unsafe { right_children[i].as_mut().parent = Some(new_node_ptr) };
-                    new_node.children.push(right_children[i]);
+                for mut child in right_children {
+                    unsafe { child.as_mut().parent = Some(new_node_ptr) };
+                    new_node.children.push(child);
                 }
+                debug_assert_eq!(div_node.children.len(), div_node.records.len() + 1);
+                debug_assert_eq!(new_node.children.len(), new_node.records.len() + 1);
                 if let Some(mut parent) = div_node.parent {
                     let pnode = unsafe { parent.as_mut() };
-                    new_node.parent = div_node.parent.clone();
+                    new_node.parent = div_node.parent;
                     let child_idx = pnode.child_index_of(div_node_ptr).unwrap();
                     pnode.records.insert(child_idx, precord);
+                    pnode.offset = 0;
                     pnode.children.insert(child_idx + 1, new_node_ptr);
                     div_node_ptr = parent;
                 } else {
@@ -459,10 +1174,26 @@ impl<
         }
     }
 
-    // DO NOT use tokio::fs::File, it cannot write correctly
-    pub fn write_to(&self, file: &mut std::fs::File) -> (u64, u32) {
+    // Writes from the current position of `file`, any `AsyncWrite + AsyncSeek`
+    // such as a `tokio::fs::File` or an in-memory `Cursor<Vec<u8>>`, and
+    // flushes it at the end
+    // Nodes are encoded by `codec`, e.g. followed by a CRC32 or encrypted
+    // `progress` is called with (saved nodes, total nodes) after each node
+    pub async fn write_to<W: AsyncWrite + AsyncSeek + Unpin>(
+        &self,
+        file: &mut W,
+        codec: &NodeCodec,
+        mut progress: Option<&mut dyn FnMut(u64, u64)>,
+    ) -> Result<(u64, u32)> {
         if unsafe { self.root.as_ref().records.len() } == 0 {
-            return (0, 0);
+            return Ok((0, 0));
+        }
+        // a zero offset marks a node as not yet written, clear any earlier save
+        let mut stack = vec![self.root];
+        while let Some(mut node_ptr) = stack.pop() {
+            let node = unsafe { node_ptr.as_mut() };
+            node.offset = 0;
+            stack.extend_from_slice(&node.children);
         }
         let mut node_ptr = self.root;
         loop {
@@ -473,7 +1204,7 @@ impl<
             let last_index = tmp_node.children.len() - 1;
             node_ptr = tmp_node.children[last_index];
         }
-        let mut offset = file.stream_position().expect("fail to get stream position");
+        let mut offset = file.stream_position().await?;
         let mut leaf_offset: u64 = 0;
         let mut leaf_size: u32 = 0;
         let mut saved_num = 0;
@@ -494,30 +1225,29 @@ impl<
                     continue;
                 }
             }
-            let mut node_buf = tmp_node.bytes();
-            if tmp_node.is_leaf {
-                let mut leaf_offset_buf = u64_to_u8v(leaf_offset);
-                node_buf.append(&mut leaf_offset_buf);
-                let mut leaf_size_buf = u32_to_u8v(leaf_size);
-                node_buf.append(&mut leaf_size_buf);
-            }
+            let node_buf = tmp_node.bytes(codec.varint)?;
+            let buf = if tmp_node.is_leaf {
+                tmp_node.next = (leaf_offset, leaf_size);
+                codec.encode_leaf(node_buf, offset, tmp_node.next)
+            } else {
+                codec.encode(node_buf, offset)
+            };
             tmp_node.offset = offset;
-            let buf = compress(&node_buf);
+            tmp_node.stored_at = offset;
             tmp_node.zip_size = buf.len() as u32;
-            offset += buf.len() as u64;
+            file.write_all(&buf).await?;
             if tmp_node.is_leaf {
+                if codec.prev_links && leaf_size != 0 {
+                    link_prev(file, (leaf_offset, leaf_size), (offset, buf.len() as u32)).await?;
+                }
                 leaf_offset = tmp_node.offset;
                 leaf_size = buf.len() as u32;
             }
-            file.write_all(&buf).expect("fail to write node");
+            offset += buf.len() as u64;
             saved_num += 1;
-            print!(
-                "\r{} / {} {:.2}%",
-                saved_num,
-                self.node_num,
-                (saved_num as f64) / (self.node_num as f64) * 100.0
-            );
-            std::io::stdout().flush().unwrap();
+            if let Some(cb) = progress.as_mut() {
+                cb(saved_num, self.node_num as u64);
+            }
             match tmp_node.parent {
                 Some(p) => {
                     node_ptr = p;
@@ -525,9 +1255,156 @@ impl<
                 None => break,
             }
         }
-        print!("\n");
+        file.flush().await?;
         let root_node = unsafe { self.root.as_ref() };
-        (root_node.offset, root_node.zip_size)
+        Ok((root_node.offset, root_node.zip_size))
+    }
+
+    /// Append the nodes changed since the tree was read, by `from_file` or
+    /// `lazy`, to `file`, whose end must be past every node read, leaving
+    /// the others where they are, and return the new root. Every index node
+    /// above a written node is written again. A leaf links to its right
+    /// sibling: with `next_links` that link is patched in place when the
+    /// sibling moves, otherwise every leaf left of a changed one is written
+    /// again too, which needs the whole tree read.
+    pub async fn write_changes_to<W: AsyncWrite + AsyncSeek + Unpin>(
+        &self,
+        file: &mut W,
+        codec: &NodeCodec,
+    ) -> Result<(u64, u32)> {
+        if unsafe { self.root.as_ref().records.is_empty() } {
+            return Ok((0, 0));
+        }
+        let mut leaves: Vec<NonNull<Node<K, V>>> = vec![];
+        let mut stack = vec![self.root];
+        while let Some(node_ptr) = stack.pop() {
+            let node = unsafe { node_ptr.as_ref() };
+            if !node.loaded {
+                continue;
+            }
+            if node.is_leaf {
+                leaves.push(node_ptr);
+            } else {
+                stack.extend(node.children.iter().rev());
+            }
+        }
+        let start = file.stream_position().await?;
+        let mut offset = start;
+        if codec.next_links {
+            Self::write_changed_leaves(&leaves, file, codec, &mut offset).await?;
+        } else {
+            let varint = codec.varint;
+            let last_changed = leaves
+                .iter()
+                .rposition(|l| unsafe { l.as_ref().offset } == 0);
+            if let Some(last) = last_changed {
+                for i in (0..=last).rev() {
+                    let next = match leaves.get(i + 1) {
+                        Some(n) => unsafe { (n.as_ref().offset, n.as_ref().zip_size) },
+                        None => (0, 0),
+                    };
+                    let leaf = unsafe { leaves[i].as_mut() };
+                    let mut node_buf = leaf.bytes(varint)?;
+                    write_offset(&mut node_buf, next.0, varint);
+                    write_size(&mut node_buf, next.1, varint);
+                    let buf = codec.encode(node_buf, offset);
+                    file.write_all(&buf).await?;
+                    leaf.offset = offset;
+                    leaf.stored_at = offset;
+                    leaf.zip_size = buf.len() as u32;
+                    leaf.next = next;
+                    if codec.prev_links && next.1 != 0 {
+                        let right = unsafe { leaves[i + 1].as_mut() };
+                        right.prev = (leaf.offset, leaf.zip_size);
+                        link_prev(file, next, right.prev).await?;
+                    }
+                    offset += buf.len() as u64;
+                }
+            }
+        }
+        Self::write_changed_index(self.root, file, codec, start, &mut offset).await?;
+        file.flush().await?;
+        let root = unsafe { self.root.as_ref() };
+        Ok((root.offset, root.zip_size))
+    }
+
+    /// Part of `write_changes_to` for a codec with `next_links`. `leaves`
+    /// are the leaves in memory in key order; changed ones are written right
+    /// to left, so each knows where its right sibling is, and the links of
+    /// the others are patched if their sibling moved. A leaf read from the
+    /// file links to where its sibling was stored, one split in memory to
+    /// the next leaf in memory.
+    async fn write_changed_leaves<W: AsyncWrite + AsyncSeek + Unpin>(
+        leaves: &[NonNull<Node<K, V>>],
+        file: &mut W,
+        codec: &NodeCodec,
+        offset: &mut u64,
+    ) -> Result<()> {
+        let stored: HashMap<u64, NonNull<Node<K, V>>> = leaves
+            .iter()
+            .map(|l| (unsafe { l.as_ref().stored_at }, *l))
+            .filter(|(at, _)| *at != 0)
+            .collect();
+        let position =
+            |n: &NonNull<Node<K, V>>| unsafe { (n.as_ref().offset, n.as_ref().zip_size) };
+        for i in (0..leaves.len()).rev() {
+            let leaf = unsafe { &mut *leaves[i].as_ptr() };
+            let next = if leaf.next.1 != 0 {
+                stored.get(&leaf.next.0).map_or(leaf.next, position)
+            } else {
+                leaves.get(i + 1).map_or((0, 0), position)
+            };
+            let moved = if leaf.offset == 0 {
+                let buf = codec.encode_leaf(leaf.bytes(codec.varint)?, *offset, next);
+                file.write_all(&buf).await?;
+                leaf.offset = *offset;
+                leaf.stored_at = *offset;
+                leaf.zip_size = buf.len() as u32;
+                *offset += buf.len() as u64;
+                true
+            } else if next != leaf.next {
+                link_next(file, (leaf.offset, leaf.zip_size), next, codec).await?;
+                true
+            } else {
+                false
+            };
+            leaf.next = next;
+            if moved && codec.prev_links && next.1 != 0 {
+                link_prev(file, next, (leaf.offset, leaf.zip_size)).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Post-order part of `write_changes_to` for index nodes. Nodes at or
+    /// past `start` were written in this pass.
+    async fn write_changed_index<W: AsyncWrite + AsyncSeek + Unpin>(
+        mut node_ptr: NonNull<Node<K, V>>,
+        file: &mut W,
+        codec: &NodeCodec,
+        start: u64,
+        offset: &mut u64,
+    ) -> Result<()> {
+        let node = unsafe { node_ptr.as_mut() };
+        if node.is_leaf {
+            return Ok(());
+        }
+        for child in node.children.clone() {
+            Box::pin(Self::write_changed_index(child, file, codec, start, offset)).await?;
+        }
+        let changed = node.offset == 0
+            || node
+                .children
+                .iter()
+                .any(|c| unsafe { c.as_ref().offset } >= start);
+        if changed {
+            let buf = codec.encode(node.bytes(codec.varint)?, *offset);
+            file.write_all(&buf).await?;
+            node.offset = *offset;
+            node.zip_size = buf.len() as u32;
+            *offset += buf.len() as u64;
+        }
+        Ok(())
     }
 
     #[allow(dead_code)]
@@ -539,14 +1416,1118 @@ impl<
         size
     }
 
+    /// Check the tree at `root` of `file`, which is `file_size` bytes long,
+    /// reading one node at a time: nodes lie within the file and decode, keys
+    /// are sorted within and across leaves and the sibling links chain every
+    /// leaf exactly once, in order. Unlike `from_file`, a broken node is
+    /// reported as the anomaly rather than failing.
+    pub async fn check_file<R: AsyncRead + AsyncSeek + Unpin>(
+        file: &mut R,
+        file_size: u64,
+        root: (u64, u32),
+        codec: &NodeCodec,
+        collator: &dyn Collator,
+    ) -> Result<FileCheck> {
+        let mut check = FileCheck::default();
+        let mut stack = vec![root];
+        let mut prev_leaf: Option<(u64, u32, (u64, u32))> = None;
+        let mut prev_key: Option<K> = None;
+        if root.1 == 0 {
+            return Ok(check);
+        }
+        while let Some((offset, size)) = stack.pop() {
+            if offset + size as u64 > file_size {
+                check.anomaly = Some(format!(
+                    "node ({}, {}) is outside of the file",
+                    offset, size
+                ));
+                return Ok(check);
+            }
+            let node = match read_node::<K, V, R>(file, offset, size, codec).await {
+                Ok((node, children)) => {
+                    if !node.is_leaf {
+                        stack.extend(children.iter().rev().filter(|c| c.1 != 0));
+                    }
+                    node
+                }
+                Err(Error::Corrupt { .. }) => {
+                    check.anomaly = Some(format!("node ({}, {}) is corrupt", offset, size));
+                    return Ok(check);
+                }
+                Err(e) => return Err(e),
+            };
+            check.node_num += 1;
+            if !node.is_leaf {
+                continue;
+            }
+            check.record_num += node.records.len();
+            for rec in &node.records {
+                let key = rec.key.smooth(collator);
+                if prev_key.as_ref().is_some_and(|pk| *pk > key) {
+                    check.anomaly = Some(format!(
+                        "key {} in node {} is out of order",
+                        rec.key, offset
+                    ));
+                    return Ok(check);
+                }
+                prev_key = Some(key);
+            }
+            let expected_prev = match prev_leaf {
+                Some((o, s, next)) => {
+                    if next != (offset, size) {
+                        check.anomaly = Some(format!(
+                            "leaf {} links to {:?} instead of {:?}",
+                            o,
+                            next,
+                            (offset, size)
+                        ));
+                        return Ok(check);
+                    }
+                    (o, s)
+                }
+                None => (0, 0),
+            };
+            if codec.prev_links && node.prev != expected_prev {
+                check.anomaly = Some(format!(
+                    "leaf {} links back to {:?} instead of {:?}",
+                    offset, node.prev, expected_prev
+                ));
+                return Ok(check);
+            }
+            prev_leaf = Some((offset, size, node.next));
+        }
+        if let Some((offset, _, next)) = prev_leaf {
+            if next != (0, 0) {
+                check.anomaly = Some(format!("last leaf {} links to {:?}", offset, next));
+            }
+        }
+        Ok(check)
+    }
+
+    /// Check the in-memory structure: keys are sorted within each node and
+    /// lie between the separators around their node, index nodes have one
+    /// child more than records, parent pointers match and all leaves are at
+    /// the same depth. Meant as a debug check after bulk inserts.
+    pub fn validate(&self) -> Result<()> {
+        let fail = |msg: String| Err(Error::Msg(format!("invalid tree: {}", msg)));
+        let mut leaf_depth: Option<usize> = None;
+        let mut stack = vec![(self.root, None::<K>, None::<K>, 0usize)];
+        while let Some((node_ptr, lo, hi, depth)) = stack.pop() {
+            let node = unsafe { node_ptr.as_ref() };
+            let keys: Vec<K> = node
+                .records
+                .iter()
+                .map(|r| r.key.smooth(&*self.collator))
+                .collect();
+            for (i, key) in keys.iter().enumerate() {
+                if i > 0 && keys[i - 1] > *key {
+                    return fail(format!("key {} is out of order", node.records[i].key));
+                }
+                if lo.as_ref().is_some_and(|l| key < l) || hi.as_ref().is_some_and(|h| key > h) {
+                    return fail(format!(
+                        "key {} is outside of its parent separators",
+                        node.records[i].key
+                    ));
+                }
+            }
+            if node.is_leaf {
+                if !node.children.is_empty() {
+                    return fail("leaf has children".to_string());
+                }
+                match leaf_depth {
+                    Some(d) if d != depth => {
+                        return fail(format!("leaves at depth {} and {}", d, depth));
+                    }
+                    _ => leaf_depth = Some(depth),
+                }
+                continue;
+            }
+            if node.children.len() != node.records.len() + 1 {
+                return fail(format!(
+                    "index node has {} records and {} children",
+                    node.records.len(),
+                    node.children.len()
+                ));
+            }
+            for (i, child_ptr) in node.children.iter().enumerate() {
+                let child = unsafe { child_ptr.as_ref() };
+                if child.parent != Some(node_ptr) {
+                    return fail(format!("child {} has a wrong parent", i));
+                }
+                let child_lo = if i == 0 {
+                    lo.clone()
+                } else {
+                    Some(keys[i - 1].clone())
+                };
+                let child_hi = if i == keys.len() {
+                    hi.clone()
+                } else {
+                    Some(keys[i].clone())
+                };
+                stack.push((*child_ptr, child_lo, child_hi, depth + 1));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn stats(&self) -> TreeStats {
+        let mut height = 1;
+        let mut node_ptr = self.root;
+        loop {
+            let node = unsafe { node_ptr.as_ref() };
+            if node.is_leaf {
+                break;
+            }
+            height += 1;
+            node_ptr = node.children[0];
+        }
+        let mut zip_size: u64 = 0;
+        let mut stack = vec![self.root];
+        while let Some(node_ptr) = stack.pop() {
+            let node = unsafe { node_ptr.as_ref() };
+            zip_size += node.zip_size as u64;
+            stack.extend_from_slice(&node.children);
+        }
+        let leaf_num = unsafe { self.leaves.as_ref().len() };
+        // a lazy tree knows no nodes until they are loaded
+        let avg = |total: f64, n: usize| if n == 0 { 0.0 } else { total / n as f64 };
+        TreeStats {
+            height,
+            node_num: self.node_num,
+            leaf_num,
+            avg_leaf_records: avg(self.record_num() as f64, leaf_num),
+            avg_zip_size: avg(zip_size as f64, self.node_num),
+        }
+    }
+
+    /// Rough number of bytes `write_to` would write with `codec`. Nodes are
+    /// written as they are, so their serialized sizes are exact; a sample of
+    /// at most 32 of them is compressed to estimate the ratio for the rest.
+    /// A node too large to save counts at its raw size.
+    pub fn estimate_size(&self, codec: &NodeCodec) -> u64 {
+        if unsafe { self.root.as_ref().records.is_empty() } {
+            return 0;
+        }
+        let step = self.node_num.div_ceil(32);
+        let (mut raw, mut sample_raw, mut sample_zip) = (0u64, 0u64, 0u64);
+        let mut stack = vec![self.root];
+        let mut i = 0;
+        while let Some(node_ptr) = stack.pop() {
+            let node = unsafe { node_ptr.as_ref() };
+            stack.extend_from_slice(&node.children);
+            let mut buf = match node.bytes(codec.varint) {
+                Ok(buf) => buf,
+                Err(_) => {
+                    raw += node.size() as u64;
+                    continue;
+                }
+            };
+            if node.is_leaf {
+                // the right sibling link, some offset and size so it does not
+                // compress better than the real one
+                let size = buf.len() as u32;
+                write_offset(&mut buf, raw, codec.varint);
+                write_size(&mut buf, size, codec.varint);
+            }
+            raw += buf.len() as u64;
+            if i % step == 0 {
+                sample_raw += buf.len() as u64;
+                sample_zip += codec.encode(buf, 0).len() as u64;
+            }
+            i += 1;
+        }
+        (raw as f64 * sample_zip as f64 / sample_raw as f64) as u64
+    }
+
+    /// Serialized bytes of up to `max` leaves spread over the tree, e.g. to
+    /// train a compression dictionary on
+    pub fn sample_leaves(&self, max: usize, varint: bool) -> Vec<Vec<u8>> {
+        let leaves = unsafe { self.leaves.as_ref() };
+        let step = leaves.len().div_ceil(max.max(1)).max(1);
+        leaves
+            .iter()
+            .step_by(step)
+            .map(|l| unsafe { l.as_ref() })
+            .filter(|l| !l.records.is_empty())
+            .filter_map(|l| l.bytes(varint).ok())
+            .collect()
+    }
+
+    /// Record with the smallest key, found by descending the leftmost
+    /// children, `None` if the tree is empty
+    pub fn first(&self) -> Option<(&K, &V)> {
+        self.edge_leaf(|n| n.children.first())
+            .records
+            .first()
+            .map(|r| (&r.key, r.value.as_ref().unwrap()))
+    }
+
+    /// Record with the largest key, see `first`
+    pub fn last(&self) -> Option<(&K, &V)> {
+        self.edge_leaf(|n| n.children.last())
+            .records
+            .last()
+            .map(|r| (&r.key, r.value.as_ref().unwrap()))
+    }
+
+    fn edge_leaf<F>(&self, mut child: F) -> &Node<K, V>
+    where
+        F: FnMut(&Node<K, V>) -> Option<&NonNull<Node<K, V>>>,
+    {
+        let mut node = unsafe { self.root.as_ref() };
+        while let Some(c) = child(node) {
+            node = unsafe { c.as_ref() };
+        }
+        node
+    }
+
+    /// Visit every record in key order.
     pub fn traverse<F>(&self, mut cb: F)
     where
         F: FnMut(&K, &V),
     {
-        for leaf in unsafe { self.leaves.as_ref() } {
-            for rec in unsafe { &leaf.as_ref().records } {
-                cb(&rec.key, &rec.value.as_ref().unwrap());
+        // Split leaves are appended to `leaves`, so walk down from the root
+        // instead to keep the order.
+        let mut stack = vec![self.root];
+        while let Some(node_ptr) = stack.pop() {
+            let node = unsafe { node_ptr.as_ref() };
+            if node.is_leaf {
+                for rec in &node.records {
+                    cb(&rec.key, rec.value.as_ref().unwrap());
+                }
+            } else {
+                stack.extend(node.children.iter().rev());
+            }
+        }
+    }
+
+    /// `traverse` handing out each value to be changed in place, e.g. to
+    /// minify definitions before saving. Leaves are written as they are, so
+    /// values that grow much make leaves larger than the limit. Every leaf is
+    /// marked changed for `write_changes_to`.
+    pub fn traverse_mut<F>(&mut self, mut cb: F)
+    where
+        F: FnMut(&K, &mut V),
+    {
+        let mut stack = vec![self.root];
+        while let Some(mut node_ptr) = stack.pop() {
+            let node = unsafe { node_ptr.as_mut() };
+            if node.is_leaf {
+                node.offset = 0;
+                for rec in node.records.iter_mut() {
+                    cb(&rec.key, rec.value.as_mut().unwrap());
+                }
+            } else {
+                stack.extend(node.children.iter().rev());
+            }
+        }
+    }
+}
+
+/// A tree that can no longer change, to be shared between threads behind an
+/// `Arc` for lookups. `Tree` is only `Send`, as an `insert` through a
+/// `&mut` would race readers walking its raw node pointers; a frozen tree
+/// has no way left to change. Keys compare in tree order, i.e.
+/// by their sort keys under the tree's collator.
+pub struct FrozenTree<K, V> {
+    tree: Tree<K, V>,
+    /// Leaves in key order
+    leaves: Vec<NonNull<Node<K, V>>>,
+    /// Smoothed last key of each leaf
+    last_keys: Vec<K>,
+}
+
+// Safety: nodes are only read once frozen
+unsafe impl<K: Send + Sync, V: Send + Sync> Send for FrozenTree<K, V> {}
+unsafe impl<K: Send + Sync, V: Send + Sync> Sync for FrozenTree<K, V> {}
+
+impl<
+        K: PartialOrd + Ord + Serializable + Smoothable + Clone + Display + Debug,
+        V: Serializable,
+    > Tree<K, V>
+{
+    pub fn freeze(self) -> FrozenTree<K, V> {
+        let mut leaves = vec![];
+        let mut stack = vec![self.root];
+        while let Some(node_ptr) = stack.pop() {
+            let node = unsafe { node_ptr.as_ref() };
+            if node.is_leaf {
+                if !node.records.is_empty() {
+                    leaves.push(node_ptr);
+                }
+            } else {
+                stack.extend(node.children.iter().rev());
+            }
+        }
+        let last_keys = leaves
+            .iter()
+            .map(|l| {
+                let node = unsafe { l.as_ref() };
+                node.records.last().unwrap().key.smooth(&*self.collator)
+            })
+            .collect();
+        FrozenTree {
+            tree: self,
+            leaves,
+            last_keys,
+        }
+    }
+}
+
+impl<
+        K: PartialOrd + Ord + Serializable + Smoothable + Clone + Display + Debug,
+        V: Serializable,
+    > FrozenTree<K, V>
+{
+    /// Value of the first record with exactly `key`
+    pub fn get(&self, key: &K) -> Option<&V> {
+        let smooth_key = key.smooth(&*self.tree.collator);
+        self.records_from(&smooth_key)
+            .take_while(|r| r.0.smooth(&*self.tree.collator) == smooth_key)
+            .find(|r| r.0 == key)
+            .map(|r| r.1)
+    }
+
+    /// Records whose keys fall in `range` in tree order, e.g. `a..b` for
+    /// the keys sorting from `a` up to `b`
+    pub fn range<'a, R: RangeBounds<K>>(
+        &'a self,
+        range: R,
+    ) -> impl Iterator<Item = (&'a K, &'a V)> + 'a {
+        let collator = &*self.tree.collator;
+        let start = match range.start_bound() {
+            Bound::Included(k) => Bound::Included(k.smooth(collator)),
+            Bound::Excluded(k) => Bound::Excluded(k.smooth(collator)),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(k) => Bound::Included(k.smooth(collator)),
+            Bound::Excluded(k) => Bound::Excluded(k.smooth(collator)),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let records: Box<dyn Iterator<Item = (&'a K, &'a V)>> = match &start {
+            Bound::Included(k) | Bound::Excluded(k) => Box::new(self.records_from(k)),
+            Bound::Unbounded => Box::new(self.records_from_leaf(0)),
+        };
+        records
+            .skip_while(move |r| match &start {
+                Bound::Excluded(k) => r.0.smooth(collator) <= *k,
+                _ => false,
+            })
+            .take_while(move |r| match &end {
+                Bound::Included(k) => r.0.smooth(collator) <= *k,
+                Bound::Excluded(k) => r.0.smooth(collator) < *k,
+                Bound::Unbounded => true,
+            })
+    }
+
+    /// Visit every record in key order.
+    pub fn traverse<F>(&self, mut cb: F)
+    where
+        F: FnMut(&K, &V),
+    {
+        for (k, v) in self.records_from_leaf(0) {
+            cb(k, v);
+        }
+    }
+
+    pub fn record_num(&self) -> usize {
+        self.tree.record_num()
+    }
+
+    /// Records from the first one whose smoothed key is not below
+    /// `smooth_key`
+    fn records_from<'a>(&'a self, smooth_key: &K) -> impl Iterator<Item = (&'a K, &'a V)> + 'a {
+        let collator = &*self.tree.collator;
+        let leaf = self.last_keys.partition_point(|k| k < smooth_key);
+        let smooth_key = smooth_key.clone();
+        self.records_from_leaf(leaf)
+            .skip_while(move |r| r.0.smooth(collator) < smooth_key)
+    }
+
+    fn records_from_leaf(&self, leaf: usize) -> impl Iterator<Item = (&K, &V)> {
+        self.leaves[leaf.min(self.leaves.len())..]
+            .iter()
+            .flat_map(|l| unsafe { &l.as_ref().records })
+            .map(|r| (&r.key, r.value.as_ref().unwrap()))
+    }
+}
+
+/// Writes a tree from records input in key order without holding it in
+/// memory. Full leaves go straight to the file and are linked to their right
+/// sibling once it is written, so the codec must have `next_links`. Index
+/// nodes are written as soon as they fill up; only the offset, size and last
+/// key of the children still waiting for a parent are kept.
+pub struct TreeBuilder<K, V> {
+    leaf: Vec<Record<K, V>>,
+    leaf_size: usize,
+    last_key: Option<K>,
+    /// Offset and size of the last leaf written, to link it to the next
+    last_leaf: Option<(u64, u32)>,
+    /// Children waiting for an index node, from the leaves up
+    levels: Vec<IndexLevel<K>>,
+    record_num: usize,
+    index_size_limit: usize,
+    leaf_size_limit: usize,
+    collator: Arc<dyn Collator>,
+}
+
+/// Offset, size and last key (smoothed) of a node written by `TreeBuilder`
+type BuiltNode<K> = (u64, u32, K);
+
+/// Children of one level of a `TreeBuilder` without a parent yet. A full
+/// group is held back until the next one has two children, so that a lone
+/// last child can still join it in `finish`.
+struct IndexLevel<K> {
+    full: Vec<BuiltNode<K>>,
+    open: Vec<BuiltNode<K>>,
+    open_size: usize,
+    written: usize,
+}
+
+impl<K: Serializable> IndexLevel<K> {
+    fn new() -> Self {
+        Self {
+            full: vec![],
+            open: vec![],
+            open_size: 0,
+            written: 0,
+        }
+    }
+
+    /// Add a child, returning the children of an index node to write
+    fn push(&mut self, child: BuiltNode<K>, size_limit: usize) -> Option<Vec<BuiltNode<K>>> {
+        // same grouping as `Tree::write_to`: each child but the last is
+        // followed by its separator
+        match self.open.last() {
+            None => self.open_size = 1 + 4 + 12,
+            Some(last) => {
+                let cost = last.2.size() + 4 + 12;
+                if self.open.len() >= 2 && self.open_size + cost > size_limit {
+                    self.full = std::mem::take(&mut self.open);
+                    self.open_size = 1 + 4 + 12;
+                } else {
+                    self.open_size += cost;
+                }
+            }
+        }
+        self.open.push(child);
+        if self.open.len() == 2 && !self.full.is_empty() {
+            Some(std::mem::take(&mut self.full))
+        } else {
+            None
+        }
+    }
+}
+
+impl<
+        K: PartialOrd + Ord + Serializable + Smoothable + Clone + Display + Debug,
+        V: Serializable,
+    > TreeBuilder<K, V>
+{
+    pub fn new(
+        index_size_limit: usize,
+        leaf_size_limit: usize,
+        collator: Arc<dyn Collator>,
+    ) -> Self {
+        Self {
+            leaf: vec![],
+            leaf_size: 0,
+            last_key: None,
+            last_leaf: None,
+            levels: vec![],
+            record_num: 0,
+            index_size_limit,
+            leaf_size_limit,
+            collator,
+        }
+    }
+
+    /// Add a record, writing the current leaf to `file` if it is full.
+    /// Fails without adding anything if `key` sorts before the previous one.
+    pub async fn insert<W: AsyncWrite + AsyncSeek + Unpin>(
+        &mut self,
+        file: &mut W,
+        codec: &NodeCodec,
+        key: K,
+        value: V,
+    ) -> Result<()> {
+        let smooth_key = key.smooth(&*self.collator);
+        if let Some(last) = &self.last_key {
+            if smooth_key < *last {
+                return Err(Error::Msg(format!("key {} is out of order", key)));
+            }
+        }
+        let rec = Record::with_value(key, value);
+        let rec_size = rec.size();
+        // same bound as a split in `Tree::insert`: header, sibling pointer
+        if !self.leaf.is_empty() && 1 + 4 + 12 + self.leaf_size + rec_size > self.leaf_size_limit {
+            self.write_leaf(file, codec).await?;
+        }
+        self.leaf.push(rec);
+        self.leaf_size += rec_size;
+        self.last_key = Some(smooth_key);
+        self.record_num += 1;
+        Ok(())
+    }
+
+    pub fn record_num(&self) -> usize {
+        self.record_num
+    }
+
+    async fn write_leaf<W: AsyncWrite + AsyncSeek + Unpin>(
+        &mut self,
+        file: &mut W,
+        codec: &NodeCodec,
+    ) -> Result<()> {
+        if !codec.next_links {
+            return Err(Error::Msg(
+                "TreeBuilder needs a codec with next_links".to_string(),
+            ));
+        }
+        let node_buf = node_bytes(true, &self.leaf, &[], codec.varint)?;
+        let offset = file.stream_position().await?;
+        let mut buf = codec.encode_leaf(node_buf, offset, (0, 0));
+        if let (true, Some(prev)) = (codec.prev_links, self.last_leaf) {
+            let at = buf.len() - LINK_SIZE;
+            buf[at..at + 8].copy_from_slice(&u64_to_u8v(prev.0));
+            buf[at + 8..].copy_from_slice(&u32_to_u8v(prev.1));
+        }
+        file.write_all(&buf).await?;
+        let leaf = (offset, buf.len() as u32);
+        if let Some(prev) = self.last_leaf {
+            link_next(file, prev, leaf, codec).await?;
+        }
+        self.last_leaf = Some(leaf);
+        let last_key = self.leaf.last().unwrap().key.smooth(&*self.collator);
+        self.leaf.clear();
+        self.leaf_size = 0;
+        self.push_child(file, codec, 0, (leaf.0, leaf.1, last_key))
+            .await
+    }
+
+    /// Add `child` to the level `depth`, writing the index nodes it fills
+    async fn push_child<W: AsyncWrite + AsyncSeek + Unpin>(
+        &mut self,
+        file: &mut W,
+        codec: &NodeCodec,
+        mut depth: usize,
+        mut child: BuiltNode<K>,
+    ) -> Result<()> {
+        loop {
+            if self.levels.len() == depth {
+                self.levels.push(IndexLevel::new());
+            }
+            match self.levels[depth].push(child, self.index_size_limit) {
+                Some(children) => {
+                    child = self.write_index(file, codec, depth, children).await?;
+                    depth += 1;
+                }
+                None => return Ok(()),
+            }
+        }
+    }
+
+    async fn write_index<W: AsyncWrite + AsyncSeek + Unpin>(
+        &mut self,
+        file: &mut W,
+        codec: &NodeCodec,
+        depth: usize,
+        mut children: Vec<BuiltNode<K>>,
+    ) -> Result<BuiltNode<K>> {
+        let records: Vec<Record<K, V>> = children[..children.len() - 1]
+            .iter()
+            .map(|c| Record::new(c.2.clone()))
+            .collect();
+        let pointers: Children = children.iter().map(|c| (c.0, c.1)).collect();
+        let node_buf = node_bytes(false, &records, &pointers, codec.varint)?;
+        let offset = file.stream_position().await?;
+        let buf = codec.encode(node_buf, offset);
+        file.write_all(&buf).await?;
+        self.levels[depth].written += 1;
+        let last_key = children.pop().unwrap().2;
+        Ok((offset, buf.len() as u32, last_key))
+    }
+
+    /// Write the last leaf and the index nodes still pending and return the
+    /// offset and size of the root, like `Tree::write_to`.
+    pub async fn finish<W: AsyncWrite + AsyncSeek + Unpin>(
+        mut self,
+        file: &mut W,
+        codec: &NodeCodec,
+    ) -> Result<(u64, u32)> {
+        if !self.leaf.is_empty() {
+            self.write_leaf(file, codec).await?;
+        }
+        let mut depth = 0;
+        while depth < self.levels.len() {
+            let level = &mut self.levels[depth];
+            let mut groups = vec![];
+            if !level.full.is_empty() {
+                let mut full = std::mem::take(&mut level.full);
+                // an index node needs two children, a lone last one joins it
+                if level.open.len() == 1 {
+                    full.append(&mut level.open);
+                }
+                groups.push(full);
+            }
+            if !level.open.is_empty() {
+                groups.push(std::mem::take(&mut level.open));
+            }
+            if level.written == 0 && groups.len() == 1 && groups[0].len() == 1 {
+                file.flush().await?;
+                return Ok((groups[0][0].0, groups[0][0].1));
+            }
+            for children in groups {
+                let parent = self.write_index(file, codec, depth, children).await?;
+                self.push_child(file, codec, depth + 1, parent).await?;
+            }
+            depth += 1;
+        }
+        Ok((0, 0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::beluga::{EntryKey, EntryValue};
+    use crate::collation::default_collator;
+
+    /// Value under `key`, descending from the root through the separators
+    fn lookup<'a>(tree: &'a Tree<EntryKey, EntryValue>, key: &EntryKey) -> Option<&'a EntryValue> {
+        let smooth_key = key.smooth(&*tree.collator);
+        let mut node = unsafe { tree.root.as_ref() };
+        while !node.is_leaf {
+            let index = node
+                .records
+                .iter()
+                .position(|r| smooth_key <= r.key)
+                .unwrap_or(node.records.len());
+            node = unsafe { node.children[index].as_ref() };
+        }
+        node.records
+            .iter()
+            .find(|r| r.key == *key)
+            .and_then(|r| r.value.as_ref())
+    }
+
+    fn build(order: &[usize], index_size: usize) -> Tree<EntryKey, EntryValue> {
+        let mut tree = Tree::new(index_size, 48, default_collator());
+        for i in order {
+            let key = EntryKey(format!("key{:05}", i));
+            tree.insert(key, EntryValue(i.to_string().into_bytes()));
+        }
+        tree
+    }
+
+    async fn read_back(
+        bytes: Vec<u8>,
+        root: (u64, u32),
+        codec: &NodeCodec,
+    ) -> Result<Tree<EntryKey, EntryValue>> {
+        let mut cursor = std::io::Cursor::new(bytes);
+        Tree::from_file(
+            &mut cursor,
+            root.0,
+            root.1,
+            64,
+            48,
+            codec,
+            default_collator(),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn checksum_catches_corrupt_nodes() {
+        let codec = NodeCodec::new(true, false);
+        let node_buf = b"leaf bytes".to_vec();
+        let buf = codec.encode(node_buf.clone(), 0);
+        assert_eq!(codec.decode(&buf, 0).unwrap(), node_buf);
+        // a changed byte that still inflates fine
+        let mut data = node_buf.clone();
+        data.extend(u32_to_u8v(crc32(&node_buf)));
+        data[0] ^= 1;
+        let bad = compress(&data);
+        assert!(codec.decode(&bad, 0).is_err());
+        assert!(NodeCodec::new(false, false).decode(&bad, 0).is_ok());
+
+        let tree = build(&(0..200).collect::<Vec<usize>>(), 64);
+        let mut cursor = std::io::Cursor::new(Vec::new());
+        let root = tree.write_to(&mut cursor, &codec, None).await.unwrap();
+        assert!(read_back(cursor.get_ref().clone(), root, &codec)
+            .await
+            .is_ok());
+        let leaf = tree.edge_leaf(|n| n.children.first());
+        let mut bytes = cursor.into_inner();
+        bytes[(leaf.offset + leaf.zip_size as u64 / 2) as usize] ^= 0x10;
+        assert!(matches!(
+            read_back(bytes, root, &codec).await,
+            Err(Error::Corrupt { .. })
+        ));
+    }
+
+    #[test]
+    fn stats_of_empty_and_lazy_trees() {
+        let empty = Tree::<EntryKey, EntryValue>::new(64, 48, default_collator());
+        let stats = empty.stats();
+        assert_eq!((stats.height, stats.node_num, stats.leaf_num), (1, 1, 1));
+        assert_eq!(stats.avg_leaf_records, 0.0);
+        assert_eq!(stats.avg_zip_size, 0.0);
+
+        let lazy = Tree::<EntryKey, EntryValue>::lazy((100, 20), 64, 48, default_collator());
+        let stats = lazy.stats();
+        assert_eq!((stats.node_num, stats.leaf_num), (0, 0));
+        assert_eq!(stats.avg_leaf_records, 0.0);
+        assert_eq!(stats.avg_zip_size, 0.0);
+
+        let stats = build(&(0..300).collect::<Vec<usize>>(), 64).stats();
+        assert_eq!(stats.avg_leaf_records, 300.0 / stats.leaf_num as f64);
+    }
+
+    #[tokio::test]
+    async fn write_progress_reaches_total() {
+        let tree = build(&(0..500).collect::<Vec<usize>>(), 64);
+        let codec = NodeCodec::new(true, false);
+        let mut calls: Vec<(u64, u64)> = vec![];
+        let mut progress = |done: u64, total: u64| calls.push((done, total));
+        // a zero offset means not written yet, files start with a header
+        let mut cursor = std::io::Cursor::new(b"head".to_vec());
+        cursor.set_position(4);
+        tree.write_to(&mut cursor, &codec, Some(&mut progress))
+            .await
+            .unwrap();
+        let total = tree.stats().node_num as u64;
+        assert!(total > 1);
+        let expected: Vec<(u64, u64)> = (1..=total).map(|done| (done, total)).collect();
+        assert_eq!(calls, expected);
+    }
+
+    #[test]
+    fn node_bytes_round_trip() {
+        let records: Vec<Record<EntryKey, EntryValue>> = (0..4)
+            .map(|i| Record::with_value(EntryKey(format!("key{}", i)), EntryValue(vec![i; 3])))
+            .collect();
+        let mut leaf = node_bytes(true, &records, &[], false).unwrap();
+        write_offset(&mut leaf, 0, false);
+        write_size(&mut leaf, 0, false);
+        let (node, children) = Node::<EntryKey, EntryValue>::from_bytes(&leaf).unwrap();
+        assert!(node.is_leaf);
+        assert_eq!(children, vec![(0, 0)]);
+        for (read, written) in node.records.iter().zip(&records) {
+            assert_eq!(read.key, written.key);
+            assert_eq!(
+                read.value.as_ref().unwrap().0,
+                written.value.as_ref().unwrap().0
+            );
+        }
+        assert_eq!(node.records.len(), records.len());
+
+        let keys: Vec<Record<EntryKey, EntryValue>> =
+            records.iter().map(|r| Record::new(r.key.clone())).collect();
+        let pointers: Vec<(u64, u32)> = (0..5).map(|i| (i * 1000 + 4, i as u32 + 7)).collect();
+        let index = node_bytes(false, &keys, &pointers, false).unwrap();
+        let (node, children) = Node::<EntryKey, EntryValue>::from_bytes(&index).unwrap();
+        assert!(!node.is_leaf);
+        assert_eq!(children, pointers);
+        let read: Vec<&EntryKey> = node.records.iter().map(|r| &r.key).collect();
+        let written: Vec<&EntryKey> = keys.iter().map(|r| &r.key).collect();
+        assert_eq!(read, written);
+        assert!(node.records.iter().all(|r| r.value.is_none()));
+    }
+
+    #[test]
+    fn varint_nodes_round_trip_smaller() {
+        let records: Vec<Record<EntryKey, EntryValue>> = (0..20)
+            .map(|i| Record::with_value(EntryKey(format!("k{}", i)), EntryValue(vec![i; 2])))
+            .collect();
+        let mut sizes = vec![];
+        for varint in [false, true] {
+            let mut bytes = node_bytes(true, &records, &[], varint).unwrap();
+            write_offset(&mut bytes, 300, varint);
+            write_size(&mut bytes, 70, varint);
+            let (node, children) = Node::<EntryKey, EntryValue>::from_bytes(&bytes).unwrap();
+            assert_eq!(children, vec![(300, 70)]);
+            assert_eq!(node.records.len(), records.len());
+            assert_eq!(node.records[19].key.0, "k19");
+            assert_eq!(node.records[19].value.as_ref().unwrap().0, vec![19; 2]);
+            sizes.push(bytes.len());
+        }
+        // two length prefixes of a record shrink from 8 bytes to 2
+        assert!(sizes[0] - sizes[1] >= 6 * records.len(), "{:?}", sizes);
+    }
+
+    #[test]
+    fn cascading_index_splits_stay_valid() {
+        let n = 3000;
+        let orders: Vec<Vec<usize>> = vec![
+            (0..n).collect(),
+            (0..n).rev().collect(),
+            (0..n).map(|i| i * 7919 % n).collect(),
+        ];
+        // from an index node split at the minimum of 3 records to one split
+        // at a few more
+        for index_size in [1, 40, 64] {
+            for order in &orders {
+                let tree = build(order, index_size);
+                tree.validate().unwrap();
+                assert!(tree.stats().height >= 4);
+                assert_eq!(tree.record_num(), n);
+                for i in 0..n {
+                    let key = EntryKey(format!("key{:05}", i));
+                    let value = lookup(&tree, &key).unwrap();
+                    assert_eq!(value.0, i.to_string().into_bytes());
+                }
+                assert!(lookup(&tree, &EntryKey("key".to_string())).is_none());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn write_to_cursor_reads_back() {
+        let n = 500;
+        let tree = build(&(0..n).rev().collect::<Vec<usize>>(), 64);
+        for prev_links in [false, true] {
+            let mut codec = NodeCodec::new(true, false);
+            codec.prev_links = prev_links;
+            let mut cursor = std::io::Cursor::new(b"head".to_vec());
+            cursor.set_position(4);
+            let root = tree.write_to(&mut cursor, &codec, None).await.unwrap();
+            assert!(root.0 >= 4);
+            let file_size = cursor.get_ref().len() as u64;
+            let check = Tree::<EntryKey, EntryValue>::check_file(
+                &mut cursor,
+                file_size,
+                root,
+                &codec,
+                &*default_collator(),
+            )
+            .await
+            .unwrap();
+            assert_eq!(check.anomaly, None);
+            assert_eq!(check.record_num, n);
+            let read: Tree<EntryKey, EntryValue> = Tree::from_file(
+                &mut cursor,
+                root.0,
+                root.1,
+                64,
+                48,
+                &codec,
+                default_collator(),
+            )
+            .await
+            .unwrap();
+            let mut keys = vec![];
+            read.traverse(|k, _| keys.push(k.0.clone()));
+            let expected: Vec<String> = (0..n).map(|i| format!("key{:05}", i)).collect();
+            assert_eq!(keys, expected);
+        }
+    }
+
+    #[test]
+    fn truncated_node_is_malformed() {
+        let records: Vec<Record<EntryKey, EntryValue>> = (0..3)
+            .map(|i| Record::with_value(EntryKey(format!("key{}", i)), EntryValue(vec![i; 10])))
+            .collect();
+        for varint in [false, true] {
+            let mut bytes = node_bytes(true, &records, &[], varint).unwrap();
+            write_offset(&mut bytes, 0, varint);
+            write_size(&mut bytes, 0, varint);
+            assert!(Node::<EntryKey, EntryValue>::from_bytes(&bytes).is_ok());
+            for len in 0..bytes.len() {
+                assert!(matches!(
+                    Node::<EntryKey, EntryValue>::from_bytes(&bytes[..len]),
+                    Err(Error::Malformed(_))
+                ));
             }
         }
     }
+
+    #[tokio::test]
+    async fn parse_progress_counts_nodes() {
+        let tree = build(&(0..500).collect::<Vec<usize>>(), 64);
+        let codec = NodeCodec::new(true, false);
+        let mut cursor = std::io::Cursor::new(Vec::new());
+        let root = tree.write_to(&mut cursor, &codec, None).await.unwrap();
+        let mut calls = vec![];
+        let mut progress = |parsed: u64| calls.push(parsed);
+        let read: Tree<EntryKey, EntryValue> = Tree::from_file_with_progress(
+            &mut cursor,
+            root.0,
+            root.1,
+            64,
+            48,
+            &codec,
+            default_collator(),
+            Some(&mut progress),
+        )
+        .await
+        .unwrap();
+        let node_num = read.stats().node_num;
+        assert!(node_num > 10);
+        assert_eq!(node_num, tree.stats().node_num);
+        assert_eq!(calls, (1..=node_num as u64).collect::<Vec<u64>>());
+    }
+
+    #[tokio::test]
+    async fn loading_prints_nothing() {
+        let tree = build(&(0..500).collect::<Vec<usize>>(), 64);
+        let codec = NodeCodec::new(true, false);
+        let mut cursor = std::io::Cursor::new(Vec::new());
+        let root = tree.write_to(&mut cursor, &codec, None).await.unwrap();
+        let printed = || PRINTED_LINES.with(|n| n.get());
+        let before = printed();
+        let read = read_back(cursor.into_inner(), root, &codec).await.unwrap();
+        assert_eq!(printed(), before);
+        read.debug_print();
+        assert_eq!(printed(), before + read.stats().node_num);
+        Tree::<EntryKey, EntryValue>::new(64, 48, default_collator()).debug_print();
+        assert_eq!(printed(), before + read.stats().node_num);
+    }
+
+    #[tokio::test]
+    async fn traverse_mut_changes_every_value() {
+        let n = 300;
+        let mut tree = build(&(0..n).collect::<Vec<usize>>(), 64);
+        let codec = NodeCodec::new(true, false);
+        let mut cursor = std::io::Cursor::new(b"head".to_vec());
+        cursor.set_position(4);
+        tree.write_to(&mut cursor, &codec, None).await.unwrap();
+        tree.traverse_mut(|_, v| v.0.extend_from_slice(b"!"));
+        let mut seen = 0;
+        tree.traverse(|k, v| {
+            assert_eq!(v.0, format!("{}!", seen).into_bytes(), "{}", k);
+            seen += 1;
+        });
+        assert_eq!(seen, n);
+        let mut stack = vec![tree.root];
+        while let Some(ptr) = stack.pop() {
+            let node = unsafe { ptr.as_ref() };
+            if node.is_leaf {
+                assert_eq!(node.offset, 0);
+            } else {
+                stack.extend(node.children.iter());
+            }
+        }
+    }
+
+    #[test]
+    fn frozen_tree_is_shared_across_threads() {
+        fn shareable<T: Send + Sync>() {}
+        shareable::<FrozenTree<EntryKey, EntryValue>>();
+        let n = 2000;
+        let order: Vec<usize> = (0..n).map(|i| i * 7919 % n).collect();
+        let tree = Arc::new(build(&order, 64).freeze());
+        let threads: Vec<_> = (0..4)
+            .map(|t| {
+                let tree = tree.clone();
+                std::thread::spawn(move || {
+                    for i in (t..n).step_by(4) {
+                        let key = EntryKey(format!("key{:05}", i));
+                        assert_eq!(tree.get(&key).unwrap().0, i.to_string().into_bytes());
+                    }
+                    assert!(tree.get(&EntryKey("key".to_string())).is_none());
+                    let from = EntryKey(format!("key{:05}", t * 100));
+                    let to = EntryKey(format!("key{:05}", t * 100 + 50));
+                    let keys: Vec<String> =
+                        tree.range(&from..&to).map(|r| r.0 .0.clone()).collect();
+                    let expected: Vec<String> = (t * 100..t * 100 + 50)
+                        .map(|i| format!("key{:05}", i))
+                        .collect();
+                    assert_eq!(keys, expected);
+                    let mut count = 0;
+                    tree.traverse(|_, _| count += 1);
+                    count
+                })
+            })
+            .collect();
+        for thread in threads {
+            assert_eq!(thread.join().unwrap(), n);
+        }
+        assert_eq!(tree.record_num(), n);
+    }
+
+    #[test]
+    fn sizes_beyond_u32_are_too_large() {
+        assert_eq!(checked_size(0, "key").unwrap(), 0);
+        assert_eq!(checked_size(u32::MAX as usize, "value").unwrap(), u32::MAX);
+        let len = u32::MAX as usize + 1;
+        assert!(matches!(
+            checked_size(len, "value"),
+            Err(Error::TooLarge { what: "value", len: l }) if l == len
+        ));
+    }
+
+    #[tokio::test]
+    async fn first_and_last_records() {
+        let empty: Tree<EntryKey, EntryValue> = Tree::new(64, 48, default_collator());
+        assert!(empty.first().is_none() && empty.last().is_none());
+
+        let n = 1000;
+        let tree = build(&(0..n).map(|i| i * 7919 % n).collect::<Vec<usize>>(), 64);
+        assert!(tree.stats().height >= 3);
+        let codec = NodeCodec::new(true, false);
+        let mut cursor = std::io::Cursor::new(b"head".to_vec());
+        cursor.set_position(4);
+        let root = tree.write_to(&mut cursor, &codec, None).await.unwrap();
+        let loaded = read_back(cursor.into_inner(), root, &codec).await.unwrap();
+        for tree in [&tree, &loaded] {
+            let (key, value) = tree.first().unwrap();
+            assert_eq!(
+                (key.0.as_str(), value.0.as_slice()),
+                ("key00000", &b"0"[..])
+            );
+            let (key, value) = tree.last().unwrap();
+            assert_eq!(
+                (key.0.as_str(), value.0.as_slice()),
+                ("key00999", &b"999"[..])
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn leaves_link_back_to_their_left_sibling() {
+        let tree = build(&(0..500).collect::<Vec<usize>>(), 64);
+        let mut codec = NodeCodec::new(true, false);
+        codec.prev_links = true;
+        let mut cursor = std::io::Cursor::new(b"head".to_vec());
+        cursor.set_position(4);
+        let root = tree.write_to(&mut cursor, &codec, None).await.unwrap();
+        let mut bytes = cursor.into_inner();
+        let read = read_back(bytes.clone(), root, &codec).await.unwrap();
+        let mut leaves: Vec<(u64, u32, (u64, u32))> = vec![];
+        let mut stack = vec![read.root];
+        while let Some(ptr) = stack.pop() {
+            let node = unsafe { ptr.as_ref() };
+            if node.is_leaf {
+                leaves.push((node.offset, node.zip_size, node.prev));
+            } else {
+                stack.extend(node.children.iter().rev());
+            }
+        }
+        assert!(leaves.len() > 2);
+        assert_eq!(leaves[0].2, (0, 0));
+        for pair in leaves.windows(2) {
+            assert_eq!(pair[1].2, (pair[0].0, pair[0].1));
+        }
+
+        // point the third leaf back to the first, skipping the second
+        let (offset, size, _) = leaves[2];
+        let at = (offset + size as u64) as usize - LINK_SIZE;
+        bytes[at..at + 8].copy_from_slice(&u64_to_u8v(leaves[0].0));
+        bytes[at + 8..at + 12].copy_from_slice(&u32_to_u8v(leaves[0].1));
+        let file_size = bytes.len() as u64;
+        let check = Tree::<EntryKey, EntryValue>::check_file(
+            &mut std::io::Cursor::new(bytes),
+            file_size,
+            root,
+            &codec,
+            &*default_collator(),
+        )
+        .await
+        .unwrap();
+        let anomaly = check.anomaly.unwrap();
+        assert!(anomaly.contains("links back to"), "{}", anomaly);
+    }
 }
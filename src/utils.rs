@@ -1,61 +1,428 @@
-pub fn u8v_to_u64(v: &[u8]) -> u64 {
-    if v.len() != 8 {
-        panic!("Invalid vector size");
+use crate::error::{Error, Result};
+
+fn invalid_size(v: &[u8]) -> Error {
+    Error::Malformed(format!("invalid vector size {}", v.len()))
+}
+
+pub fn u8v_to_u64(v: &[u8]) -> Result<u64> {
+    Ok(u64::from_be_bytes(
+        v.try_into().map_err(|_| invalid_size(v))?,
+    ))
+}
+
+pub fn u64_to_u8v(v: u64) -> Vec<u8> {
+    v.to_be_bytes().to_vec()
+}
+
+pub fn u8v_to_u32(v: &[u8]) -> Result<u32> {
+    Ok(u32::from_be_bytes(
+        v.try_into().map_err(|_| invalid_size(v))?,
+    ))
+}
+
+pub fn u8v_to_u16(v: &[u8]) -> Result<u16> {
+    Ok(u16::from_be_bytes(
+        v.try_into().map_err(|_| invalid_size(v))?,
+    ))
+}
+
+pub fn u32_to_u8v(v: u32) -> Vec<u8> {
+    v.to_be_bytes().to_vec()
+}
+
+pub fn u16_to_u8v(v: u16) -> Vec<u8> {
+    v.to_be_bytes().to_vec()
+}
+
+/// Fill `buf` from `offset` of `file` without moving its cursor, so several
+/// readers can share one handle. Targets without positioned reads seek the
+/// shared cursor instead, one reader at a time.
+pub fn read_exact_at(file: &std::fs::File, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
     }
-    let mut r: u64 = 0;
-    for i in 0..8 {
-        r |= (v[i] as u64) << (7 - i) * 8;
+    #[cfg(windows)]
+    {
+        let mut done = 0;
+        while done < buf.len() {
+            let n = std::os::windows::fs::FileExt::seek_read(
+                file,
+                &mut buf[done..],
+                offset + done as u64,
+            )?;
+            if n == 0 {
+                return Err(std::io::ErrorKind::UnexpectedEof.into());
+            }
+            done += n;
+        }
+        Ok(())
+    }
+    #[cfg(not(any(unix, windows)))]
+    {
+        use std::io::{Read, Seek};
+        static CURSOR: std::sync::Mutex<()> = std::sync::Mutex::new(());
+        let _guard = CURSOR.lock().unwrap_or_else(|e| e.into_inner());
+        let mut file = file;
+        file.seek(std::io::SeekFrom::Start(offset))?;
+        file.read_exact(buf)
     }
-    r
 }
 
-pub fn u64_to_u8v(v: u64) -> Vec<u8> {
-    let mut r: Vec<u8> = Vec::new();
-    for i in (0..8).rev() {
-        let elem = (v >> (i * 8)) as u8;
-        r.push(elem);
+/// Append `v` as an unsigned LEB128 varint, 7 bits per byte, low bits first.
+pub fn write_varint(v: u64, buf: &mut Vec<u8>) {
+    let mut v = v;
+    while v >= 0x80 {
+        buf.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+/// Match `text` against a glob `pattern` with `*` and `?` wildcards.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+    let t: Vec<char> = text.chars().collect();
+    let (mut pi, mut ti) = (0, 0);
+    // position of the last `*` and the text position it is matched up to
+    let mut star: Option<(usize, usize)> = None;
+    while ti < t.len() {
+        if pi < p.len() && (p[pi] == '?' || (p[pi] != '*' && p[pi] == t[ti])) {
+            pi += 1;
+            ti += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            star = Some((pi, ti));
+            pi += 1;
+        } else if let Some((sp, st)) = star {
+            pi = sp + 1;
+            ti = st + 1;
+            star = Some((sp, st + 1));
+        } else {
+            return false;
+        }
     }
-    return r;
+    p[pi..].iter().all(|c| *c == '*')
 }
 
-pub fn u8v_to_u32(v: &[u8]) -> u32 {
-    if v.len() != 4 {
-        panic!("Invalid vector size");
+/// Levenshtein distance between `a` and `b`, or `None` as soon as it is known
+/// to exceed `max`.
+pub fn bounded_levenshtein(a: &[char], b: &[char], max: usize) -> Option<usize> {
+    if a.len().abs_diff(b.len()) > max {
+        return None;
     }
-    let mut r: u32 = 0;
-    for i in 0..4 {
-        r |= (v[i] as u32) << (3 - i) * 8;
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    let mut cur: Vec<usize> = vec![0; b.len() + 1];
+    for i in 1..=a.len() {
+        cur[0] = i;
+        let mut row_min = cur[0];
+        for j in 1..=b.len() {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            cur[j] = (prev[j] + 1).min(cur[j - 1] + 1).min(prev[j - 1] + cost);
+            row_min = row_min.min(cur[j]);
+        }
+        if row_min > max {
+            return None;
+        }
+        std::mem::swap(&mut prev, &mut cur);
+    }
+    let d = prev[b.len()];
+    if d <= max {
+        Some(d)
+    } else {
+        None
     }
-    r
 }
 
-pub fn u8v_to_u16(v: &[u8]) -> u16 {
-    if v.len() != 2 {
-        panic!("Invalid vector size");
+/// Strip accents from `s`, e.g. "résumé" becomes "resume". With the `nfc`
+/// feature every combining mark is removed after canonical decomposition;
+/// without it only combining marks and precomposed Latin-1 letters are folded.
+pub fn fold_diacritics(s: &str) -> String {
+    #[cfg(feature = "nfc")]
+    {
+        use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+        s.nfd().filter(|c| !is_combining_mark(*c)).collect()
     }
-    let mut r: u16 = 0;
-    for i in 0..2 {
-        r |= (v[i] as u16) << (1 - i) * 8;
+    #[cfg(not(feature = "nfc"))]
+    {
+        s.chars()
+            .filter(|c| !('\u{300}'..='\u{36f}').contains(c))
+            .map(|c| match c {
+                'À'..='Å' => 'A',
+                'Ç' => 'C',
+                'È'..='Ë' => 'E',
+                'Ì'..='Ï' => 'I',
+                'Ñ' => 'N',
+                'Ò'..='Ö' | 'Ø' => 'O',
+                'Ù'..='Ü' => 'U',
+                'Ý' => 'Y',
+                'à'..='å' => 'a',
+                'ç' => 'c',
+                'è'..='ë' => 'e',
+                'ì'..='ï' => 'i',
+                'ñ' => 'n',
+                'ò'..='ö' | 'ø' => 'o',
+                'ù'..='ü' => 'u',
+                'ý' | 'ÿ' => 'y',
+                _ => c,
+            })
+            .collect()
     }
-    r
 }
 
-pub fn u32_to_u8v(v: u32) -> Vec<u8> {
-    let mut r: Vec<u8> = Vec::new();
-    for i in (0..4).rev() {
-        let elem = (v >> (i * 8)) as u8;
-        r.push(elem);
+/// MIME type of a resource from the extension of its `name`, or from the
+/// leading bytes of `data` when the extension is missing or unknown.
+pub fn mime_type(name: &str, data: &[u8]) -> &'static str {
+    let ext = match name.rsplit_once('.') {
+        Some((_, ext)) => ext.to_ascii_lowercase(),
+        None => String::new(),
+    };
+    match ext.as_str() {
+        "png" => return "image/png",
+        "jpg" | "jpeg" => return "image/jpeg",
+        "gif" => return "image/gif",
+        "webp" => return "image/webp",
+        "svg" => return "image/svg+xml",
+        "bmp" => return "image/bmp",
+        "ico" => return "image/x-icon",
+        "mp3" => return "audio/mpeg",
+        "ogg" | "oga" => return "audio/ogg",
+        "wav" => return "audio/wav",
+        "spx" => return "audio/ogg",
+        "mp4" => return "video/mp4",
+        "css" => return "text/css",
+        "js" => return "text/javascript",
+        "html" | "htm" => return "text/html",
+        "txt" => return "text/plain",
+        "json" => return "application/json",
+        "pdf" => return "application/pdf",
+        "ttf" => return "font/ttf",
+        "otf" => return "font/otf",
+        "woff" => return "font/woff",
+        "woff2" => return "font/woff2",
+        _ => {}
+    }
+    let riff = |kind: &[u8]| data.starts_with(b"RIFF") && data.get(8..12) == Some(kind);
+    if data.starts_with(b"\x89PNG") {
+        "image/png"
+    } else if data.starts_with(&[0xff, 0xd8, 0xff]) {
+        "image/jpeg"
+    } else if data.starts_with(b"GIF8") {
+        "image/gif"
+    } else if riff(b"WEBP") {
+        "image/webp"
+    } else if riff(b"WAVE") {
+        "audio/wav"
+    } else if data.starts_with(b"ID3")
+        || (data.len() > 1 && data[0] == 0xff && data[1] & 0xe0 == 0xe0)
+    {
+        "audio/mpeg"
+    } else if data.starts_with(b"OggS") {
+        "audio/ogg"
+    } else if data.starts_with(b"wOF2") {
+        "font/woff2"
+    } else if data.starts_with(b"wOFF") {
+        "font/woff"
+    } else if data.starts_with(b"%PDF") {
+        "application/pdf"
+    } else {
+        "application/octet-stream"
     }
-    return r;
 }
 
-pub fn u16_to_u8v(v: u16) -> Vec<u8> {
-    let mut r: Vec<u8> = Vec::new();
-    for i in (0..2).rev() {
-        let elem = (v >> (i * 8)) as u8;
-        r.push(elem);
+/// Rewrite the quoted values of `src` and `href` attributes inside the tags
+/// of `html` with `f`, keeping a value as is when `f` returns `None`. Text
+/// outside tags and other attributes are left untouched.
+pub fn rewrite_urls<F>(html: &str, f: F) -> String
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let b = html.as_bytes();
+    let mut out = String::with_capacity(html.len());
+    let mut copied = 0;
+    let mut in_tag = false;
+    let mut i = 0;
+    while i < b.len() {
+        match b[i] {
+            b'<' if !in_tag => in_tag = true,
+            b'>' if in_tag => in_tag = false,
+            q @ (b'"' | b'\'') if in_tag => {
+                let start = i + 1;
+                let end = match b[start..].iter().position(|c| *c == q) {
+                    Some(n) => start + n,
+                    None => break,
+                };
+                // attribute name before `=`, allowing spaces around it
+                let mut j = i;
+                while j > 0 && b[j - 1].is_ascii_whitespace() {
+                    j -= 1;
+                }
+                if j > 0 && b[j - 1] == b'=' {
+                    j -= 1;
+                    while j > 0 && b[j - 1].is_ascii_whitespace() {
+                        j -= 1;
+                    }
+                    let name_end = j;
+                    while j > 0 && (b[j - 1].is_ascii_alphanumeric() || b[j - 1] == b'-') {
+                        j -= 1;
+                    }
+                    let name = &html[j..name_end];
+                    if name.eq_ignore_ascii_case("src") || name.eq_ignore_ascii_case("href") {
+                        if let Some(v) = f(&html[start..end]) {
+                            out.push_str(&html[copied..start]);
+                            out.push_str(&v);
+                            copied = end;
+                        }
+                    }
+                }
+                i = end;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    out.push_str(&html[copied..]);
+    out
+}
+
+/// Index just past the comment or string starting at `i` of `b`, or `i`
+/// itself when none starts there.
+fn skip_css_literal(b: &[u8], i: usize) -> usize {
+    if b[i..].starts_with(b"/*") {
+        return match find_from(b, i + 2, b"*/") {
+            Some(n) => n + 2,
+            None => b.len(),
+        };
+    }
+    if b[i] == b'"' || b[i] == b'\'' {
+        let mut j = i + 1;
+        while j < b.len() && b[j] != b[i] {
+            j += if b[j] == b'\\' { 2 } else { 1 };
+        }
+        return (j + 1).min(b.len());
+    }
+    i
+}
+
+fn find_from(b: &[u8], from: usize, pat: &[u8]) -> Option<usize> {
+    b[from..]
+        .windows(pat.len())
+        .position(|w| w == pat)
+        .map(|n| from + n)
+}
+
+/// Index of the first of `stops` at nesting depth 0 from `i`, skipping
+/// comments, strings and bracketed parts, or the length of `b`
+fn css_scan_to(b: &[u8], mut i: usize, stops: &[u8]) -> usize {
+    let mut depth = 0usize;
+    while i < b.len() {
+        let skipped = skip_css_literal(b, i);
+        if skipped != i {
+            i = skipped;
+            continue;
+        }
+        match b[i] {
+            c if depth == 0 && stops.contains(&c) => return i,
+            b'(' | b'[' | b'{' => depth += 1,
+            b')' | b']' | b'}' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+        i += 1;
+    }
+    b.len()
+}
+
+/// Prefix every selector of `css` with `scope`, e.g. `p, .a` becomes
+/// `.d p, .d .a`, so several dictionaries can style one page. Rules inside
+/// `@media`, `@supports` and `@layer` are scoped too; other at-rules such as
+/// `@keyframes` and `@font-face` are kept as they are. `html`, `body` and
+/// `:root` are replaced by `scope` itself. Not a full CSS parser: comments
+/// between rules are dropped.
+pub fn scope_css(css: &str, scope: &str) -> String {
+    let b = css.as_bytes();
+    let mut out = String::with_capacity(css.len() + css.len() / 4);
+    let mut i = 0;
+    while i < b.len() {
+        let skipped = skip_css_literal(b, i);
+        if skipped != i || b[i].is_ascii_whitespace() {
+            i = skipped.max(i + 1);
+            continue;
+        }
+        let end = css_scan_to(b, i, b"{;}");
+        let prelude = css[i..end].trim();
+        if end == b.len() || b[end] != b'{' {
+            // statement at-rule like @import, or a stray character
+            if !prelude.is_empty() {
+                out.push_str(prelude);
+                out.push_str(if end < b.len() && b[end] == b';' {
+                    ";\n"
+                } else {
+                    "\n"
+                });
+            }
+            i = end + 1;
+            continue;
+        }
+        let close = css_scan_to(b, end + 1, b"}");
+        let body = &css[end + 1..close.min(b.len())];
+        if let Some(at_rule) = prelude.strip_prefix('@') {
+            let name = at_rule
+                .split(|c: char| c.is_whitespace() || c == '(')
+                .next()
+                .unwrap_or("")
+                .to_ascii_lowercase();
+            if matches!(name.as_str(), "media" | "supports" | "layer" | "container") {
+                out.push_str(&format!("{} {{\n{}}}\n", prelude, scope_css(body, scope)));
+            } else {
+                out.push_str(&format!("{} {{{}}}\n", prelude, body));
+            }
+        } else {
+            let selectors: Vec<String> = split_selectors(prelude)
+                .into_iter()
+                .map(|sel| scope_selector(sel, scope))
+                .collect();
+            out.push_str(&format!("{} {{{}}}\n", selectors.join(", "), body));
+        }
+        i = close + 1;
+    }
+    out
+}
+
+fn split_selectors(prelude: &str) -> Vec<&str> {
+    let b = prelude.as_bytes();
+    let mut parts = vec![];
+    let mut start = 0;
+    loop {
+        let end = css_scan_to(b, start, b",");
+        parts.push(prelude[start..end].trim());
+        if end >= b.len() {
+            break;
+        }
+        start = end + 1;
+    }
+    parts.into_iter().filter(|p| !p.is_empty()).collect()
+}
+
+fn scope_selector(sel: &str, scope: &str) -> String {
+    // drop every leading root element, e.g. `html body .x` becomes `.x`
+    let mut rest = sel;
+    let mut rooted = false;
+    'strip: loop {
+        for root in ["html", "body", ":root"] {
+            if let Some(r) = rest.strip_prefix(root) {
+                if r.is_empty() || r.starts_with(|c: char| c.is_whitespace() || c == '>') {
+                    rest = r.trim_start().trim_start_matches('>').trim_start();
+                    rooted = true;
+                    continue 'strip;
+                }
+            }
+        }
+        break;
+    }
+    match (rooted, rest.is_empty()) {
+        (_, true) => scope.to_string(),
+        (true, false) => format!("{} {}", scope, rest),
+        (false, false) => format!("{} {}", scope, sel),
     }
-    return r;
 }
 
 pub struct Scanner<'a> {
@@ -72,28 +439,18 @@ impl<'a> Scanner<'a> {
         self.pos += n;
     }
 
-    pub fn read(&mut self, n: usize) -> Vec<u8> {
-        let r = self.buf[self.pos..self.pos + n].to_vec();
+    pub fn read(&mut self, n: usize) -> &'a [u8] {
+        let r = &self.buf[self.pos..self.pos + n];
         self.forward(n);
         r
     }
 
     pub fn read_u64(&mut self) -> u64 {
-        let r = u8v_to_u64(&self.buf[self.pos..self.pos + 8]);
-        self.forward(8);
-        r
+        self.try_read_u64().expect("Scanner: read past the end")
     }
 
     pub fn read_u32(&mut self) -> u32 {
-        let r = u8v_to_u32(&self.buf[self.pos..self.pos + 4]);
-        self.forward(4);
-        r
-    }
-
-    pub fn read_u16(&mut self) -> u16 {
-        let r = u8v_to_u16(&self.buf[self.pos..self.pos + 2]);
-        self.forward(2);
-        r
+        self.try_read_u32().expect("Scanner: read past the end")
     }
 
     pub fn read_u8(&mut self) -> u8 {
@@ -102,13 +459,223 @@ impl<'a> Scanner<'a> {
         r
     }
 
-    pub fn read_string(&mut self, n: usize) -> String {
-        let r = String::from_utf8(self.buf[self.pos..self.pos + n].to_vec()).unwrap();
-        self.forward(n);
-        r
-    }
-
     pub fn is_end(&self) -> bool {
         self.pos == self.buf.len()
     }
+
+    fn check(&self, n: usize) -> Result<()> {
+        match self.pos.checked_add(n) {
+            Some(end) if end <= self.buf.len() => Ok(()),
+            _ => Err(Error::Malformed(format!(
+                "unexpected end of data, {} byte(s) wanted at {} of {}",
+                n,
+                self.pos,
+                self.buf.len()
+            ))),
+        }
+    }
+
+    /// `read` failing instead of panicking past the end of the buffer
+    pub fn try_read(&mut self, n: usize) -> Result<&'a [u8]> {
+        self.check(n)?;
+        Ok(self.read(n))
+    }
+
+    pub fn try_read_u64(&mut self) -> Result<u64> {
+        u8v_to_u64(self.try_read(8)?)
+    }
+
+    pub fn try_read_u32(&mut self) -> Result<u32> {
+        u8v_to_u32(self.try_read(4)?)
+    }
+
+    pub fn try_read_u16(&mut self) -> Result<u16> {
+        u8v_to_u16(self.try_read(2)?)
+    }
+
+    pub fn try_read_u8(&mut self) -> Result<u8> {
+        self.check(1)?;
+        Ok(self.read_u8())
+    }
+
+    pub fn try_read_varint(&mut self) -> Result<u64> {
+        let mut r: u64 = 0;
+        for shift in (0..64).step_by(7) {
+            let b = self.try_read_u8()?;
+            r |= ((b & 0x7f) as u64) << shift;
+            if b & 0x80 == 0 {
+                return Ok(r);
+            }
+        }
+        Err(Error::Malformed("varint is too long".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_matches_wildcards() {
+        assert!(glob_match("w*rd", "word"));
+        assert!(glob_match("w*rd", "wrd"));
+        assert!(glob_match("w*rd", "weird"));
+        assert!(!glob_match("w*rd", "words"));
+        assert!(glob_match("c?t", "cat"));
+        assert!(!glob_match("c?t", "ct"));
+        assert!(!glob_match("c?t", "coat"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("**a*", "banana"));
+        assert!(glob_match("*ana", "banana"));
+        assert!(!glob_match("", "a"));
+        assert!(glob_match("ç?u*", "çœur"));
+    }
+
+    #[test]
+    fn levenshtein_stops_past_the_bound() {
+        let chars = |s: &str| s.chars().collect::<Vec<char>>();
+        let distance = |a: &str, b: &str, max| bounded_levenshtein(&chars(a), &chars(b), max);
+        assert_eq!(distance("kitten", "sitting", 3), Some(3));
+        assert_eq!(distance("kitten", "sitting", 2), None);
+        assert_eq!(distance("same", "same", 0), Some(0));
+        assert_eq!(distance("", "abc", 3), Some(3));
+        assert_eq!(distance("", "abc", 2), None);
+        assert_eq!(distance("receive", "recieve", 2), Some(2));
+        assert_eq!(distance("ab", "abcdef", 3), None);
+        assert_eq!(distance("café", "cafe", 1), Some(1));
+    }
+
+    #[test]
+    fn diacritics_are_folded() {
+        assert_eq!(fold_diacritics("résumé"), "resume");
+        assert_eq!(fold_diacritics("Ça naïve"), "Ca naive");
+        assert_eq!(fold_diacritics("cafe\u{301}"), "cafe");
+        assert_eq!(fold_diacritics("plain"), "plain");
+    }
+
+    #[test]
+    fn scanner_reports_short_buffers() {
+        let mut scanner = Scanner::new(&[0, 0, 0, 7, 1, 2]);
+        assert_eq!(scanner.try_read_u32().unwrap(), 7);
+        assert!(matches!(scanner.try_read_u32(), Err(Error::Malformed(_))));
+        assert!(matches!(scanner.try_read(3), Err(Error::Malformed(_))));
+        assert!(matches!(
+            scanner.try_read(usize::MAX),
+            Err(Error::Malformed(_))
+        ));
+        assert_eq!(scanner.try_read_u16().unwrap(), 0x102);
+        assert!(scanner.is_end());
+        assert!(matches!(scanner.try_read_u8(), Err(Error::Malformed(_))));
+        assert!(matches!(
+            Scanner::new(&[0x80, 0x80]).try_read_varint(),
+            Err(Error::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn integers_are_big_endian() {
+        assert_eq!(u64_to_u8v(0x0102030405060708), vec![1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(u32_to_u8v(0x01020304), vec![1, 2, 3, 4]);
+        assert_eq!(u16_to_u8v(0x0102), vec![1, 2]);
+        assert_eq!(
+            u8v_to_u64(&[1, 2, 3, 4, 5, 6, 7, 8]).unwrap(),
+            0x0102030405060708
+        );
+        assert_eq!(u8v_to_u32(&[1, 2, 3, 4]).unwrap(), 0x01020304);
+        assert_eq!(u8v_to_u16(&[1, 2]).unwrap(), 0x0102);
+        assert!(matches!(u8v_to_u64(&[1, 2, 3]), Err(Error::Malformed(_))));
+        assert!(matches!(
+            u8v_to_u32(&[1, 2, 3, 4, 5]),
+            Err(Error::Malformed(_))
+        ));
+        assert!(matches!(u8v_to_u16(&[]), Err(Error::Malformed(_))));
+    }
+
+    #[test]
+    fn varints_round_trip() {
+        for (v, len) in [
+            (0, 1),
+            (127, 1),
+            (128, 2),
+            (16383, 2),
+            (16384, 3),
+            (u64::MAX, 10),
+        ] {
+            let mut buf = vec![];
+            write_varint(v, &mut buf);
+            assert_eq!(buf.len(), len, "{}", v);
+            let mut scanner = Scanner::new(&buf);
+            assert_eq!(scanner.try_read_varint().unwrap(), v);
+            assert!(scanner.is_end());
+        }
+        assert_eq!(Scanner::new(&[0xac, 0x02]).try_read_varint().unwrap(), 300);
+    }
+
+    #[test]
+    fn mime_types_from_extension_or_bytes() {
+        assert_eq!(mime_type("a.png", &[]), "image/png");
+        assert_eq!(mime_type("sound/A.MP3", &[]), "audio/mpeg");
+        assert_eq!(mime_type("style.css", &[]), "text/css");
+        assert_eq!(mime_type("app.js", &[]), "text/javascript");
+        assert_eq!(mime_type("font.woff2", &[]), "font/woff2");
+        // the extension wins over the bytes
+        assert_eq!(mime_type("a.css", b"\x89PNG"), "text/css");
+        assert_eq!(mime_type("image", b"\x89PNG\r\n"), "image/png");
+        assert_eq!(mime_type("a.dat", b"ID3\x04"), "audio/mpeg");
+        assert_eq!(mime_type("a.dat", b"RIFF\0\0\0\0WAVEfmt "), "audio/wav");
+        assert_eq!(mime_type("a.dat", b"data"), "application/octet-stream");
+        assert_eq!(mime_type("", &[]), "application/octet-stream");
+    }
+
+    #[test]
+    fn urls_are_rewritten_inside_tags_only() {
+        let upper = |url: &str| Some(url.to_uppercase());
+        assert_eq!(
+            rewrite_urls(r#"<img src="a.png"> src="b.png""#, upper),
+            r#"<img src="A.PNG"> src="b.png""#
+        );
+        assert_eq!(
+            rewrite_urls(r#"<a class="x" HREF = 'y.html' title="z">"#, upper),
+            r#"<a class="x" HREF = 'Y.HTML' title="z">"#
+        );
+        assert_eq!(
+            rewrite_urls(r#"<img data-src="a" src="b">"#, upper),
+            r#"<img data-src="a" src="B">"#
+        );
+        let keep = |url: &str| (url != "keep").then(|| "new".to_string());
+        assert_eq!(
+            rewrite_urls(r#"<a href="keep"><a href="x">"#, keep),
+            r#"<a href="keep"><a href="new">"#
+        );
+        // an unclosed quote leaves the rest as is
+        assert_eq!(
+            rewrite_urls(r#"<img src="a.png>"#, upper),
+            r#"<img src="a.png>"#
+        );
+    }
+
+    #[test]
+    fn css_rules_are_scoped() {
+        assert_eq!(
+            scope_css("p, .a { color: red }", ".d"),
+            ".d p, .d .a { color: red }\n"
+        );
+        assert_eq!(
+            scope_css("body { margin: 0 } html > body .x {a:b}", ".d"),
+            ".d { margin: 0 }\n.d .x {a:b}\n"
+        );
+        assert_eq!(
+            scope_css(r#"a[title="a,b{"] { } /* x { } */"#, ".d"),
+            ".d a[title=\"a,b{\"] { }\n"
+        );
+        assert_eq!(
+            scope_css(
+                "@import url(\"x.css\");\n@media (max-width: 600px) { p { a: b } }",
+                ".d"
+            ),
+            "@import url(\"x.css\");\n@media (max-width: 600px) {\n.d p { a: b }\n}\n"
+        );
+        let keyframes = "@keyframes spin { from { a: b } to { a: c } }";
+        assert_eq!(scope_css(keyframes, ".d"), format!("{}\n", keyframes));
+    }
 }
@@ -0,0 +1,78 @@
+use crate::error::Result;
+use std::io::{Read, Write};
+use std::sync::Arc;
+use zstd::dict::{DecoderDictionary, EncoderDictionary};
+
+const LEVEL: i32 = zstd::DEFAULT_COMPRESSION_LEVEL;
+/// Largest dictionary trained, zstd's own default
+const MAX_DICTIONARY_SIZE: usize = 110 * 1024;
+
+/// zstd compression of nodes, optionally with a dictionary trained on nodes
+/// of the same file. Small nodes of similar headwords share most of their
+/// content with the dictionary and compress far better than on their own.
+#[derive(Clone)]
+pub struct ZstdCodec {
+    dictionary: Option<Arc<(EncoderDictionary<'static>, DecoderDictionary<'static>)>>,
+}
+
+impl std::fmt::Debug for ZstdCodec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ZstdCodec")
+    }
+}
+
+impl ZstdCodec {
+    /// Codec using `dictionary`, none if it is empty
+    pub fn new(dictionary: &[u8]) -> Self {
+        let dictionary = (!dictionary.is_empty()).then(|| {
+            Arc::new((
+                EncoderDictionary::copy(dictionary, LEVEL),
+                DecoderDictionary::copy(dictionary),
+            ))
+        });
+        Self { dictionary }
+    }
+
+    /// Train a dictionary on `samples`, serialized nodes. `None` when they
+    /// are too few or too small to train on.
+    pub fn train(samples: &[Vec<u8>]) -> Option<Vec<u8>> {
+        let total: usize = samples.iter().map(|s| s.len()).sum();
+        // the dictionary is stored in the metadata, keep it small next to
+        // the nodes it has to pay for
+        let size = (total / 100).min(MAX_DICTIONARY_SIZE);
+        if samples.len() < 8 || size < 256 {
+            return None;
+        }
+        zstd::dict::from_samples(samples, size).ok()
+    }
+
+    pub fn compress(&self, buf: &[u8]) -> Vec<u8> {
+        let mut e = match &self.dictionary {
+            Some(d) => zstd::Encoder::with_prepared_dictionary(Vec::new(), &d.0),
+            None => zstd::Encoder::new(Vec::new(), LEVEL),
+        }
+        .expect("ZstdEncoder: Fail to create");
+        e.write_all(buf).expect("ZstdEncoder: Fail to write");
+        e.finish().expect("ZstdEncoder: Fail to finish")
+    }
+
+    /// Reader decompressing `input` while it is read
+    pub fn decoder<'a, R: Read + 'a>(&'a self, input: R) -> Result<Box<dyn Read + 'a>> {
+        let input = std::io::BufReader::new(input);
+        Ok(match &self.dictionary {
+            Some(d) => Box::new(zstd::Decoder::with_prepared_dictionary(input, &d.1)?),
+            None => Box::new(zstd::Decoder::with_buffer(input)?),
+        })
+    }
+
+    pub fn decompress(&self, buf: &[u8]) -> Result<Vec<u8>> {
+        let mut data = vec![];
+        match &self.dictionary {
+            Some(d) => {
+                zstd::Decoder::with_prepared_dictionary(buf, &d.1)?.read_to_end(&mut data)?
+            }
+            None => zstd::Decoder::with_buffer(buf)?.read_to_end(&mut data)?,
+        };
+        Ok(data)
+    }
+}